@@ -0,0 +1,130 @@
+use std::{collections::HashMap, fmt, time::Duration};
+
+use libp2p::{PeerId, request_response::OutboundRequestId};
+use tokio::time::Instant;
+
+// 同時に送っておくリクエストの数。全部を一度に送るとストリームが足りなくなる。
+pub const WINDOW: usize = 16;
+
+// /bench <peer> <count> <size> の途中経過
+#[derive(Debug)]
+pub struct Bench {
+    pub peer_id: PeerId,
+    pub size: usize,
+    // まだ送っていない数
+    remaining: usize,
+    count: usize,
+    started: Instant,
+    in_flight: HashMap<OutboundRequestId, Instant>,
+    latencies: Vec<Duration>,
+    failures: usize,
+    // 受け取ったレスポンスのバイト数
+    received: u64,
+}
+
+impl Bench {
+    pub fn new(peer_id: PeerId, count: usize, size: usize) -> Self {
+        Bench {
+            peer_id,
+            size,
+            remaining: count,
+            count,
+            started: Instant::now(),
+            in_flight: HashMap::new(),
+            latencies: Vec::with_capacity(count),
+            failures: 0,
+            received: 0,
+        }
+    }
+
+    // 今送ってよい数
+    pub fn to_send(&self) -> usize {
+        self.remaining.min(WINDOW.saturating_sub(self.in_flight.len()))
+    }
+
+    pub fn sent(&mut self, id: OutboundRequestId) {
+        self.remaining -= 1;
+        self.in_flight.insert(id, Instant::now());
+    }
+
+    // このベンチのリクエストならtrue
+    pub fn answered(&mut self, id: OutboundRequestId, bytes: usize) -> bool {
+        let Some(sent) = self.in_flight.remove(&id) else {
+            return false;
+        };
+        self.latencies.push(sent.elapsed());
+        self.received += bytes as u64;
+        true
+    }
+
+    pub fn failed(&mut self, id: OutboundRequestId) -> bool {
+        if self.in_flight.remove(&id).is_none() {
+            return false;
+        }
+        self.failures += 1;
+        true
+    }
+
+    pub fn is_done(&self) -> bool {
+        self.remaining == 0 && self.in_flight.is_empty()
+    }
+
+    pub fn report(&mut self) -> Report {
+        self.latencies.sort();
+        let percentile = |p: usize| {
+            self.latencies
+                .get(self.latencies.len().saturating_sub(1) * p / 100)
+                .copied()
+                .unwrap_or_default()
+        };
+        Report {
+            count: self.count,
+            completed: self.latencies.len(),
+            failures: self.failures,
+            elapsed: self.started.elapsed(),
+            bytes: (self.latencies.len() * self.size) as u64 + self.received,
+            p50: percentile(50),
+            p90: percentile(90),
+            p99: percentile(99),
+            max: self.latencies.last().copied().unwrap_or_default(),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct Report {
+    count: usize,
+    completed: usize,
+    failures: usize,
+    elapsed: Duration,
+    // 送ったリクエストと受け取ったレスポンスの合計
+    bytes: u64,
+    p50: Duration,
+    p90: Duration,
+    p99: Duration,
+    max: Duration,
+}
+
+impl fmt::Display for Report {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let secs = self.elapsed.as_secs_f64().max(f64::EPSILON);
+        writeln!(
+            f,
+            "{}/{} requests in {:.2}s ({} failed), {:.1} req/s, {:.2} MiB/s",
+            self.completed,
+            self.count,
+            secs,
+            self.failures,
+            self.completed as f64 / secs,
+            self.bytes as f64 / secs / (1024.0 * 1024.0)
+        )?;
+        write!(
+            f,
+            "latency p50 {:.1}ms, p90 {:.1}ms, p99 {:.1}ms, max {:.1}ms",
+            self.p50.as_secs_f64() * 1000.0,
+            self.p90.as_secs_f64() * 1000.0,
+            self.p99.as_secs_f64() * 1000.0,
+            self.max.as_secs_f64() * 1000.0
+        )
+    }
+}
@@ -0,0 +1,100 @@
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use libp2p::{Multiaddr, swarm::{ConnectionId, dial_opts::DialOpts}};
+use tokio::time::Instant;
+
+// ダイヤルに失敗したときにやり直す決まり
+#[derive(Debug, Clone, Copy)]
+pub struct RetryPolicy {
+    // 最初の1回も含めた回数
+    pub max_attempts: u32,
+    // 1回目の失敗の後に待つ時間。失敗するたびに倍にする。
+    pub backoff: Duration,
+    pub max_backoff: Duration,
+    // 同時に起動したノードが同じタイミングでやり直さないよう、待ち時間に足す幅
+    pub jitter: Duration,
+}
+
+// --dial-attempts、--dial-backoff、--dial-max-backoff、--dial-jitter を指定しなかったときの値
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        RetryPolicy {
+            max_attempts: 5,
+            backoff: Duration::from_secs(1),
+            max_backoff: Duration::from_secs(30),
+            jitter: Duration::from_millis(500),
+        }
+    }
+}
+
+impl RetryPolicy {
+    // attempt回目の失敗の後に待つ時間
+    pub fn delay(&self, attempt: u32) -> Duration {
+        let backoff = self.backoff.saturating_mul(1 << attempt.saturating_sub(1).min(16));
+        backoff.min(self.max_backoff) + self.jitter()
+    }
+
+    // 乱数のcrateを入れるほどではないので、時刻の端数を使う
+    fn jitter(&self) -> Duration {
+        let max = self.jitter.as_millis() as u64;
+        if max == 0 {
+            return Duration::ZERO;
+        }
+        let nanos = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default().subsec_nanos() as u64;
+        Duration::from_millis(nanos % max)
+    }
+}
+
+// 失敗したときにどうするか
+#[derive(Debug, PartialEq, Eq)]
+pub enum Outcome {
+    Retry(Duration),
+    GaveUp(u32),
+}
+
+// 1つのアドレスへのダイヤルとやり直し
+#[derive(Debug)]
+pub struct Dialer {
+    pub address: Multiaddr,
+    policy: RetryPolicy,
+    attempts: u32,
+    // 最後にダイヤルした接続。失敗のイベントがこのダイヤルのものかを見分ける。
+    connection: Option<ConnectionId>,
+    // 次にダイヤルする時刻
+    pub next: Option<Instant>,
+}
+
+impl Dialer {
+    pub fn new(address: Multiaddr, policy: RetryPolicy) -> Self {
+        Dialer {
+            address,
+            policy,
+            attempts: 0,
+            connection: None,
+            next: Some(Instant::now()),
+        }
+    }
+
+    // ダイヤルするときのDialOpts。回数を数える。
+    pub fn dial_opts(&mut self) -> DialOpts {
+        let opts = DialOpts::unknown_peer_id().address(self.address.clone()).build();
+        self.connection = Some(opts.connection_id());
+        self.attempts += 1;
+        self.next = None;
+        opts
+    }
+
+    pub fn is_mine(&self, connection_id: ConnectionId) -> bool {
+        self.connection == Some(connection_id)
+    }
+
+    pub fn failed(&mut self) -> Outcome {
+        self.connection = None;
+        if self.attempts >= self.policy.max_attempts {
+            return Outcome::GaveUp(self.attempts);
+        }
+        let delay = self.policy.delay(self.attempts);
+        self.next = Some(Instant::now() + delay);
+        Outcome::Retry(delay)
+    }
+}
@@ -1,142 +1,93 @@
 use std::error::Error;
 
-use futures::stream::StreamExt;
-use libp2p::{
-    Multiaddr, PeerId, StreamProtocol, noise,
-    request_response::{self, ProtocolSupport},
-    swarm::{NetworkBehaviour, SwarmEvent},
-    tcp, yamux,
-};
-use serde::{Deserialize, Serialize};
-use tokio::{io, io::AsyncBufReadExt, select};
+use tokio::{io, io::AsyncBufReadExt, select, sync::mpsc};
+use tokio_util::sync::CancellationToken;
 use tracing_subscriber::EnvFilter;
 
-// Request/Responseで送受信するメッセージ型
-#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
-struct ChatRequest {
-    data: String,
-}
-
-#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
-struct ChatResponse {
-    data: String,
-}
+use crate::{
+    options::{LogFormat, Options},
+    service::{Command, NodeService},
+};
 
-// MyBehaviour と MyBehaviourEvent ができる
-#[derive(NetworkBehaviour)]
-struct MyBehaviour {
-    request_response: request_response::cbor::Behaviour<ChatRequest, ChatResponse>,
-}
+mod bench;
+mod dial;
+mod options;
+mod service;
+mod systemd;
 
 #[tokio::main]
 async fn main() -> Result<(), Box<dyn Error>> {
-    // 1番目は自分のポート番号。必須。
-    let my_port = std::env::args().nth(1).expect("Listen port number");
-    // 2番目は接続先のポート番号。ないなら接続しに行かない。
-    let connect_port = std::env::args().nth(2).unwrap_or("".to_string());
+    let opts = Options::parse()?;
 
     // libp2pのトレースログを出力可能にする。出力するには環境変数RUST_LOGの設定が必要。
     //  export RUST_LOG=info,[ConnectionHandler::poll]=trace,[NetworkBehaviour::poll]=trace
     //  https://libp2p.github.io/rust-libp2p/metrics_example/index.html#opentelemetry
-    let _ = tracing_subscriber::fmt()
-        .with_env_filter(EnvFilter::from_default_env())
-        .try_init();
+    init_tracing(opts.log_format);
 
-    let mut swarm = libp2p::SwarmBuilder::with_new_identity()
-        .with_tokio()
-        .with_tcp(
-            tcp::Config::default(),
-            noise::Config::new,     // noise, tls, plaintext(for test), ...
-            yamux::Config::default, // yamux, mplex, ...
-        )?
-        .with_behaviour(|_| MyBehaviour {
-            request_response: request_response::cbor::Behaviour::<ChatRequest, ChatResponse>::new(
-                [(StreamProtocol::new("/chat-chat/1"), ProtocolSupport::Full)],
-                request_response::Config::default(),
-            ),
-        })?
-        .build();
+    let mut service = NodeService::new(&opts)?;
+    println!("My peer ID: {}", service.local_peer_id());
 
-    let peer_id = swarm.local_peer_id();
-    println!("My peer ID: {}", peer_id);
-
-    // Read full lines from stdin
-    let mut stdin = io::BufReader::new(io::stdin()).lines();
-
-    // Listen on all interfaces and whatever port the OS assigns
-    swarm.listen_on(format!("/ip4/0.0.0.0/tcp/{my_port}").parse()?)?;
-
-    if connect_port.len() > 0 {
-        let remote: Multiaddr = format!("/ip4/127.0.0.1/tcp/{connect_port}").parse()?;
-        swarm.dial(remote)?;
-        println!("Dialed");
+    service.listen(&opts.my_port)?;
+    if let Some(connect_port) = &opts.connect_port {
+        service.dial(connect_port)?;
     }
 
     println!("Enter messages via STDIN and they will be sent to connected peer");
 
-    // ConnectionEstablishedでpeer_idを保存して使うのだが、未設定だとsend_request()でエラーになるのでこうしている
-    let mut connected_peer_id: Option<PeerId> = None;
+    // 標準入力の読み込み、swarmのイベントループ、Ctrl-Cの待ち受けはそれぞれ別のタスクにする。
+    // 入力はチャネルで渡すので、NodeServiceはどこから来たかを知らない。
+    // 終わるときはtokenをcancelし、どのタスクも待っているところで止まる。
+    let token = CancellationToken::new();
+    let (tx, rx) = mpsc::channel(32);
+    let reader = tokio::spawn(read_stdin(tx, token.clone()));
+    let ctrl_c = token.clone();
+    tokio::spawn(async move {
+        if tokio::signal::ctrl_c().await.is_ok() {
+            ctrl_c.cancel();
+        }
+    });
+    service.run(rx, token.clone()).await;
+    // Shutdownで終わったときは標準入力のタスクも止める
+    token.cancel();
+    let _ = reader.await;
+    Ok(())
+}
+
+// Read full lines from stdin
+// 入力が終わってもCtrl-Cまでは動き続ける。swarmのタスクが詰まっていても入力を待つのはこのタスクだけ。
+async fn read_stdin(tx: mpsc::Sender<Command>, token: CancellationToken) {
+    let mut stdin = io::BufReader::new(io::stdin()).lines();
     loop {
-        select! {
-            Ok(Some(line)) = stdin.next_line() => {
-                // 標準入力をそのまま接続先にリクエストとして送信
-                // なお複数接続は考慮していない
-                println!("input: {line}");
-                if let Some(peer_id) = connected_peer_id {
-                    let id = swarm.behaviour_mut()
-                        .request_response
-                        .send_request(&peer_id, ChatRequest{data: line});
-                    println!("send request id: {}", id);
-                } else {
-                    eprintln!("Peer not found");
-                }
+        let line = select! {
+            _ = token.cancelled() => break,
+            line = stdin.next_line() => match line {
+                Ok(Some(line)) => line,
+                _ => break,
             },
-            event = swarm.select_next_some() => match event {
-                // 通信系イベント?
-
-                SwarmEvent::NewListenAddr { address, .. } => {
-                    println!("Local node is listening on {address}");
-                },
-                SwarmEvent::ConnectionEstablished {peer_id, connection_id: _, endpoint: _, num_established: _, concurrent_dial_errors: _, established_in: _ } => {
-                    // 接続時にPeerIdを覚える
-                    println!("connected: {}", peer_id);
-                    connected_peer_id = Some(peer_id);
-                },
-                SwarmEvent::ConnectionClosed { peer_id: _, connection_id: _, endpoint: _, num_established: _, cause: _ } => {
-                    // 切断時にPeerIdは忘れる
-                    println!("disconnected");
-                    connected_peer_id = None;
-                },
-                // SwarmEvent::Behaviour(event) => println!("{event:?}"),
-                SwarmEvent::Behaviour(MyBehaviourEvent::RequestResponse(request_response::Event::Message {
-                    peer: _,
-                    connection_id: _,
-                    message: request_response::Message::Request { request_id: _, request, channel },
-                })) => {
-                    // リクエスト受信とレスポンス送信
-                    // リクエスト文字列を大文字にして返すだけ
-                    println!("request: {}", request.data);
-                    let res_msg = request.data.to_uppercase();
-                    if let Err(e) = swarm
-                        .behaviour_mut()
-                        .request_response
-                        .send_response(channel, ChatResponse{data: res_msg}) {
-                        println!("response send error: {e:?}");
-                    } else {
-                        println!("send response");
-                    }
-                },
-                SwarmEvent::Behaviour(MyBehaviourEvent::RequestResponse(request_response::Event::Message {
-                    peer: _,
-                    connection_id: _,
-                    message: request_response::Message::Response { request_id: _, response }
-                })) => {
-                    // レスポンス受信
-                    println!("response: {}", response.data);
-                },
-
-                _ => {}
+        };
+        let command = match Command::parse(line) {
+            Ok(command) => command,
+            Err(e) => {
+                eprintln!("{e}");
+                continue;
             }
+        };
+        // チャネルがいっぱいでも、終わるときは待たない
+        select! {
+            _ = token.cancelled() => break,
+            sent = tx.send(command) => if sent.is_err() {
+                break;
+            },
         }
     }
 }
+
+// --log-format json ならJSON形式で出力する。
+// JSONのときはpeer_id, request_idなどのフィールドがそのままキーになる。
+fn init_tracing(format: LogFormat) {
+    let builder = tracing_subscriber::fmt().with_env_filter(EnvFilter::from_default_env());
+    let _ = match format {
+        LogFormat::Text => builder.try_init(),
+        LogFormat::Json => builder.json().try_init(),
+    };
+}
@@ -0,0 +1,105 @@
+use std::{error::Error, str::FromStr, time::Duration};
+
+use crate::dial::RetryPolicy;
+
+// コマンドライン引数
+//  chat-req-res <my port> [connect port] [--log-format text|json] [--idle-timeout <secs> | --keep-alive]
+//               [--max-message-size <bytes>] [--dial-attempts <n>] [--dial-backoff <secs>] [--dial-max-backoff <secs>]
+//               [--dial-jitter <millis>]
+//               [--yamux-max-streams <n>] [--tcp-nodelay true|false] [--tcp-backlog <n>] [--tcp-ttl <n>]
+#[derive(Debug)]
+pub struct Options {
+    // 自分のポート番号。必須。
+    pub my_port: String,
+    // 接続先のポート番号。ないなら接続しに行かない。
+    pub connect_port: Option<String>,
+    pub log_format: LogFormat,
+    // 通信がない接続を閉じるまでの時間。指定がなければlibp2pのデフォルト。
+    pub idle_timeout: Option<Duration>,
+    // リクエスト・レスポンスの最大バイト数。これを超えるものは送らず、受信もデコード前に捨てる。
+    pub max_message_size: u64,
+    // 接続先へのダイヤルに失敗したときのやり直し方
+    pub dial_retry: RetryPolicy,
+    // 1つの接続で同時に開けるyamuxのストリームの数。指定がなければyamuxのデフォルト(512)。
+    pub yamux_max_streams: Option<usize>,
+    // TCPのソケットの設定。指定がなければlibp2pのデフォルト(nodelayは有効、backlogは1024、TTLはOSのデフォルト)。
+    pub tcp_nodelay: Option<bool>,
+    pub tcp_backlog: Option<u32>,
+    pub tcp_ttl: Option<u32>,
+}
+
+// 指定がないときの最大バイト数
+pub const DEFAULT_MAX_MESSAGE_SIZE: u64 = 64 * 1024;
+
+impl Options {
+    pub fn parse() -> Result<Self, Box<dyn Error>> {
+        let mut positional = Vec::new();
+        let mut log_format = LogFormat::default();
+        let mut idle_timeout = None;
+        let mut max_message_size = DEFAULT_MAX_MESSAGE_SIZE;
+        let mut dial_retry = RetryPolicy::default();
+        let mut yamux_max_streams = None;
+        let (mut tcp_nodelay, mut tcp_backlog, mut tcp_ttl) = (None, None, None);
+        let mut args = std::env::args().skip(1);
+        while let Some(arg) = args.next() {
+            match arg.as_str() {
+                "--log-format" => log_format = value(&mut args, &arg)?.parse()?,
+                "--idle-timeout" => idle_timeout = Some(Duration::from_secs(value(&mut args, &arg)?.parse()?)),
+                // 長時間チャットするときは接続を閉じないようにする
+                "--keep-alive" => idle_timeout = Some(Duration::from_secs(u64::MAX)),
+                "--max-message-size" => max_message_size = value(&mut args, &arg)?.parse()?,
+                "--dial-attempts" => dial_retry.max_attempts = value(&mut args, &arg)?.parse()?,
+                "--dial-backoff" => dial_retry.backoff = Duration::from_secs(value(&mut args, &arg)?.parse()?),
+                "--dial-max-backoff" => dial_retry.max_backoff = Duration::from_secs(value(&mut args, &arg)?.parse()?),
+                "--dial-jitter" => dial_retry.jitter = Duration::from_millis(value(&mut args, &arg)?.parse()?),
+                "--yamux-max-streams" => yamux_max_streams = Some(value(&mut args, &arg)?.parse()?),
+                "--tcp-nodelay" => tcp_nodelay = Some(value(&mut args, &arg)?.parse()?),
+                "--tcp-backlog" => tcp_backlog = Some(value(&mut args, &arg)?.parse()?),
+                "--tcp-ttl" => tcp_ttl = Some(value(&mut args, &arg)?.parse()?),
+                _ if arg.starts_with("--") => return Err(format!("unknown argument: {arg}").into()),
+                _ => positional.push(arg),
+            }
+        }
+        let mut positional = positional.into_iter();
+        let my_port = positional.next().ok_or("Listen port number")?;
+        let connect_port = positional.next();
+        Ok(Options {
+            my_port,
+            connect_port,
+            log_format,
+            idle_timeout,
+            max_message_size,
+            dial_retry,
+            yamux_max_streams,
+            tcp_nodelay,
+            tcp_backlog,
+            tcp_ttl,
+        })
+    }
+}
+
+// ログの出力形式
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum LogFormat {
+    // 人が読む形式(tracing-subscriberのデフォルト)
+    #[default]
+    Text,
+    // 1行1JSON。Loki/Elasticなどにそのまま入れられる。
+    Json,
+}
+
+impl FromStr for LogFormat {
+    type Err = Box<dyn Error>;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "text" => Ok(LogFormat::Text),
+            "json" => Ok(LogFormat::Json),
+            _ => Err(format!("unknown log format: {s}").into()),
+        }
+    }
+}
+
+fn value(args: &mut impl Iterator<Item = String>, name: &str) -> Result<String, Box<dyn Error>> {
+    args.next().ok_or_else(|| format!("{name} needs a value").into())
+}
@@ -0,0 +1,545 @@
+use std::{
+    collections::{HashMap, HashSet},
+    error::Error,
+};
+
+use futures::stream::StreamExt;
+use libp2p::{
+    Multiaddr, PeerId, StreamProtocol, Swarm,
+    core::{ConnectedPoint, transport::ListenerId},
+    noise,
+    request_response::{self, ProtocolSupport},
+    swarm::{ConnectionId, NetworkBehaviour, SwarmEvent},
+    tcp, yamux,
+};
+use serde::{Deserialize, Serialize};
+use tokio::{select, sync::mpsc, time::Instant};
+use tokio_util::sync::CancellationToken;
+
+use crate::{
+    bench::Bench,
+    dial::{Dialer, Outcome, RetryPolicy},
+    options::Options,
+    systemd,
+};
+
+// Request/Responseで送受信するメッセージ型
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+struct ChatRequest {
+    data: String,
+    // /bench のリクエスト。受け取った側は表示しない。古いpeerは知らないので省略できるようにする。
+    #[serde(default)]
+    bench: bool,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+struct ChatResponse {
+    data: String,
+}
+
+// MyBehaviour と MyBehaviourEvent ができる
+#[derive(NetworkBehaviour)]
+struct MyBehaviour {
+    request_response: request_response::cbor::Behaviour<ChatRequest, ChatResponse>,
+}
+
+// NodeServiceへの指示。標準入力など、外からチャネルで送る。
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Command {
+    // 接続先にリクエストとして送る
+    Send(String),
+    // sizeバイトのリクエストをcount回送り、スループットとレイテンシを測る
+    Bench { peer_id: PeerId, count: usize, size: usize },
+    // ポートにダイヤルする。つながっているかダイヤル中ならしない。
+    Dial(String),
+    // 接続を閉じてrun()を終える
+    Shutdown,
+}
+
+impl Command {
+    // 標準入力の1行。"/bench"、"/dial"、"/shutdown" 以外はそのまま送る。
+    pub fn parse(line: String) -> Result<Self, String> {
+        if line.trim() == "/shutdown" {
+            return Ok(Command::Shutdown);
+        }
+        if let Some(port) = line.strip_prefix("/dial") {
+            return match port.trim() {
+                "" => Err("usage: /dial <port>".to_string()),
+                port => Ok(Command::Dial(port.to_string())),
+            };
+        }
+        let Some(args) = line.strip_prefix("/bench") else {
+            return Ok(Command::Send(line));
+        };
+        let usage = || "usage: /bench <peer id> <count> <size>".to_string();
+        let mut args = args.split_whitespace();
+        let (Some(peer_id), Some(count), Some(size), None) = (args.next(), args.next(), args.next(), args.next()) else {
+            return Err(usage());
+        };
+        Ok(Command::Bench {
+            peer_id: peer_id.parse().map_err(|_| usage())?,
+            count: count.parse().map_err(|_| usage())?,
+            size: size.parse().map_err(|_| usage())?,
+        })
+    }
+}
+
+// swarmとそのイベントループ
+pub struct NodeService {
+    swarm: Swarm<MyBehaviour>,
+    max_message_size: u64,
+    // shutdown()で閉じる
+    listeners: Vec<ListenerId>,
+    // まだつながっていないダイヤル先
+    dialers: Vec<Dialer>,
+    dial_retry: RetryPolicy,
+    // ConnectionEstablishedでpeer_idを保存して使うのだが、未設定だとsend_request()でエラーになるのでこうしている
+    connected_peer_id: Option<PeerId>,
+    // peerごとの接続。同じpeerに複数の接続があることもあるので、すべて閉じたら切断とする。
+    connections: HashMap<PeerId, HashSet<ConnectionId>>,
+    // 接続ごとの相手のアドレス(相手から来た接続も含む)。同じところに二重にダイヤルしないために使う。
+    remotes: HashMap<ConnectionId, Multiaddr>,
+    // ダイヤルしてつながったアドレスとそのpeer。相手から来た接続はアドレスが待ち受けているものと違うので、
+    // 一度つながったアドレスならpeerでつながっているかを見る。
+    known: HashMap<Multiaddr, PeerId>,
+    // listenできたらsystemdに準備完了を伝える(1回だけ)
+    notified_ready: bool,
+    // 実行中の /bench
+    bench: Option<Bench>,
+}
+
+impl NodeService {
+    pub fn new(opts: &Options) -> Result<Self, Box<dyn Error>> {
+        let max_message_size = opts.max_message_size;
+        let swarm = libp2p::SwarmBuilder::with_new_identity()
+            .with_tokio()
+            .with_tcp(
+                tcp_config(opts),
+                noise::Config::new, // noise, tls, plaintext(for test), ...
+                || yamux_config(opts),  // yamux, mplex, ...
+            )?
+            .with_behaviour(|_| {
+                // 大きすぎるメッセージはCBORをデコードする前に捨てる
+                let codec = request_response::cbor::codec::Codec::<ChatRequest, ChatResponse>::default()
+                    .set_request_size_maximum(max_message_size)
+                    .set_response_size_maximum(max_message_size);
+                MyBehaviour {
+                    request_response: request_response::Behaviour::with_codec(
+                        codec,
+                        [(StreamProtocol::new("/chat-chat/1"), ProtocolSupport::Full)],
+                        request_response::Config::default(),
+                    ),
+                }
+            })?
+            .with_swarm_config(|cfg| match opts.idle_timeout {
+                Some(timeout) => cfg.with_idle_connection_timeout(timeout),
+                None => cfg,
+            })
+            .build();
+        Ok(NodeService {
+            swarm,
+            max_message_size,
+            listeners: Vec::new(),
+            dialers: Vec::new(),
+            dial_retry: opts.dial_retry,
+            connected_peer_id: None,
+            connections: HashMap::new(),
+            remotes: HashMap::new(),
+            known: HashMap::new(),
+            notified_ready: false,
+            bench: None,
+        })
+    }
+
+    pub fn local_peer_id(&self) -> &PeerId {
+        self.swarm.local_peer_id()
+    }
+
+    pub fn listen(&mut self, port: &str) -> Result<(), Box<dyn Error>> {
+        // Listen on all interfaces and whatever port the OS assigns
+        let id = self.swarm.listen_on(format!("/ip4/0.0.0.0/tcp/{port}").parse()?)?;
+        self.listeners.push(id);
+        Ok(())
+    }
+
+    // 失敗してもdial_retryに従ってやり直す。
+    // すでにつながっているか、ダイヤル中(やり直し待ちも含む)のアドレスなら何もしない。
+    pub fn dial(&mut self, port: &str) -> Result<(), Box<dyn Error>> {
+        let remote: Multiaddr = format!("/ip4/127.0.0.1/tcp/{port}").parse()?;
+        let connected_peer = self.known.get(&remote).is_some_and(|peer_id| self.swarm.is_connected(peer_id));
+        if connected_peer || self.remotes.values().any(|addr| *addr == remote) {
+            println!("Already connected to {remote}");
+            return Ok(());
+        }
+        if self.dialers.iter().any(|d| d.address == remote) {
+            println!("Already dialing {remote}");
+            return Ok(());
+        }
+        self.dialers.push(Dialer::new(remote, self.dial_retry));
+        self.dial_due();
+        Ok(())
+    }
+
+    // 時刻が来たダイヤルをする
+    fn dial_due(&mut self) {
+        let now = Instant::now();
+        let swarm = &mut self.swarm;
+        self.dialers.retain_mut(|dialer| {
+            if !dialer.next.is_some_and(|t| t <= now) {
+                return true;
+            }
+            match swarm.dial(dialer.dial_opts()) {
+                Ok(()) => {
+                    println!("Dialed {}", dialer.address);
+                    true
+                }
+                Err(e) => {
+                    println!("Dial error for {}: {e}", dialer.address);
+                    retry_or_give_up(dialer)
+                }
+            }
+        });
+    }
+
+    // tokenがcancelされるか、Shutdownが来るまでイベントを処理する。
+    // 送る側がすべてなくなっても(標準入力が/dev/nullなど)止めず、コマンドを待つのをやめるだけ。
+    // 待っているのはどれもcancel safeなもの(mpscのrecv、swarmの次のイベント、タイマー)なので、
+    // どの枝が選ばれても他の枝のイベントは失われない。各枝の処理は待たずにすぐ戻る。
+    pub async fn run(&mut self, mut commands: mpsc::Receiver<Command>, token: CancellationToken) {
+        let mut commands_open = true;
+        loop {
+            let next_dial = self.dialers.iter().filter_map(|d| d.next).min();
+            select! {
+                _ = token.cancelled() => break,
+                command = commands.recv(), if commands_open => match command {
+                    Some(Command::Shutdown) => break,
+                    Some(command) => self.handle_command(command),
+                    None => commands_open = false,
+                },
+                event = self.swarm.select_next_some() => self.handle_event(event),
+                _ = tokio::time::sleep_until(next_dial.unwrap_or_else(Instant::now)), if next_dial.is_some() => self.dial_due(),
+            }
+        }
+        self.shutdown();
+    }
+
+    pub fn handle_command(&mut self, command: Command) {
+        match command {
+            Command::Send(line) => {
+                // 標準入力をそのまま接続先にリクエストとして送信
+                // なお複数接続は考慮していない
+                println!("input: {line}");
+                if line.len() as u64 > self.max_message_size {
+                    // CBORのぶん少し大きくなるので、本文だけで判定するのは目安
+                    eprintln!("Message too large: {} bytes (max {})", line.len(), self.max_message_size);
+                } else if let Some(peer_id) = self.connected_peer_id {
+                    let id = self.swarm.behaviour_mut()
+                        .request_response
+                        .send_request(&peer_id, ChatRequest{data: line, bench: false});
+                    println!("send request id: {}", id);
+                    tracing::info!(peer_id = %peer_id, request_id = %id, "request sent");
+                } else {
+                    eprintln!("Peer not found");
+                }
+            }
+            Command::Bench { peer_id, count, size } => {
+                if self.bench.is_some() {
+                    eprintln!("A benchmark is already running");
+                } else if size as u64 > self.max_message_size {
+                    eprintln!("Size too large: {size} bytes (max {})", self.max_message_size);
+                } else {
+                    println!("Benchmarking {peer_id}: {count} requests of {size} bytes");
+                    self.bench = Some(Bench::new(peer_id, count, size));
+                    self.bench_next();
+                }
+            }
+            Command::Dial(port) => {
+                if let Err(e) = self.dial(&port) {
+                    eprintln!("Dial error: {e}");
+                }
+            }
+            Command::Shutdown => self.shutdown(),
+        }
+    }
+
+    // 返ってきた分だけ次のリクエストを送る。すべて終わったら結果を表示する。
+    fn bench_next(&mut self) {
+        let Some(bench) = self.bench.as_mut() else {
+            return;
+        };
+        for _ in 0..bench.to_send() {
+            let request = ChatRequest { data: "x".repeat(bench.size), bench: true };
+            let id = self.swarm.behaviour_mut().request_response.send_request(&bench.peer_id, request);
+            bench.sent(id);
+        }
+        if bench.is_done() {
+            println!("{}", bench.report());
+            self.bench = None;
+        }
+    }
+
+    // 待ち受けをやめ、つながっているpeerとの接続を閉じる
+    pub fn shutdown(&mut self) {
+        self.dialers.clear();
+        self.bench = None;
+        self.connected_peer_id = None;
+        self.remotes.clear();
+        for peer_id in std::mem::take(&mut self.connections).into_keys() {
+            let _ = self.swarm.disconnect_peer_id(peer_id);
+        }
+        for id in self.listeners.drain(..) {
+            self.swarm.remove_listener(id);
+        }
+        println!("Shutting down");
+        if let Err(e) = systemd::notify("STOPPING=1") {
+            println!("sd_notify error: {e:?}");
+        }
+    }
+
+    fn handle_event(&mut self, event: SwarmEvent<MyBehaviourEvent>) {
+        match event {
+            // 通信系イベント?
+
+            SwarmEvent::NewListenAddr { address, .. } => {
+                println!("Local node is listening on {address}");
+                if !self.notified_ready {
+                    if let Err(e) = systemd::notify("READY=1") {
+                        println!("sd_notify error: {e:?}");
+                    }
+                    self.notified_ready = true;
+                }
+            },
+            SwarmEvent::ConnectionEstablished {peer_id, connection_id, endpoint, num_established: _, concurrent_dial_errors: _, established_in: _ } => {
+                if let ConnectedPoint::Dialer { address, .. } = &endpoint {
+                    self.known.insert(address.clone(), peer_id);
+                }
+                self.remotes.insert(connection_id, endpoint.get_remote_address().clone());
+                // 接続時にPeerIdを覚える
+                let connections = self.connections.entry(peer_id).or_default();
+                if connections.is_empty() {
+                    println!("connected: {}", peer_id);
+                }
+                connections.insert(connection_id);
+                tracing::info!(peer_id = %peer_id, connections = connections.len(), "connection established");
+                self.connected_peer_id = Some(peer_id);
+                self.dialers.retain(|d| !d.is_mine(connection_id));
+            },
+            SwarmEvent::OutgoingConnectionError { connection_id, error, .. } => {
+                self.dialers.retain_mut(|dialer| {
+                    if !dialer.is_mine(connection_id) {
+                        return true;
+                    }
+                    println!("Dial error for {}: {error}", dialer.address);
+                    retry_or_give_up(dialer)
+                });
+            },
+            SwarmEvent::ConnectionClosed { peer_id, connection_id, endpoint: _, num_established: _, cause: _ } => {
+                let Some(connections) = self.connections.get_mut(&peer_id) else {
+                    return;
+                };
+                connections.remove(&connection_id);
+                self.remotes.remove(&connection_id);
+                if !connections.is_empty() {
+                    // 他の接続が残っているならまだ送れる
+                    tracing::info!(peer_id = %peer_id, connections = connections.len(), "connection closed");
+                    return;
+                }
+                // すべて閉じたらPeerIdは忘れる。他にもつながっているpeerがいればそちらに送る。
+                self.connections.remove(&peer_id);
+                println!("disconnected: {}", peer_id);
+                if self.connected_peer_id == Some(peer_id) {
+                    self.connected_peer_id = self.connections.keys().next().copied();
+                }
+            },
+            // SwarmEvent::Behaviour(event) => println!("{event:?}"),
+            SwarmEvent::Behaviour(MyBehaviourEvent::RequestResponse(request_response::Event::Message {
+                peer,
+                connection_id: _,
+                message: request_response::Message::Request { request_id, request, channel },
+            })) => {
+                // リクエスト受信とレスポンス送信
+                // リクエスト文字列を大文字にして返すだけ
+                if !request.bench {
+                    println!("request: {}", request.data);
+                }
+                tracing::info!(peer_id = %peer, request_id = %request_id, "request received");
+                if let Err(e) = self.swarm
+                    .behaviour_mut()
+                    .request_response
+                    .send_response(channel, respond(&request)) {
+                    println!("response send error: {e:?}");
+                } else if !request.bench {
+                    println!("send response");
+                }
+            },
+            SwarmEvent::Behaviour(MyBehaviourEvent::RequestResponse(request_response::Event::Message {
+                peer,
+                connection_id: _,
+                message: request_response::Message::Response { request_id, response }
+            })) => {
+                // レスポンス受信
+                if self.bench.as_mut().is_some_and(|b| b.answered(request_id, response.data.len())) {
+                    self.bench_next();
+                    return;
+                }
+                println!("response: {}", response.data);
+                tracing::info!(peer_id = %peer, request_id = %request_id, "response received");
+            },
+            SwarmEvent::Behaviour(MyBehaviourEvent::RequestResponse(request_response::Event::OutboundFailure {
+                peer,
+                request_id,
+                error,
+                ..
+            })) => {
+                if self.bench.as_mut().is_some_and(|b| b.failed(request_id)) {
+                    tracing::warn!(peer_id = %peer, request_id = %request_id, error = %error, "bench request failed");
+                    self.bench_next();
+                } else {
+                    println!("request failed: {error}");
+                }
+            },
+
+            _ => {}
+        }
+    }
+}
+
+// --tcp-nodelay などのソケットの設定
+fn tcp_config(opts: &Options) -> tcp::Config {
+    let mut cfg = tcp::Config::default();
+    if let Some(nodelay) = opts.tcp_nodelay {
+        cfg = cfg.nodelay(nodelay);
+    }
+    if let Some(backlog) = opts.tcp_backlog {
+        cfg = cfg.listen_backlog(backlog);
+    }
+    if let Some(ttl) = opts.tcp_ttl {
+        cfg = cfg.ttl(ttl);
+    }
+    cfg
+}
+
+// 受信ウィンドウはyamuxが通信量に合わせて自動で広げるので、変えられるのはストリームの数だけ。
+// (set_receive_window_size と set_max_buffer_size は非推奨で、今のyamuxでは効かない)
+fn yamux_config(opts: &Options) -> yamux::Config {
+    let mut cfg = yamux::Config::default();
+    if let Some(n) = opts.yamux_max_streams {
+        cfg.set_max_num_streams(n);
+    }
+    cfg
+}
+
+// リクエストへのレスポンス。swarmを動かさなくても確かめられるよう、ここでは何も送らない。
+fn respond(request: &ChatRequest) -> ChatResponse {
+    ChatResponse { data: request.data.to_uppercase() }
+}
+
+// やり直すならtrue。あきらめたらそれを知らせる。
+fn retry_or_give_up(dialer: &mut Dialer) -> bool {
+    match dialer.failed() {
+        Outcome::Retry(delay) => {
+            println!("Retrying {} in {:.1}s", dialer.address, delay.as_secs_f32());
+            true
+        }
+        Outcome::GaveUp(attempts) => {
+            println!("Gave up dialing {} after {attempts} attempts", dialer.address);
+            tracing::warn!(address = %dialer.address, attempts, "gave up dialing");
+            false
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::*;
+    use crate::options::DEFAULT_MAX_MESSAGE_SIZE;
+
+    fn request(data: &str) -> ChatRequest {
+        ChatRequest { data: data.to_string(), bench: false }
+    }
+
+    #[test]
+    fn respond_uppercases_request() {
+        assert_eq!(respond(&request("hello")), ChatResponse { data: "HELLO".to_string() });
+    }
+
+    #[test]
+    fn respond_keeps_size_for_bench() {
+        let bench = ChatRequest { data: "x".repeat(1024), bench: true };
+        assert_eq!(respond(&bench).data.len(), 1024);
+    }
+
+    #[test]
+    fn respond_to_empty_and_non_ascii() {
+        assert_eq!(respond(&request("")).data, "");
+        assert_eq!(respond(&request("héllo ü")).data, "HÉLLO Ü");
+    }
+
+    fn options() -> Options {
+        Options {
+            my_port: "0".to_string(),
+            connect_port: None,
+            log_format: Default::default(),
+            idle_timeout: None,
+            max_message_size: DEFAULT_MAX_MESSAGE_SIZE,
+            dial_retry: RetryPolicy::default(),
+            yamux_max_streams: None,
+            tcp_nodelay: None,
+            tcp_backlog: None,
+            tcp_ttl: None,
+        }
+    }
+
+    // 空いているポートで待ち受けたNodeService
+    fn listening() -> NodeService {
+        let mut service = NodeService::new(&options()).unwrap();
+        service.listen("0").unwrap();
+        assert_eq!(service.listeners.len(), 1);
+        service
+    }
+
+    #[test]
+    fn parse_commands() {
+        assert_eq!(Command::parse("/shutdown".to_string()), Ok(Command::Shutdown));
+        assert_eq!(Command::parse("/dial 4001".to_string()), Ok(Command::Dial("4001".to_string())));
+        assert!(Command::parse("/dial".to_string()).is_err());
+        assert!(Command::parse("/bench x 1 1".to_string()).is_err());
+        assert_eq!(Command::parse("hello".to_string()), Ok(Command::Send("hello".to_string())));
+    }
+
+    #[tokio::test]
+    async fn run_returns_when_cancelled() {
+        let mut service = listening();
+        let (tx, rx) = mpsc::channel(8);
+        let token = CancellationToken::new();
+        // つながっているpeerがいないので送られないが、runは処理を続ける
+        tx.send(Command::Send("hello".to_string())).await.unwrap();
+        token.cancel();
+        tokio::time::timeout(Duration::from_secs(5), service.run(rx, token)).await.unwrap();
+        assert!(service.listeners.is_empty());
+        assert!(service.connections.is_empty());
+    }
+
+    #[tokio::test]
+    async fn run_keeps_going_after_commands_close() {
+        let mut service = listening();
+        let (tx, rx) = mpsc::channel(8);
+        let token = CancellationToken::new();
+        // 標準入力が終わったときと同じ
+        drop(tx);
+        let run = tokio::time::timeout(Duration::from_millis(200), service.run(rx, token.clone())).await;
+        assert!(run.is_err(), "run must not stop when the command channel closes");
+        assert_eq!(service.listeners.len(), 1);
+    }
+
+    #[tokio::test]
+    async fn run_returns_on_shutdown_command() {
+        let mut service = listening();
+        let (tx, rx) = mpsc::channel(8);
+        tx.send(Command::Shutdown).await.unwrap();
+        tokio::time::timeout(Duration::from_secs(5), service.run(rx, CancellationToken::new())).await.unwrap();
+        assert!(service.listeners.is_empty());
+    }
+}
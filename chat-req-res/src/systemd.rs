@@ -0,0 +1,30 @@
+use std::io;
+
+// systemd(Type=notify)に状態を通知する。sd_notify(3)と同じことを自前でやっている。
+// systemdから起動されていない(NOTIFY_SOCKETがない)ときは何もしない。
+#[cfg(unix)]
+pub fn notify(state: &str) -> io::Result<()> {
+    use std::os::unix::{ffi::OsStrExt, net::UnixDatagram};
+
+    let Some(path) = std::env::var_os("NOTIFY_SOCKET") else {
+        return Ok(());
+    };
+    let socket = UnixDatagram::unbound()?;
+
+    // '@'で始まるのはLinuxの抽象名前空間
+    #[cfg(target_os = "linux")]
+    if let Some(name) = path.as_bytes().strip_prefix(b"@") {
+        use std::os::{linux::net::SocketAddrExt, unix::net::SocketAddr};
+        let addr = SocketAddr::from_abstract_name(name)?;
+        socket.send_to_addr(state.as_bytes(), &addr)?;
+        return Ok(());
+    }
+
+    socket.send_to(state.as_bytes(), path)?;
+    Ok(())
+}
+
+#[cfg(not(unix))]
+pub fn notify(_state: &str) -> io::Result<()> {
+    Ok(())
+}
@@ -0,0 +1,67 @@
+use std::{
+    collections::HashSet,
+    error::Error,
+    fs,
+    io,
+    path::PathBuf,
+};
+
+use libp2p::PeerId;
+
+// /block したpeerの一覧。ファイルを指定すれば1行1PeerIdで保存し、次の起動でも有効になる。
+#[derive(Debug, Default)]
+pub struct Blocklist {
+    peers: HashSet<PeerId>,
+    path: Option<PathBuf>,
+}
+
+impl Blocklist {
+    pub fn load(path: Option<PathBuf>) -> Result<Self, Box<dyn Error>> {
+        let mut peers = HashSet::new();
+        if let Some(path) = &path
+            && path.exists()
+        {
+            for line in fs::read_to_string(path)?.lines() {
+                let line = line.trim();
+                if !line.is_empty() {
+                    peers.insert(line.parse()?);
+                }
+            }
+        }
+        Ok(Blocklist { peers, path })
+    }
+
+    pub fn contains(&self, peer_id: &PeerId) -> bool {
+        self.peers.contains(peer_id)
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &PeerId> {
+        self.peers.iter()
+    }
+
+    // 追加したらtrue
+    pub fn insert(&mut self, peer_id: PeerId) -> io::Result<bool> {
+        let added = self.peers.insert(peer_id);
+        if added {
+            self.save()?;
+        }
+        Ok(added)
+    }
+
+    // 削除したらtrue
+    pub fn remove(&mut self, peer_id: &PeerId) -> io::Result<bool> {
+        let removed = self.peers.remove(peer_id);
+        if removed {
+            self.save()?;
+        }
+        Ok(removed)
+    }
+
+    fn save(&self) -> io::Result<()> {
+        let Some(path) = &self.path else {
+            return Ok(());
+        };
+        let text: String = self.peers.iter().map(|p| format!("{p}\n")).collect();
+        fs::write(path, text)
+    }
+}
@@ -0,0 +1,82 @@
+use std::{
+    collections::HashMap,
+    fs, io,
+    path::PathBuf,
+};
+
+use libp2p::{
+    StreamProtocol,
+    request_response::{self, ProtocolSupport},
+};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+// ハッシュを指定してブロック(ファイルの中身)をもらう。小さなbitswapのようなもの。
+pub const PROTOCOL: StreamProtocol = StreamProtocol::new("/blocks/1");
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Want(pub String);
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub enum BlockResponse {
+    Block(Vec<u8>),
+    NotFound,
+}
+
+pub type Behaviour = request_response::cbor::Behaviour<Want, BlockResponse>;
+
+// ブロックはファイル1つ分なので、--max-message-size ではなくこれを上限にする(/add もこれを超えるものは置かない)
+pub const MAX_BLOCK_SIZE: usize = 10 * 1024 * 1024;
+
+// 要求(ハッシュ)は --max-message-size まで、ブロックはMAX_BLOCK_SIZEまで。超えたらデコードする前に捨てる。
+pub fn behaviour(max_message_size: u64) -> Behaviour {
+    let codec = request_response::cbor::codec::Codec::<Want, BlockResponse>::default()
+        .set_request_size_maximum(max_message_size)
+        // CBORのぶん少し大きくなる
+        .set_response_size_maximum(MAX_BLOCK_SIZE as u64 + 1024);
+    Behaviour::with_codec(codec, [(PROTOCOL, ProtocolSupport::Full)], request_response::Config::default())
+}
+
+// 中身のSHA-256(hex)がそのままキーになる
+pub fn hash(data: &[u8]) -> String {
+    Sha256::digest(data).iter().map(|b| format!("{b:02x}")).collect()
+}
+
+// ブロックを置いておく場所。ディレクトリを指定すればハッシュをファイル名にして保存する。
+#[derive(Debug, Default)]
+pub struct BlockStore {
+    blocks: HashMap<String, Vec<u8>>,
+    dir: Option<PathBuf>,
+}
+
+impl BlockStore {
+    pub fn new(dir: Option<PathBuf>) -> io::Result<Self> {
+        let mut blocks = HashMap::new();
+        if let Some(dir) = &dir {
+            fs::create_dir_all(dir)?;
+            for entry in fs::read_dir(dir)? {
+                let entry = entry?;
+                let data = fs::read(entry.path())?;
+                blocks.insert(hash(&data), data);
+            }
+        }
+        Ok(BlockStore { blocks, dir })
+    }
+
+    pub fn put(&mut self, data: Vec<u8>) -> io::Result<String> {
+        let hash = hash(&data);
+        if let Some(dir) = &self.dir {
+            fs::write(dir.join(&hash), &data)?;
+        }
+        self.blocks.insert(hash.clone(), data);
+        Ok(hash)
+    }
+
+    pub fn get(&self, hash: &str) -> Option<&Vec<u8>> {
+        self.blocks.get(hash)
+    }
+
+    pub fn hashes(&self) -> impl Iterator<Item = &String> {
+        self.blocks.keys()
+    }
+}
@@ -0,0 +1,55 @@
+use libp2p::PeerId;
+
+// 受信したチャットのメッセージ
+#[derive(Debug, Clone, Copy)]
+pub struct Message<'a> {
+    // 書いた人
+    pub from: PeerId,
+    pub topic: &'a str,
+    pub text: &'a str,
+}
+
+impl Message<'_> {
+    // 同じtopicに返す
+    pub fn reply(&self, text: impl Into<String>) -> Reply {
+        Reply::Publish { topic: self.topic.to_string(), text: text.into() }
+    }
+}
+
+// botが返すもの。swarmのタスクが代わりに送る。
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Reply {
+    Publish { topic: String, text: String },
+    Dm { to: PeerId, text: String },
+}
+
+// 自動応答などを作るためのbot。必要なイベントだけ実装すればよい。
+// swarmのイベントループの中で呼ぶので、時間のかかる処理はしないこと。
+pub trait ChatBot: Send {
+    fn on_message(&mut self, _message: &Message<'_>) -> Vec<Reply> {
+        Vec::new()
+    }
+
+    // presenceで新しいpeerが来たとき
+    fn on_peer_joined(&mut self, _peer_id: &PeerId, _nick: Option<&str>) -> Vec<Reply> {
+        Vec::new()
+    }
+
+    fn on_dm(&mut self, _from: &PeerId, _text: &str) -> Vec<Reply> {
+        Vec::new()
+    }
+}
+
+// HELLOにはWORLD、WORLDにはHELLOを返す
+#[derive(Debug, Default)]
+pub struct HelloBot;
+
+impl ChatBot for HelloBot {
+    fn on_message(&mut self, message: &Message<'_>) -> Vec<Reply> {
+        match message.text {
+            "HELLO" => vec![message.reply("WORLD")],
+            "WORLD" => vec![message.reply("HELLO")],
+            _ => Vec::new(),
+        }
+    }
+}
@@ -0,0 +1,166 @@
+use std::time::SystemTime;
+
+use libp2p::PeerId;
+
+use crate::{presence::Status, schedule};
+
+// 標準入力から受け付けるコマンド。"/"で始まる行がコマンドで、それ以外はチャットとしてpublishする。
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Command {
+    // 接続を切り、以後の接続もgossipsubのメッセージも拒否する
+    Block(PeerId),
+    Unblock(PeerId),
+    // 自分宛て(@nick)のメッセージを表示する
+    Mentions,
+    // topicのデスクトップ通知を止める・再開する
+    Mute(String),
+    Unmute(String),
+    // 指定した時刻にpublishする
+    SendAt(SystemTime, String),
+    // nickかpeer idを指定して1対1で送る
+    Dm(String, String),
+    // 経由するpeerを順に指定して送る。最後が宛先。
+    Route(Vec<String>, String),
+    // 1対1のやり取りを表示する
+    Conv(String),
+    // フィンガープリントを表示する。trueなら確かめたものとして記録する。
+    Verify(String, bool),
+    // peerに自分だけの名前を付ける。空なら取り消す。
+    Alias(PeerId, String),
+    // DHT(Kademlia)にkey-valueを置く・取り出す
+    Put(String, String),
+    Get(String),
+    // DHTに署名つきでnickを登録する・nickの持ち主を調べる
+    Claim(String),
+    Whois(String),
+    // コンテンツ(CIDなどの識別子)を持っていると知らせる・持っているpeerを探す
+    Provide(String),
+    FindProviders(String),
+    // ファイルをブロックとして置き、提供する
+    Add(String),
+    // ハッシュのブロックを持っているpeerからもらう。保存先を指定できる。
+    Fetch(String, Option<String>),
+    // DHTのルーティングテーブルとbootstrapの状態
+    KadStats,
+    // 在席状況の一覧
+    Who,
+    // 見たことのあるpeerの一覧(接続中か、最後に見たのはいつか)
+    Peers,
+    // 最近の接続・切断・接続の失敗
+    Connlog,
+    // 最近publishしたメッセージと、何台に送った(届いた)か
+    Sent,
+    // --measure で集めた、伝わるまでにかかった時間
+    Measure,
+    // 外からつながるかどうかの診断
+    Diag,
+    // topicごとのgossipsubのmesh
+    Mesh,
+    // relayのreservationとrelay経由のアドレス
+    Relay,
+    // peerの評判とban中のpeer
+    Reputation,
+    // 自分の状態(online/away)を変える
+    Status(Status),
+}
+
+impl Command {
+    // コマンドでない行はNone
+    pub fn parse(line: &str) -> Option<Result<Command, String>> {
+        let line = line.strip_prefix('/')?;
+        let mut words = line.split_whitespace();
+        let name = words.next().unwrap_or_default();
+        let command = match name {
+            "block" => peer_id(words.next()).map(Command::Block),
+            "unblock" => peer_id(words.next()).map(Command::Unblock),
+            "mentions" => Ok(Command::Mentions),
+            "mute" => topic(words.next()).map(Command::Mute),
+            "unmute" => topic(words.next()).map(Command::Unmute),
+            "send-at" => match (words.next(), words.collect::<Vec<_>>().join(" ")) {
+                (Some(time), text) if !text.is_empty() => schedule::parse_time(time).map(|at| Command::SendAt(at, text)),
+                _ => Err("usage: /send-at <+30s|+10m|+2h|unix secs> <text>".to_string()),
+            },
+            "dm" => match (words.next(), words.collect::<Vec<_>>().join(" ")) {
+                (Some(to), text) if !text.is_empty() => Ok(Command::Dm(to.to_string(), text)),
+                _ => Err("usage: /dm <nick|peer id> <text>".to_string()),
+            },
+            "route" => match (words.next(), words.collect::<Vec<_>>().join(" ")) {
+                (Some(route), text) if !text.is_empty() => {
+                    Ok(Command::Route(route.split(',').map(String::from).collect(), text))
+                }
+                _ => Err("usage: /route <hop>,...,<destination> <text>".to_string()),
+            },
+            "conv" => words
+                .next()
+                .map(|to| Command::Conv(to.to_string()))
+                .ok_or_else(|| "usage: /conv <nick|peer id>".to_string()),
+            "verify" => match (words.next(), words.next()) {
+                (Some(name), None) => Ok(Command::Verify(name.to_string(), false)),
+                (Some(name), Some("ok")) => Ok(Command::Verify(name.to_string(), true)),
+                _ => Err("usage: /verify <nick|peer id> [ok]".to_string()),
+            },
+            "alias" => peer_id(words.next()).map(|p| Command::Alias(p, words.collect::<Vec<_>>().join(" "))),
+            "put" => match (words.next(), words.collect::<Vec<_>>().join(" ")) {
+                (Some(key), value) if !value.is_empty() => Ok(Command::Put(key.to_string(), value)),
+                _ => Err("usage: /put <key> <value>".to_string()),
+            },
+            "get" => words
+                .next()
+                .map(|key| Command::Get(key.to_string()))
+                .ok_or_else(|| "usage: /get <key>".to_string()),
+            "claim" => words
+                .next()
+                .map(|nick| Command::Claim(nick.to_lowercase()))
+                .ok_or_else(|| "usage: /claim <nick>".to_string()),
+            "whois" => words
+                .next()
+                .map(|nick| Command::Whois(nick.to_lowercase()))
+                .ok_or_else(|| "usage: /whois <nick>".to_string()),
+            "provide" => words
+                .next()
+                .map(|cid| Command::Provide(cid.to_string()))
+                .ok_or_else(|| "usage: /provide <cid>".to_string()),
+            "find-providers" => words
+                .next()
+                .map(|cid| Command::FindProviders(cid.to_string()))
+                .ok_or_else(|| "usage: /find-providers <cid>".to_string()),
+            "add" => words
+                .next()
+                .map(|path| Command::Add(path.to_string()))
+                .ok_or_else(|| "usage: /add <path>".to_string()),
+            "fetch" => words
+                .next()
+                .map(|hash| Command::Fetch(hash.to_string(), words.next().map(String::from)))
+                .ok_or_else(|| "usage: /fetch <hash> [output path]".to_string()),
+            "kad" => match words.next() {
+                None | Some("stats") => Ok(Command::KadStats),
+                Some(_) => Err("usage: /kad stats".to_string()),
+            },
+            "who" => Ok(Command::Who),
+            "peers" => Ok(Command::Peers),
+            "connlog" => Ok(Command::Connlog),
+            "sent" => Ok(Command::Sent),
+            "measure" => Ok(Command::Measure),
+            "diag" => Ok(Command::Diag),
+            "mesh" => Ok(Command::Mesh),
+            "relay" => Ok(Command::Relay),
+            "reputation" => Ok(Command::Reputation),
+            "status" => words
+                .next()
+                .and_then(Status::parse)
+                .map(Command::Status)
+                .ok_or_else(|| "usage: /status online|away".to_string()),
+            _ => Err(format!("unknown command: /{name}")),
+        };
+        Some(command)
+    }
+}
+
+fn peer_id(word: Option<&str>) -> Result<PeerId, String> {
+    let word = word.ok_or("peer id is required")?;
+    word.parse().map_err(|e| format!("invalid peer id {word}: {e}"))
+}
+
+fn topic(word: Option<&str>) -> Result<String, String> {
+    word.map(String::from).ok_or_else(|| "topic is required".to_string())
+}
@@ -0,0 +1,69 @@
+use std::{
+    collections::VecDeque,
+    fmt,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use libp2p::{Multiaddr, PeerId};
+
+// 覚えておくイベントの数。古いものから捨てる。
+pub const CAPACITY: usize = 100;
+
+#[derive(Debug, Clone)]
+pub enum Kind {
+    Established { address: Multiaddr, relayed: bool },
+    // causeがNoneなら自分か相手が普通に閉じた
+    Closed { cause: Option<String> },
+    DialFailed { error: String },
+    IncomingFailed { address: Multiaddr, error: String },
+}
+
+#[derive(Debug, Clone)]
+pub struct Entry {
+    pub at: SystemTime,
+    pub peer_id: Option<PeerId>,
+    pub kind: Kind,
+}
+
+impl fmt::Display for Entry {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let secs = self.at.duration_since(UNIX_EPOCH).unwrap_or_default().as_secs() % 86400;
+        write!(f, "{:02}:{:02}:{:02} UTC ", secs / 3600, secs / 60 % 60, secs % 60)?;
+        match &self.peer_id {
+            Some(peer_id) => write!(f, "{peer_id}: ")?,
+            None => write!(f, "unknown peer: ")?,
+        }
+        match &self.kind {
+            Kind::Established { address, relayed: true } => write!(f, "established via {address} (relayed)"),
+            Kind::Established { address, relayed: false } => write!(f, "established via {address}"),
+            Kind::Closed { cause: Some(cause) } => write!(f, "closed: {cause}"),
+            Kind::Closed { cause: None } => write!(f, "closed"),
+            Kind::DialFailed { error } => write!(f, "dial failed: {error}"),
+            Kind::IncomingFailed { address, error } => write!(f, "incoming from {address} failed: {error}"),
+        }
+    }
+}
+
+// 最近の接続の出来事。つながったり切れたりする原因を /connlog で調べる。
+#[derive(Debug, Default)]
+pub struct ConnLog {
+    entries: VecDeque<Entry>,
+}
+
+impl ConnLog {
+    pub fn push(&mut self, peer_id: Option<PeerId>, kind: Kind) {
+        if self.entries.len() == CAPACITY {
+            self.entries.pop_front();
+        }
+        self.entries.push_back(Entry {
+            at: SystemTime::now(),
+            peer_id,
+            kind,
+        });
+    }
+
+    // 古い順
+    pub fn iter(&self) -> impl Iterator<Item = &Entry> {
+        self.entries.iter()
+    }
+}
@@ -0,0 +1,173 @@
+use std::{
+    collections::{HashMap, HashSet},
+    error::Error,
+    fs, io,
+    path::PathBuf,
+};
+
+use libp2p::PeerId;
+
+// フィンガープリントに使う絵文字。1バイトの下位6ビットで選ぶ。
+const EMOJI: [&str; 64] = [
+    "🐶", "🐱", "🐭", "🐹", "🐰", "🦊", "🐻", "🐼", "🐨", "🐯", "🦁", "🐮", "🐷", "🐸", "🐵", "🐔",
+    "🐧", "🐦", "🐤", "🦆", "🦅", "🦉", "🦇", "🐺", "🐗", "🐴", "🦄", "🐝", "🐛", "🦋", "🐌", "🐞",
+    "🐢", "🐍", "🦎", "🐙", "🦑", "🦀", "🐡", "🐠", "🐟", "🐬", "🐳", "🦈", "🐊", "🐅", "🐆", "🦓",
+    "🍎", "🍐", "🍊", "🍋", "🍌", "🍉", "🍇", "🍓", "🍒", "🍑", "🍍", "🥝", "🍅", "🥕", "🌽", "🍄",
+];
+
+// nickを最初に名乗ったpeerを覚えておく(TOFU: trust on first use)。
+// nickは誰でも名乗れるが、PeerIdはgossipsubの署名の鍵から決まるので、
+// 同じnickを別のPeerIdが名乗ったらなりすましを疑う。
+// ファイルを指定すれば "<nick>\t<peer id>\t<verified 0|1>" の行で保存する。
+#[derive(Debug, Default)]
+pub struct Contacts {
+    by_nick: HashMap<String, PeerId>,
+    // /verify でフィンガープリントを確かめたpeer
+    verified: HashSet<PeerId>,
+    path: Option<PathBuf>,
+}
+
+// nickとPeerIdの組を見たときの結果
+#[derive(Debug, PartialEq, Eq)]
+pub enum Binding {
+    New,
+    Known,
+    // 前にこのnickを名乗っていたPeerId
+    Conflict(PeerId),
+}
+
+impl Contacts {
+    pub fn load(path: Option<PathBuf>) -> Result<Self, Box<dyn Error>> {
+        let mut contacts = Contacts {
+            path,
+            ..Default::default()
+        };
+        if let Some(path) = &contacts.path
+            && path.exists()
+        {
+            for line in fs::read_to_string(path)?.lines() {
+                let [nick, peer_id, verified] = line.split('\t').collect::<Vec<_>>()[..] else {
+                    return Err(format!("invalid contact: {line}").into());
+                };
+                let peer_id: PeerId = peer_id.parse()?;
+                contacts.by_nick.insert(nick.to_string(), peer_id);
+                if verified == "1" {
+                    contacts.verified.insert(peer_id);
+                }
+            }
+        }
+        Ok(contacts)
+    }
+
+    // 初めてのnickなら覚える。覚えている組は変えない。
+    pub fn bind(&mut self, nick: &str, peer_id: PeerId) -> io::Result<Binding> {
+        let binding = match self.by_nick.get(&nick.to_lowercase()) {
+            None => {
+                self.by_nick.insert(nick.to_lowercase(), peer_id);
+                self.save()?;
+                Binding::New
+            }
+            Some(known) if *known == peer_id => Binding::Known,
+            Some(known) => Binding::Conflict(*known),
+        };
+        Ok(binding)
+    }
+
+    // 相手と別の手段(対面や電話など)でフィンガープリントを確かめたら呼ぶ
+    pub fn verify(&mut self, peer_id: PeerId) -> io::Result<bool> {
+        let inserted = self.verified.insert(peer_id);
+        self.save()?;
+        Ok(inserted)
+    }
+
+    pub fn is_verified(&self, peer_id: &PeerId) -> bool {
+        self.verified.contains(peer_id)
+    }
+
+    fn save(&self) -> io::Result<()> {
+        let Some(path) = &self.path else {
+            return Ok(());
+        };
+        let text: String = self
+            .by_nick
+            .iter()
+            .map(|(nick, peer_id)| format!("{nick}\t{peer_id}\t{}\n", u8::from(self.is_verified(peer_id))))
+            .collect();
+        fs::write(path, text)
+    }
+
+    // メッセージに付ける表示。署名はgossipsub(ValidationMode::Strict)が確認済みなので、
+    // ここでは署名した鍵(PeerId)と名乗っているnickが合っているかを見る。
+    pub fn badge(&self, from: &PeerId, nick: Option<&str>) -> String {
+        let Some(nick) = nick else {
+            return format!("signed by {from}");
+        };
+        match self.by_nick.get(&nick.to_lowercase()) {
+            Some(known) if known == from && self.is_verified(from) => format!("✓✓ {nick} (verified)"),
+            Some(known) if known == from => format!("✓ {nick}"),
+            Some(known) => format!("⚠ {nick} is claimed by {from}, first seen as {known}"),
+            None => format!("? {nick}"),
+        }
+    }
+}
+
+// 相手と読み合わせて確かめるための短い表示。PeerIdの末尾8バイトから作る。
+// 絵文字とhexのどちらで比べてもよい。
+pub fn fingerprint(peer_id: &PeerId) -> String {
+    let bytes = peer_id.to_bytes();
+    let tail = &bytes[bytes.len().saturating_sub(8)..];
+    let emoji: String = tail.iter().map(|b| EMOJI[usize::from(b & 0x3f)]).collect::<Vec<_>>().join(" ");
+    let hex: String = tail
+        .chunks(2)
+        .map(|c| c.iter().map(|b| format!("{b:02x}")).collect::<String>())
+        .collect::<Vec<_>>()
+        .join(" ");
+    format!("{emoji}  ({hex})")
+}
+
+// peerに付けた自分だけの名前(/alias)。PeerIdの代わりに表示する。
+// ファイルを指定すれば "<peer id>\t<alias>" の行で保存する。
+#[derive(Debug, Default)]
+pub struct Aliases {
+    names: HashMap<PeerId, String>,
+    path: Option<PathBuf>,
+}
+
+impl Aliases {
+    pub fn load(path: Option<PathBuf>) -> Result<Self, Box<dyn Error>> {
+        let mut names = HashMap::new();
+        if let Some(path) = &path
+            && path.exists()
+        {
+            for line in fs::read_to_string(path)?.lines() {
+                let Some((peer_id, alias)) = line.split_once('\t') else {
+                    return Err(format!("invalid alias: {line}").into());
+                };
+                names.insert(peer_id.parse()?, alias.to_string());
+            }
+        }
+        Ok(Aliases { names, path })
+    }
+
+    pub fn get(&self, peer_id: &PeerId) -> Option<&str> {
+        self.names.get(peer_id).map(String::as_str)
+    }
+
+    pub fn find(&self, alias: &str) -> Option<PeerId> {
+        self.names.iter().find(|(_, a)| a.as_str() == alias).map(|(p, _)| *p)
+    }
+
+    // 空の名前なら取り消す
+    pub fn set(&mut self, peer_id: PeerId, alias: String) -> io::Result<()> {
+        if alias.is_empty() {
+            self.names.remove(&peer_id);
+        } else {
+            self.names.insert(peer_id, alias);
+        }
+        let Some(path) = &self.path else {
+            return Ok(());
+        };
+        let text: String = self.names.iter().map(|(p, a)| format!("{p}\t{a}\n")).collect();
+        fs::write(path, text)
+    }
+}
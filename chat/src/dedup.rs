@@ -0,0 +1,46 @@
+use std::collections::VecDeque;
+
+use libp2p::{PeerId, gossipsub::MessageId};
+
+// 覚えておくメッセージの数
+const CAPACITY: usize = 1024;
+
+// 同じメッセージを2回表示しないため、最近表示したものを覚えておく(LRU)。
+// MessageIdは受け取った側で時刻を混ぜて作っているので、書いた人とシーケンス番号で見分ける。
+#[derive(Debug)]
+pub struct Displayed {
+    keys: VecDeque<String>,
+}
+
+impl Default for Displayed {
+    fn default() -> Self {
+        Displayed {
+            keys: VecDeque::with_capacity(CAPACITY),
+        }
+    }
+}
+
+impl Displayed {
+    // 初めてならtrue。覚えていたものは新しい扱いにする。
+    pub fn first_time(&mut self, key: &str) -> bool {
+        if let Some(pos) = self.keys.iter().position(|k| k == key) {
+            if let Some(k) = self.keys.remove(pos) {
+                self.keys.push_back(k);
+            }
+            return false;
+        }
+        if self.keys.len() == CAPACITY {
+            self.keys.pop_front();
+        }
+        self.keys.push_back(key.to_string());
+        true
+    }
+}
+
+// 表示で重複を見分けるキー。署名のないメッセージはMessageIdで代用する。
+pub fn key(source: Option<PeerId>, sequence_number: Option<u64>, id: &MessageId) -> String {
+    match (source, sequence_number) {
+        (Some(source), Some(seqno)) => format!("{source}/{seqno}"),
+        _ => id.to_string(),
+    }
+}
@@ -0,0 +1,99 @@
+use std::{
+    collections::{HashMap, VecDeque},
+    fmt,
+    time::Instant,
+};
+
+use libp2p::{PeerId, request_response::OutboundRequestId};
+
+// 覚えておく送信の数。古いものから捨てる。
+pub const CAPACITY: usize = 50;
+
+// 自分がpublishしたメッセージを誰に送ったか
+#[derive(Debug)]
+pub struct Sent {
+    pub number: u64,
+    pub at: Instant,
+    pub text: String,
+    // gossipsubで送ったならtrue。falseならmeshがなくて直接送った。
+    pub gossipsub: bool,
+    pub sent_to: Vec<PeerId>,
+    // 直接送ったときに受け取ったと返事をくれたpeerと、断られたか届かなかったpeer
+    pub accepted: Vec<PeerId>,
+    pub failed: Vec<PeerId>,
+}
+
+impl Sent {
+    // 直接送ったものの返事がすべて来たか
+    pub fn is_settled(&self) -> bool {
+        self.accepted.len() + self.failed.len() >= self.sent_to.len()
+    }
+}
+
+impl fmt::Display for Sent {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "#{} {}s ago ", self.number, self.at.elapsed().as_secs())?;
+        if self.gossipsub {
+            // gossipsubは受け取ったかを返さないので、送った数だけ
+            write!(f, "gossipsub to {} peers", self.sent_to.len())?;
+        } else {
+            write!(f, "direct, {}/{} accepted", self.accepted.len(), self.sent_to.len())?;
+            if !self.failed.is_empty() {
+                write!(f, ", {} failed", self.failed.len())?;
+            }
+        }
+        write!(f, ": {}", self.text)
+    }
+}
+
+// 最近publishしたメッセージ。/sent で表示する。
+#[derive(Debug, Default)]
+pub struct Deliveries {
+    sent: VecDeque<Sent>,
+    // 直接送ったrequestとその送信の番号
+    requests: HashMap<OutboundRequestId, u64>,
+    next: u64,
+}
+
+impl Deliveries {
+    // 番号を返す
+    pub fn push(&mut self, text: &str, gossipsub: bool, sent_to: Vec<PeerId>) -> u64 {
+        if self.sent.len() == CAPACITY
+            && let Some(old) = self.sent.pop_front()
+        {
+            self.requests.retain(|_, number| *number != old.number);
+        }
+        self.next += 1;
+        self.sent.push_back(Sent {
+            number: self.next,
+            at: Instant::now(),
+            text: text.to_string(),
+            gossipsub,
+            sent_to,
+            accepted: Vec::new(),
+            failed: Vec::new(),
+        });
+        self.next
+    }
+
+    pub fn add_request(&mut self, request_id: OutboundRequestId, number: u64) {
+        self.requests.insert(request_id, number);
+    }
+
+    // 直接送ったものの返事。自分がpublishしたものでなければNone。
+    pub fn answered(&mut self, request_id: OutboundRequestId, peer_id: PeerId, accepted: bool) -> Option<&Sent> {
+        let number = self.requests.remove(&request_id)?;
+        let sent = self.sent.iter_mut().find(|s| s.number == number)?;
+        if accepted {
+            sent.accepted.push(peer_id);
+        } else {
+            sent.failed.push(peer_id);
+        }
+        Some(sent)
+    }
+
+    // 古い順
+    pub fn iter(&self) -> impl Iterator<Item = &Sent> {
+        self.sent.iter()
+    }
+}
@@ -0,0 +1,17 @@
+use libp2p::{Multiaddr, PeerId};
+
+// /diag で表示するためにイベントから集めておくもの
+#[derive(Debug, Default)]
+pub struct Diagnostics {
+    // identifyで他のpeerから見えていると教えてもらったアドレス
+    pub observed: Vec<(PeerId, Multiaddr)>,
+    // UPnPの最後の結果
+    pub upnp: Option<String>,
+}
+
+impl Diagnostics {
+    pub fn observed(&mut self, peer_id: PeerId, addr: Multiaddr) {
+        self.observed.retain(|(p, _)| *p != peer_id);
+        self.observed.push((peer_id, addr));
+    }
+}
@@ -0,0 +1,33 @@
+use libp2p::{
+    StreamProtocol,
+    request_response::{self, ProtocolSupport},
+};
+use serde::{Deserialize, Serialize};
+
+// gossipsubを通さず、相手に直接送るためのrequest-response
+pub const PROTOCOL: StreamProtocol = StreamProtocol::new("/chat/direct/1");
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub enum DirectRequest {
+    // meshができる前でも届くよう、topicへのメッセージを直接送る
+    Publish { topic: String, text: String },
+    // 1対1のメッセージ(/dm)
+    Dm { text: String },
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub enum DirectResponse {
+    Accepted,
+    // 受け取らなかった理由
+    Rejected(String),
+}
+
+pub type Behaviour = request_response::cbor::Behaviour<DirectRequest, DirectResponse>;
+
+// 相手から来るものは信用できないので、--max-message-size を超えたらデコードする前に捨てる
+pub fn behaviour(max_message_size: u64) -> Behaviour {
+    let codec = request_response::cbor::codec::Codec::<DirectRequest, DirectResponse>::default()
+        .set_request_size_maximum(max_message_size)
+        .set_response_size_maximum(max_message_size);
+    Behaviour::with_codec(codec, [(PROTOCOL, ProtocolSupport::Full)], request_response::Config::default())
+}
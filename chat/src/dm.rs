@@ -0,0 +1,50 @@
+use std::{
+    collections::HashMap,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use libp2p::PeerId;
+
+// 1対1のメッセージ
+#[derive(Debug, Clone)]
+pub struct DirectMessage {
+    // 自分が送ったものならtrue
+    pub outgoing: bool,
+    pub text: String,
+    pub at: SystemTime,
+}
+
+impl std::fmt::Display for DirectMessage {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let secs = self.at.duration_since(UNIX_EPOCH).unwrap_or_default().as_secs() % 86400;
+        write!(
+            f,
+            "{:02}:{:02}:{:02} UTC {} {}",
+            secs / 3600,
+            secs / 60 % 60,
+            secs % 60,
+            if self.outgoing { ">" } else { "<" },
+            self.text
+        )
+    }
+}
+
+// peerごとのやり取り。/conv で表示する。
+#[derive(Debug, Default)]
+pub struct Conversations {
+    peers: HashMap<PeerId, Vec<DirectMessage>>,
+}
+
+impl Conversations {
+    pub fn push(&mut self, peer_id: PeerId, outgoing: bool, text: String) {
+        self.peers.entry(peer_id).or_default().push(DirectMessage {
+            outgoing,
+            text,
+            at: SystemTime::now(),
+        });
+    }
+
+    pub fn get(&self, peer_id: &PeerId) -> &[DirectMessage] {
+        self.peers.get(peer_id).map(Vec::as_slice).unwrap_or_default()
+    }
+}
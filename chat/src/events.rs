@@ -0,0 +1,43 @@
+use libp2p::{Multiaddr, PeerId, gossipsub};
+use tokio::sync::broadcast;
+
+// 購読者ごとに溜めておける数。これより遅れると古いものから落とす。
+pub const CAPACITY: usize = 256;
+
+// swarmのタスクで起きたことのうち、表示やブリッジなど外に知らせるもの。
+// 送る側は誰が受け取るかを気にしない。
+#[derive(Debug, Clone)]
+pub enum NodeEvent {
+    // 順番を揃えて表示してよくなったチャットのメッセージ
+    Message {
+        // 書いた人
+        from: PeerId,
+        // 中継してくれたpeer
+        peer_id: PeerId,
+        id: gossipsub::MessageId,
+        topic: gossipsub::TopicHash,
+        text: String,
+    },
+    // 順番待ちの間に届かなかったメッセージの数
+    Missed { from: PeerId, count: u64 },
+    Dm { from: PeerId, text: String },
+    PeerConnected { peer_id: PeerId, address: Multiaddr, relayed: bool },
+    NatStatusChanged { old: String, new: String },
+}
+
+pub fn bus() -> broadcast::Sender<NodeEvent> {
+    broadcast::channel(CAPACITY).0
+}
+
+// 次のイベント。遅れて落としたものは数だけ知らせて読み続ける。送る側がなくなったらNone。
+pub async fn recv(rx: &mut broadcast::Receiver<NodeEvent>, subscriber: &str) -> Option<NodeEvent> {
+    loop {
+        match rx.recv().await {
+            Ok(event) => return Some(event),
+            Err(broadcast::error::RecvError::Lagged(n)) => {
+                tracing::warn!(subscriber, skipped = n, "event subscriber lagged");
+            }
+            Err(broadcast::error::RecvError::Closed) => return None,
+        }
+    }
+}
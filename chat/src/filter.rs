@@ -0,0 +1,84 @@
+use std::{error::Error, fs};
+
+use regex::Regex;
+
+use crate::options::Options;
+
+// 受信メッセージを表示する前に通すフィルタ。
+// Okなら表示する本文(伏せ字にするなど書き換えてもよい)、Errなら拒否した理由を返す。
+pub trait Filter: Send + Sync {
+    fn apply(&self, text: String) -> Result<String, String>;
+}
+
+// 長すぎるメッセージを拒否する
+pub struct MaxLength(pub usize);
+
+impl Filter for MaxLength {
+    fn apply(&self, text: String) -> Result<String, String> {
+        let len = text.chars().count();
+        if len > self.0 {
+            return Err(format!("too long ({len} > {} chars)", self.0));
+        }
+        Ok(text)
+    }
+}
+
+// 登録した単語を伏せ字にする
+pub struct WordList(pub Vec<String>);
+
+impl Filter for WordList {
+    fn apply(&self, mut text: String) -> Result<String, String> {
+        for word in &self.0 {
+            // 大文字で送られてくるので大文字にしてから比べる
+            let word = word.to_uppercase();
+            text = text.replace(&word, &"*".repeat(word.chars().count()));
+        }
+        Ok(text)
+    }
+}
+
+// 正規表現にマッチしたら拒否する
+pub struct Deny(pub Regex);
+
+impl Filter for Deny {
+    fn apply(&self, text: String) -> Result<String, String> {
+        if self.0.is_match(&text) {
+            return Err(format!("matched {}", self.0));
+        }
+        Ok(text)
+    }
+}
+
+// 登録した順に適用する。どれかが拒否したらそこで終わり。
+#[derive(Default)]
+pub struct Filters(Vec<Box<dyn Filter>>);
+
+impl Filters {
+    pub fn from_options(opts: &Options) -> Result<Self, Box<dyn Error>> {
+        let mut filters = Filters::default();
+        if let Some(max) = opts.filter_max_length {
+            filters.push(MaxLength(max));
+        }
+        if let Some(path) = &opts.filter_words {
+            let words = fs::read_to_string(path)?
+                .lines()
+                .map(str::trim)
+                .filter(|w| !w.is_empty())
+                .map(String::from)
+                .collect();
+            filters.push(WordList(words));
+        }
+        for pattern in &opts.filter_deny {
+            filters.push(Deny(Regex::new(pattern)?));
+        }
+        Ok(filters)
+    }
+
+    pub fn push(&mut self, filter: impl Filter + 'static) {
+        self.0.push(Box::new(filter));
+    }
+
+    pub fn apply(&self, text: String) -> Result<String, String> {
+        self.0.iter().try_fold(text, |text, filter| filter.apply(text))
+    }
+}
@@ -0,0 +1,97 @@
+// イベントが起きたらシェルのコマンドを実行し、イベントをJSONにして標準入力に渡す。
+//  chat --on-message ./urgent.sh --on-message-match '(?i)urgent' --on-connect ./connected.sh --on-nat-status ./nat.sh
+//
+// 渡す内容("timestamp"はUNIX秒)
+//  {"event": "message", "topic": "...", "from": "<peer id>", "message_id": "...", "text": "...", "timestamp": ...}
+//  {"event": "peer_connected", "peer_id": "...", "address": "<multiaddr>", "relayed": false, "timestamp": ...}
+//  {"event": "nat_status_changed", "old": "...", "new": "...", "timestamp": ...}
+
+use std::{
+    error::Error,
+    process::Stdio,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use regex::Regex;
+use serde_json::{Value, json};
+use tokio::{io::AsyncWriteExt, process::Command};
+
+use crate::options::Options;
+
+#[derive(Debug, Default)]
+pub struct Hooks {
+    on_message: Option<String>,
+    // これにマッチしたメッセージのときだけ実行する
+    message_pattern: Option<Regex>,
+    on_connect: Option<String>,
+    on_nat_status: Option<String>,
+}
+
+impl Hooks {
+    pub fn from_options(opts: &Options) -> Result<Self, Box<dyn Error>> {
+        Ok(Hooks {
+            on_message: opts.on_message.clone(),
+            message_pattern: opts.on_message_match.as_deref().map(Regex::new).transpose()?,
+            on_connect: opts.on_connect.clone(),
+            on_nat_status: opts.on_nat_status.clone(),
+        })
+    }
+
+    pub fn message(&self, topic: &str, from: &str, message_id: &str, text: &str) {
+        if let Some(pattern) = &self.message_pattern
+            && !pattern.is_match(text)
+        {
+            return;
+        }
+        run(
+            self.on_message.as_deref(),
+            json!({"event": "message", "topic": topic, "from": from, "message_id": message_id, "text": text}),
+        );
+    }
+
+    pub fn connected(&self, peer_id: &str, address: &str, relayed: bool) {
+        run(
+            self.on_connect.as_deref(),
+            json!({"event": "peer_connected", "peer_id": peer_id, "address": address, "relayed": relayed}),
+        );
+    }
+
+    pub fn nat_status(&self, old: &str, new: &str) {
+        run(
+            self.on_nat_status.as_deref(),
+            json!({"event": "nat_status_changed", "old": old, "new": new}),
+        );
+    }
+}
+
+// 別タスクで実行する。終わるのは待たない。
+fn run(command: Option<&str>, mut event: Value) {
+    let Some(command) = command else {
+        return;
+    };
+    event["timestamp"] = json!(SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default().as_secs());
+    let command = command.to_string();
+    tokio::spawn(async move {
+        let child = Command::new("sh")
+            .arg("-c")
+            .arg(&command)
+            .stdin(Stdio::piped())
+            .spawn();
+        let mut child = match child {
+            Ok(child) => child,
+            Err(e) => {
+                println!("Hook error for {command}: {e:?}");
+                return;
+            }
+        };
+        if let Some(mut stdin) = child.stdin.take() {
+            // 読まずに終わるコマンドもあるので、書けなくても気にしない
+            let _ = stdin.write_all(format!("{event}\n").as_bytes()).await;
+        }
+        match child.wait().await {
+            Ok(status) if status.success() => {}
+            Ok(status) => println!("Hook {command} exited with {status}"),
+            Err(e) => println!("Hook error for {command}: {e:?}"),
+        }
+    });
+}
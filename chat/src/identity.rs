@@ -0,0 +1,78 @@
+use std::{error::Error, fs, path::Path, str::FromStr};
+
+use base64::{Engine, engine::general_purpose::STANDARD};
+use libp2p::identity::Keypair;
+
+// ノードの鍵の種類。PeerIdはこの鍵から決まる。
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum KeyType {
+    #[default]
+    Ed25519,
+    // Ethereumなどと同じ曲線
+    Secp256k1,
+    // ECDSA P-256
+    Ecdsa,
+}
+
+impl FromStr for KeyType {
+    type Err = Box<dyn Error>;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "ed25519" => Ok(KeyType::Ed25519),
+            "secp256k1" => Ok(KeyType::Secp256k1),
+            "ecdsa" => Ok(KeyType::Ecdsa),
+            _ => Err(format!("unknown key type: {s}").into()),
+        }
+    }
+}
+
+pub fn generate(key_type: KeyType) -> Keypair {
+    match key_type {
+        KeyType::Ed25519 => Keypair::generate_ed25519(),
+        KeyType::Secp256k1 => Keypair::generate_secp256k1(),
+        KeyType::Ecdsa => Keypair::generate_ecdsa(),
+    }
+}
+
+// 鍵ファイルがあれば読み込み、なければ作って保存する。
+// 中身はlibp2pのprotobuf形式なので鍵の種類はファイルから分かる。
+pub fn load_or_generate(path: &Path, key_type: KeyType) -> Result<Keypair, Box<dyn Error>> {
+    if path.exists() {
+        let keypair = Keypair::from_protobuf_encoding(&fs::read(path)?)?;
+        println!("Loaded {:?} key from {}", keypair.key_type(), path.display());
+        return Ok(keypair);
+    }
+    let keypair = generate(key_type);
+    save(path, &keypair)?;
+    println!("Generated {key_type:?} key to {}", path.display());
+    Ok(keypair)
+}
+
+// libp2pのprotobuf形式で書き出す。go-libp2pやjs-libp2pでもそのまま読める。
+pub fn save(path: &Path, keypair: &Keypair) -> Result<(), Box<dyn Error>> {
+    fs::write(path, keypair.to_protobuf_encoding()?)?;
+    // 秘密鍵なので自分だけ読めるようにしておく
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        fs::set_permissions(path, fs::Permissions::from_mode(0o600))?;
+    }
+    Ok(())
+}
+
+// IPFSのconfig(Identity.PrivKey)などで使われるbase64形式
+pub fn to_base64(keypair: &Keypair) -> Result<String, Box<dyn Error>> {
+    Ok(STANDARD.encode(keypair.to_protobuf_encoding()?))
+}
+
+// 他の実装で作った鍵を読み込む。protobufのバイナリでもそれをbase64にしたテキストでもよい。
+pub fn import(path: &Path) -> Result<Keypair, Box<dyn Error>> {
+    let bytes = fs::read(path)?;
+    if let Ok(keypair) = Keypair::from_protobuf_encoding(&bytes) {
+        return Ok(keypair);
+    }
+    let text = String::from_utf8(bytes)?;
+    let decoded = STANDARD.decode(text.trim())?;
+    Ok(Keypair::from_protobuf_encoding(&decoded)?)
+}
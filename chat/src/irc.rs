@@ -0,0 +1,311 @@
+// IRCクライアントから使えるようにする簡易IRCサーバ。
+//  chat --irc 127.0.0.1:6667
+// IRCのチャンネル #name はgossipsubのtopic name に対応する。
+// JOINでsubscribe、PRIVMSGでpublish、受信したメッセージはJOINしているクライアントにPRIVMSGで届ける。
+// PARTしてもそのクライアントに届けなくなるだけで、unsubscribeはしない(他のクライアントがいるかもしれないため)。
+// PRIVMSG <nick> はDMとして送り、受け取ったDMは接続しているすべてのクライアントに届ける。
+
+use std::{
+    io,
+    sync::atomic::{AtomicU64, Ordering},
+};
+
+use tokio::{
+    io::{AsyncBufReadExt, AsyncWriteExt, BufReader},
+    net::{TcpListener, TcpStream},
+    select,
+    sync::{Mutex, broadcast, mpsc},
+};
+
+const SERVER: &str = "chat";
+
+// IRCクライアントからswarmへの依頼
+#[derive(Debug)]
+pub enum Request {
+    Join(String),
+    Publish { topic: String, text: String },
+    // to はIRCのnick(nick_forで作ったもの)かpeer id、/alias の名前
+    Dm { to: String, text: String },
+}
+
+// IRCクライアントに届けるメッセージ
+#[derive(Debug, Clone)]
+struct Delivery {
+    // NoneならDM
+    topic: Option<String>,
+    from: String,
+    text: String,
+    // 送ったIRCクライアント自身には返さない
+    origin: Option<u64>,
+}
+
+pub struct Gateway {
+    requests: Mutex<mpsc::Receiver<Request>>,
+    deliveries: broadcast::Sender<Delivery>,
+}
+
+impl Gateway {
+    pub async fn listen(addr: &str) -> io::Result<Self> {
+        let listener = TcpListener::bind(addr).await?;
+        println!("IRC gateway listening on {}", listener.local_addr()?);
+        let (req_tx, req_rx) = mpsc::channel(64);
+        let (deliveries, _) = broadcast::channel(256);
+
+        let client_deliveries = deliveries.clone();
+        tokio::spawn(async move {
+            let next_id = AtomicU64::new(0);
+            loop {
+                match listener.accept().await {
+                    Ok((stream, peer)) => {
+                        println!("IRC client connected from {peer}");
+                        let id = next_id.fetch_add(1, Ordering::Relaxed);
+                        tokio::spawn(client(id, stream, req_tx.clone(), client_deliveries.clone()));
+                    }
+                    Err(e) => println!("IRC accept error: {e:?}"),
+                }
+            }
+        });
+
+        Ok(Gateway {
+            requests: Mutex::new(req_rx),
+            deliveries,
+        })
+    }
+
+    pub async fn recv(&self) -> Option<Request> {
+        self.requests.lock().await.recv().await
+    }
+
+    // p2p側で受け取ったメッセージをtopicにJOINしているクライアントに届ける
+    pub fn deliver(&self, topic: &str, from: &str, text: &str) {
+        // 誰も接続していないときはErrになるだけなので無視する
+        let _ = self.deliveries.send(Delivery {
+            topic: Some(topic.to_string()),
+            from: from.to_string(),
+            text: text.to_string(),
+            origin: None,
+        });
+    }
+
+    // 受け取ったDMはどのクライアント宛てか分からないので全員に届ける
+    pub fn deliver_dm(&self, from: &str, text: &str) {
+        let _ = self.deliveries.send(Delivery {
+            topic: None,
+            from: from.to_string(),
+            text: text.to_string(),
+            origin: None,
+        });
+    }
+}
+
+// IRCのnickに使えるようPeerIdを短くする
+pub fn nick_for(peer_id: &str) -> String {
+    let start = peer_id.len().saturating_sub(8);
+    format!("p2p-{}", &peer_id[start..])
+}
+
+// nickやchannel名に使えない文字(空白と制御文字)を置き換える
+fn sanitize_name(name: &str) -> String {
+    name.chars().map(|c| if c.is_whitespace() || c.is_control() { '_' } else { c }).collect()
+}
+
+// 相手から来た本文に改行があるとそこから別のIRCコマンドとして解釈されるので、行ごとにPRIVMSGを分ける
+fn privmsg(from: &str, target: &str, text: &str) -> String {
+    let from = sanitize_name(from);
+    let target = sanitize_name(target);
+    text.split(['\r', '\n'])
+        .filter(|line| !line.is_empty())
+        .map(|line| format!(":{from}!{from}@{SERVER} PRIVMSG {target} :{}\r\n", line.replace('\0', "")))
+        .collect()
+}
+
+async fn client(
+    id: u64,
+    stream: TcpStream,
+    requests: mpsc::Sender<Request>,
+    deliveries: broadcast::Sender<Delivery>,
+) {
+    let (reader, mut writer) = stream.into_split();
+    let mut lines = BufReader::new(reader).lines();
+    let mut incoming = deliveries.subscribe();
+    let mut nick = String::from("*");
+    let mut channels: Vec<String> = Vec::new();
+
+    loop {
+        let out = select! {
+            line = lines.next_line() => {
+                let Ok(Some(line)) = line else { break };
+                match handle_line(&line, id, &mut nick, &mut channels, &requests, &deliveries).await {
+                    Some(out) => out,
+                    None => break,
+                }
+            }
+            delivery = incoming.recv() => match delivery {
+                Ok(d) if d.origin == Some(id) => continue,
+                Ok(Delivery { topic: Some(topic), from, text, .. }) if channels.contains(&topic) => {
+                    privmsg(&from, &format!("#{topic}"), &text)
+                }
+                Ok(Delivery { topic: None, from, text, .. }) => privmsg(&from, &nick, &text),
+                Ok(_) => continue,
+                // 遅すぎて取りこぼした分は諦める
+                Err(broadcast::error::RecvError::Lagged(_)) => continue,
+                Err(broadcast::error::RecvError::Closed) => break,
+            },
+        };
+        if !out.is_empty() && writer.write_all(out.as_bytes()).await.is_err() {
+            break;
+        }
+    }
+    println!("IRC client {nick} disconnected");
+}
+
+// 1行処理して、クライアントに返す文字列を返す。Noneなら切断する。
+async fn handle_line(
+    line: &str,
+    id: u64,
+    nick: &mut String,
+    channels: &mut Vec<String>,
+    requests: &mpsc::Sender<Request>,
+    deliveries: &broadcast::Sender<Delivery>,
+) -> Option<String> {
+    let (command, rest) = line.split_once(' ').unwrap_or((line, ""));
+    let reply = match command.to_uppercase().as_str() {
+        "NICK" => {
+            // 空のnickにはしない
+            let name = sanitize_name(rest.trim());
+            if !name.is_empty() {
+                *nick = name;
+            }
+            String::new()
+        }
+        "USER" => format!(":{SERVER} 001 {nick} :Welcome to the libp2p chat IRC gateway\r\n"),
+        "PING" => format!(":{SERVER} PONG {SERVER} {rest}\r\n"),
+        "JOIN" => {
+            let mut out = String::new();
+            for channel in rest.split_whitespace().next().unwrap_or("").split(',') {
+                let Some(topic) = channel.strip_prefix('#').filter(|t| !t.is_empty()) else { continue };
+                if !channels.iter().any(|c| c == topic) {
+                    channels.push(topic.to_string());
+                    requests.send(Request::Join(topic.to_string())).await.ok()?;
+                }
+                out.push_str(&format!(":{nick}!{nick}@{SERVER} JOIN #{topic}\r\n"));
+            }
+            out
+        }
+        "PART" => {
+            let mut out = String::new();
+            for channel in rest.split_whitespace().next().unwrap_or("").split(',') {
+                let Some(topic) = channel.strip_prefix('#').filter(|t| !t.is_empty()) else { continue };
+                channels.retain(|c| c != topic);
+                out.push_str(&format!(":{nick}!{nick}@{SERVER} PART #{topic}\r\n"));
+            }
+            out
+        }
+        "PRIVMSG" => {
+            let (target, text) = rest.split_once(' ').unwrap_or((rest, ""));
+            let text = text.strip_prefix(':').unwrap_or(text);
+            if target.is_empty() || target == "#" {
+                return Some(format!(":{SERVER} 411 {nick} :No recipient given\r\n"));
+            }
+            match target.strip_prefix('#') {
+                Some(topic) => {
+                    let text = format!("<{nick}> {text}");
+                    requests
+                        .send(Request::Publish { topic: topic.to_string(), text: text.clone() })
+                        .await
+                        .ok()?;
+                    // 同じノードにつないでいる他のIRCクライアントにも見せる
+                    let _ = deliveries.send(Delivery {
+                        topic: Some(topic.to_string()),
+                        from: nick.clone(),
+                        text,
+                        origin: Some(id),
+                    });
+                    String::new()
+                }
+                None => {
+                    requests
+                        .send(Request::Dm { to: target.to_string(), text: text.to_string() })
+                        .await
+                        .ok()?;
+                    String::new()
+                }
+            }
+        }
+        "QUIT" => return None,
+        _ => String::new(),
+    };
+    Some(reply)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn privmsg_splits_lines() {
+        let out = privmsg("p2p-abc", "#test-net", "hello\r\nQUIT :bye\nworld");
+        assert_eq!(
+            out,
+            ":p2p-abc!p2p-abc@chat PRIVMSG #test-net :hello\r\n\
+             :p2p-abc!p2p-abc@chat PRIVMSG #test-net :QUIT :bye\r\n\
+             :p2p-abc!p2p-abc@chat PRIVMSG #test-net :world\r\n"
+        );
+    }
+
+    #[test]
+    fn privmsg_drops_nul_and_empty_lines() {
+        assert_eq!(privmsg("a", "b", "x\0y\r\n\r\n"), ":a!a@chat PRIVMSG b :xy\r\n");
+        assert_eq!(privmsg("a", "b", "\n"), "");
+    }
+
+    // 1行処理したときの返事と、swarmへの依頼
+    async fn handle(line: &str, nick: &mut String, channels: &mut Vec<String>) -> (Option<String>, Vec<Request>) {
+        let (tx, mut rx) = mpsc::channel(8);
+        let (deliveries, _) = broadcast::channel(8);
+        let reply = handle_line(line, 0, nick, channels, &tx, &deliveries).await;
+        drop(tx);
+        let mut requests = Vec::new();
+        while let Some(request) = rx.recv().await {
+            requests.push(request);
+        }
+        (reply, requests)
+    }
+
+    #[tokio::test]
+    async fn handle_line_malformed() {
+        let (mut nick, mut channels) = ("alice".to_string(), Vec::new());
+        for line in ["", " ", "JOIN", "JOIN #", "JOIN ,,#", "PART", "PART #", "NICK", "PING", "UNKNOWN x y", "\u{0}"] {
+            let (reply, requests) = handle(line, &mut nick, &mut channels).await;
+            assert!(reply.is_some(), "{line:?} must not disconnect");
+            assert!(requests.is_empty(), "{line:?} must not reach the swarm");
+        }
+        assert!(channels.is_empty());
+        for line in ["PRIVMSG", "PRIVMSG  :hi", "PRIVMSG # :hi"] {
+            let (reply, requests) = handle(line, &mut nick, &mut channels).await;
+            assert!(reply.unwrap().contains(" 411 "), "{line:?}");
+            assert!(requests.is_empty(), "{line:?}");
+        }
+    }
+
+    #[tokio::test]
+    async fn handle_line_routes_requests() {
+        let (mut nick, mut channels) = ("*".to_string(), Vec::new());
+        handle("NICK bad\tnick\r", &mut nick, &mut channels).await;
+        assert_eq!(nick, "bad_nick");
+        handle("NICK ", &mut nick, &mut channels).await;
+        assert_eq!(nick, "bad_nick");
+        let (_, requests) = handle("JOIN #a,b,#c", &mut nick, &mut channels).await;
+        assert!(matches!(&requests[..], [Request::Join(a), Request::Join(c)] if a == "a" && c == "c"));
+        let (_, requests) = handle("PRIVMSG #a :hi", &mut nick, &mut channels).await;
+        assert!(matches!(&requests[..], [Request::Publish { topic, text }] if topic == "a" && text == "<bad_nick> hi"));
+        let (_, requests) = handle("PRIVMSG p2p-abc :hi", &mut nick, &mut channels).await;
+        assert!(matches!(&requests[..], [Request::Dm { to, text }] if to == "p2p-abc" && text == "hi"));
+        assert_eq!(handle("QUIT", &mut nick, &mut channels).await.0, None);
+    }
+
+    #[test]
+    fn privmsg_sanitizes_names() {
+        assert_eq!(privmsg("a b\r\n", "#t\0", "x"), ":a___!a___@chat PRIVMSG #t_ :x\r\n");
+    }
+}
@@ -0,0 +1,28 @@
+use std::time::{Duration, Instant};
+
+// --kad-bootstrap-interval を指定しなかったときの間隔(libp2pの自動bootstrapと同じ5分)
+pub const DEFAULT_BOOTSTRAP_INTERVAL: Duration = Duration::from_secs(5 * 60);
+
+// DHTのbootstrapの状態。/kad stats で表示する。
+#[derive(Debug, Default)]
+pub struct KadStatus {
+    // ルーティングテーブルが空でbootstrapできなかった。peerが入ったらすぐにやり直す。
+    pub pending: bool,
+    // 最後に終わったbootstrapの時刻と結果
+    pub last: Option<(Instant, Result<(), String>)>,
+    pub bootstraps: u32,
+    pub failures: u32,
+    // ルーティングテーブルに入った・外れたpeerの数(起動してから)
+    pub added: u32,
+    pub removed: u32,
+}
+
+impl KadStatus {
+    pub fn finished(&mut self, result: Result<(), String>) {
+        match &result {
+            Ok(()) => self.bootstraps += 1,
+            Err(_) => self.failures += 1,
+        }
+        self.last = Some((Instant::now(), result));
+    }
+}
@@ -21,24 +21,123 @@
 // #![doc = include_str!("../README.md")]
 
 use std::{
-    collections::hash_map::DefaultHasher,
+    collections::{HashSet, hash_map::DefaultHasher},
     error::Error,
     hash::{Hash, Hasher},
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
     time::Duration,
 };
 
 use futures::stream::StreamExt;
 use libp2p::{
-    Swarm, gossipsub, identity::Keypair, mdns, noise, swarm::{NetworkBehaviour, SwarmEvent}, tcp, yamux
+    Multiaddr, PeerId, Swarm, Transport, allow_block_list, core::{muxing::StreamMuxerBox, transport::{Boxed, timeout::TransportTimeout}, upgrade}, autonat, gossipsub, identify, identity::Keypair, kad::{self, store::RecordStore}, mdns, multiaddr::Protocol, noise, relay, request_response, swarm::{self, NetworkBehaviour, SwarmEvent, behaviour::toggle::Toggle, dial_opts::{DialOpts, PeerCondition}}, tcp, upnp, yamux
 };
-use tokio::{io, io::AsyncBufReadExt, select};
-use tracing_subscriber::EnvFilter;
+use tokio::{io, io::AsyncBufReadExt, select, sync::{broadcast, mpsc::{self, error::TrySendError}}};
+use tracing_appender::{non_blocking::WorkerGuard, rolling::Rotation};
+use tracing_subscriber::{EnvFilter, fmt, prelude::*};
+
+use crate::{
+    blocklist::Blocklist,
+    bot::{ChatBot, Reply},
+    blocks::BlockStore,
+    command::Command,
+    contacts::{Aliases, Binding, Contacts},
+    dedup::Displayed,
+    direct::{DirectRequest, DirectResponse},
+    events::NodeEvent,
+    filter::Filters,
+    hooks::Hooks,
+    mention::Mention,
+    options::{LogFormat, Options},
+    peers::PeerStore,
+    profile::Profile,
+    record::{Recorded, Recorder},
+    relay_status::RelayStatus,
+    reorder::{Delivery, Reorder},
+    reputation::Offense,
+    schedule::Schedule,
+    state::{Fetch, State, lock},
+    subscription::SubscriptionFilter,
+    validation::{recovered_via_gossip, validate, validate_direct, validate_dm},
+    webhook::Webhook,
+};
+
+mod blocklist;
+mod bot;
+mod blocks;
+mod command;
+mod connlog;
+mod contacts;
+mod dedup;
+mod delivery;
+mod direct;
+mod dm;
+mod diag;
+mod events;
+mod filter;
+mod hooks;
+mod identity;
+mod irc;
+mod kad_status;
+mod matrix;
+mod measure;
+mod mention;
+mod mqtt;
+mod nickname;
+mod notify;
+mod outbox;
+mod presence;
+mod profile;
+mod px;
+mod options;
+mod peers;
+mod record;
+mod relay_status;
+mod reorder;
+mod reputation;
+mod routing;
+mod schedule;
+mod state;
+mod subscription;
+mod validation;
+mod webhook;
+
+// チャットのtopic
+const TOPIC: &str = "test-net";
+// --peer で指定したpeerが切れていないか確かめる間隔
+const REDIAL_INTERVAL: Duration = Duration::from_secs(10);
 
 // We create a custom network behaviour that combines Gossipsub and Mdns.
 #[derive(NetworkBehaviour)]
 struct MyBehaviour {
-    gossipsub: gossipsub::Behaviour,
-    mdns: mdns::tokio::Behaviour,
+    gossipsub: gossipsub::Behaviour<gossipsub::IdentityTransform, SubscriptionFilter>,
+    // --disable で止められるものはToggleで包む
+    mdns: Toggle<mdns::tokio::Behaviour>,
+    // ルータにUPnPでポートを開けてもらう。家庭内LANから外部と話すため。
+    upnp: Toggle<upnp::tokio::Behaviour>,
+    // /block したpeerとの接続を拒否する
+    blocked: allow_block_list::Behaviour<allow_block_list::BlockedPeers>,
+    // NATの内側同士でもrelay経由でつながるようにする
+    relay_client: relay::client::Behaviour,
+    // relayに自分のアドレスなどを教える
+    identify: Toggle<identify::Behaviour>,
+    // 他のpeerにダイヤルバックしてもらい、外から届くかを調べる
+    autonat: Toggle<autonat::Behaviour>,
+    // gossipsubで送れないときに直接送る
+    direct: direct::Behaviour,
+    // 接続したらプロフィールを交換する
+    profile: profile::Behaviour,
+    // key-valueを置くDHT
+    kad: Toggle<kad::Behaviour<kad::store::MemoryStore>>,
+    // ハッシュを指定してブロックをもらう
+    blocks: blocks::Behaviour,
+    // 署名つきのアドレスを教え合う
+    px: px::Behaviour,
+    // つながっていないpeerへ、経由するpeerを指定して送る
+    route: routing::Behaviour,
+    // --measure で届くまでにかかった時間を送り手に知らせる
+    measure: measure::Behaviour,
 }
 
 #[tokio::main]
@@ -46,129 +145,2046 @@ async fn main() -> Result<(), Box<dyn Error>> {
     // libp2pのトレースログを出力可能にする。出力するには環境変数RUST_LOGの設定が必要。
     //  export RUST_LOG=info,[ConnectionHandler::poll]=trace,[NetworkBehaviour::poll]=trace
     //  https://libp2p.github.io/rust-libp2p/metrics_example/index.html#opentelemetry
-    let _ = tracing_subscriber::fmt()
-        .with_env_filter(EnvFilter::from_default_env())
-        .try_init();
+    let opts = Arc::new(Options::parse()?);
+    // guardを落とすとファイルへの書き込みスレッドが止まるのでmainの最後まで持っておく
+    let _log_guard = init_tracing(&opts)?;
 
-    let use_quic = if let Some(arg) = std::env::args().nth(1) {
-        arg == "quic"
-    } else {
-        false
+    // 記録したイベントの再生だけならswarmは作らない
+    if let Some(path) = &opts.replay {
+        return replay(path);
+    }
+
+    println!("use: quic={}", opts.use_quic);
+    let fn_swarm = get_swarm_fn(opts.use_quic);
+
+    // swarmを作り直してもPeerIdが変わらないよう、鍵はここで作って使い回す
+    let keypair = match (&opts.import_key, &opts.identity) {
+        (Some(src), identity_path) => {
+            let keypair = identity::import(src)?;
+            if let Some(path) = identity_path {
+                identity::save(path, &keypair)?;
+            }
+            keypair
+        }
+        (None, Some(path)) => identity::load_or_generate(path, opts.key_type)?,
+        (None, None) => identity::generate(opts.key_type),
     };
-    println!("use: quic={}", use_quic);
-    let fn_swarm = get_swarm_fn(use_quic);
+    println!("My peer ID: {}", keypair.public().to_peer_id());
 
-    // QUICの有無をオプションで変更できるようにしたかったが .with_quic()の有無で型が変わるので止めた
-    let mut swarm = fn_swarm.0()?;
+    // 鍵の書き出しだけなら起動しない
+    if let Some(path) = &opts.export_key {
+        identity::save(path, &keypair)?;
+        println!("Exported key to {}", path.display());
+        return Ok(());
+    }
+    if opts.export_key_base64 {
+        println!("{}", identity::to_base64(&keypair)?);
+        return Ok(());
+    }
 
     // Create a Gossipsub topic
-    let topic = gossipsub::IdentTopic::new("test-net");
-    // subscribes to our topic
-    swarm.behaviour_mut().gossipsub.subscribe(&topic)?;
+    let topic = gossipsub::IdentTopic::new(TOPIC);
 
-    // Read full lines from stdin
-    let mut stdin = io::BufReader::new(io::stdin()).lines();
+    // 標準入力は別タスクで読む。swarmのタスクが落ちても入力は失われない。
+    let (line_tx, line_rx) = mpsc::channel(32);
+    tokio::spawn(read_stdin(line_tx));
+
+    // ブリッジ類はswarmを作り直しても使い続ける
+    let mqtt = match &opts.mqtt {
+        Some(broker) => {
+            let mqtt_topic = opts
+                .mqtt_topic
+                .clone()
+                .unwrap_or_else(|| format!("libp2p/{topic}"));
+            let client_id = format!("chat-{}", keypair.public().to_peer_id());
+            Some(mqtt::Bridge::connect(broker, mqtt_topic, client_id)?)
+        }
+        None => None,
+    };
+
+    let matrix = match (&opts.matrix_homeserver, &opts.matrix_room) {
+        (Some(homeserver), Some(room)) => {
+            let token = std::env::var("MATRIX_ACCESS_TOKEN")
+                .map_err(|_| "MATRIX_ACCESS_TOKEN is not set")?;
+            Some(matrix::Bridge::connect(homeserver, room.clone(), token).await?)
+        }
+        (None, None) => None,
+        _ => return Err("--matrix-homeserver and --matrix-room must be given together".into()),
+    };
+
+    let irc = match &opts.irc {
+        Some(addr) => Some(irc::Gateway::listen(addr).await?),
+        None => None,
+    };
 
-    // Listen on all interfaces and whatever port the OS assigns
-    fn_swarm.1(&mut swarm)?;
+    let webhook = match &opts.webhook {
+        Some(url) => Some(Webhook::new(
+            url.clone(),
+            opts.webhook_match.as_deref(),
+            std::env::var("WEBHOOK_SECRET").ok(),
+        )?),
+        None => None,
+    };
+
+    let ctx = Arc::new(Context {
+        opts: opts.clone(),
+        keypair: keypair.clone(),
+        topic: topic.clone(),
+        lines: tokio::sync::Mutex::new(line_rx),
+        state: State {
+            blocklist: Mutex::new(Blocklist::load(opts.blocklist.clone())?),
+            peers: Mutex::new(PeerStore::load(opts.peer_store.clone())?),
+            contacts: Mutex::new(Contacts::load(opts.contacts.clone())?),
+            aliases: Mutex::new(Aliases::load(opts.aliases.clone())?),
+            blocks: Mutex::new(BlockStore::new(opts.blocks.clone())?),
+            schedule: Mutex::new(Schedule::load(opts.schedule.clone())?),
+            ..Default::default()
+        },
+        filters: Filters::from_options(&opts)?,
+        mqtt,
+        matrix,
+        irc,
+        webhook,
+        hooks: Hooks::from_options(&opts)?,
+        events: events::bus(),
+        bots: Mutex::new(vec![Box::new(bot::HelloBot)]),
+    });
+    let state = &ctx.state;
+    // 購読してから起動する。swarmを作り直しても購読者はそのまま。
+    tokio::spawn(render(ctx.clone(), ctx.events.subscribe()));
+    tokio::spawn(forward(ctx.clone(), ctx.events.subscribe()));
 
     println!("Enter messages via STDIN and they will be sent to connected peers using Gossipsub");
 
+    // swarmのタスクがpanicやエラーで終わったら、同じ鍵とpeer一覧で作り直して再開する
+    let mut restarts = 0;
+    loop {
+        // QUICの有無をオプションで変更できるようにしたかったが .with_quic()の有無で型が変わるので止めた
+        let mut swarm = fn_swarm.0(keypair.clone(), &opts)?;
+        // subscribes to our topic
+        swarm.behaviour_mut().gossipsub.subscribe(&topic)?;
+        swarm.behaviour_mut().gossipsub.subscribe(&gossipsub::IdentTopic::new(presence::TOPIC))?;
+        for name in lock(&state.irc_topics).iter() {
+            swarm.behaviour_mut().gossipsub.subscribe(&gossipsub::IdentTopic::new(name))?;
+        }
+        // Listen on all interfaces and whatever port the OS assigns
+        fn_swarm.1(&mut swarm)?;
+        // relayにreservationを取り、relay経由のアドレスでも待ち受ける
+        *lock(&state.relay) = RelayStatus::default();
+        if let Some(relay) = &opts.relay {
+            listen_on_relay(&mut swarm, state, relay)?;
+        }
+        // 自分では分からない外部アドレスを教えておく
+        for addr in &opts.external_addresses {
+            swarm.add_external_address(addr.clone());
+        }
+
+        lock(&state.peers).reset_connections();
+        for peer_id in lock(&state.blocklist).iter() {
+            swarm.behaviour_mut().blocked.block_peer(*peer_id);
+            swarm.behaviour_mut().gossipsub.blacklist_peer(peer_id);
+        }
+        for peer_id in lock(&state.reputation).banned() {
+            swarm.behaviour_mut().blocked.block_peer(*peer_id);
+            swarm.behaviour_mut().gossipsub.blacklist_peer(peer_id);
+        }
+        // 保存してあるブロックを提供し直す(provider recordは作り直すと消える)
+        if let Some(kad) = swarm.behaviour_mut().kad.as_mut() {
+            for hash in lock(&state.blocks).hashes() {
+                kad.start_providing(kad::RecordKey::new(hash))?;
+            }
+        }
+        let peers: Vec<(PeerId, Multiaddr)> = lock(&state.known_peers)
+            .iter()
+            .chain(opts.permanent_peers.iter().map(|(p, a)| (p, a)))
+            .map(|(p, a)| (*p, a.clone()))
+            .collect();
+        for (peer_id, addr) in peers {
+            lock(&state.peers).add_address(peer_id, addr.clone());
+            swarm.behaviour_mut().gossipsub.add_explicit_peer(&peer_id);
+            if let Some(kad) = swarm.behaviour_mut().kad.as_mut() {
+                kad.add_address(&peer_id, addr);
+            }
+            dial_peer(&mut swarm, state, peer_id);
+        }
+
+        // 2回目以降は前回の記録に追記する
+        let recorder = match &opts.record {
+            Some(path) if restarts == 0 => Some(Recorder::create(path)?),
+            Some(path) => Some(Recorder::append(path)?),
+            None => None,
+        };
+
+        let task = tokio::spawn(run_swarm(swarm, ctx.clone(), recorder));
+        match task.await {
+            Ok(Ok(())) => return Ok(()),
+            Ok(Err(e)) => println!("Swarm stopped with error: {e}"),
+            Err(e) => println!("Swarm task panicked: {e}"),
+        }
+
+        restarts += 1;
+        println!("Restarting swarm ({restarts})");
+        tokio::time::sleep(Duration::from_secs(1)).await;
+    }
+}
+
+// swarmのタスクに渡すもの。swarmを作り直しても同じものを使い続ける。
+struct Context {
+    opts: Arc<Options>,
+    // 自分のpeer recordに署名する
+    keypair: Keypair,
+    topic: gossipsub::IdentTopic,
+    // 標準入力の行
+    lines: tokio::sync::Mutex<mpsc::Receiver<String>>,
+    state: State,
+    filters: Filters,
+    mqtt: Option<mqtt::Bridge>,
+    matrix: Option<matrix::Bridge>,
+    irc: Option<irc::Gateway>,
+    webhook: Option<Webhook>,
+    // イベントが起きたら実行するコマンド
+    hooks: Hooks,
+    // 表示やブリッジはここを購読して、swarmのタスクとは別に動く
+    events: broadcast::Sender<NodeEvent>,
+    // 受信したメッセージなどに自動で応答する
+    bots: Mutex<Vec<Box<dyn ChatBot>>>,
+}
+
+// Read full lines from stdin
+// swarmのタスクが追いつかないときは読むのを待つ(大量に貼り付けても捨てない)
+async fn read_stdin(tx: mpsc::Sender<String>) {
+    let mut stdin = io::BufReader::new(io::stdin()).lines();
+    let mut waiting = false;
+    while let Ok(Some(line)) = stdin.next_line().await {
+        match tx.try_send(line) {
+            Ok(()) => waiting = false,
+            Err(TrySendError::Full(line)) => {
+                if !waiting {
+                    println!("Input queue full, waiting for messages to be sent...");
+                    waiting = true;
+                }
+                if tx.send(line).await.is_err() {
+                    break;
+                }
+            }
+            Err(TrySendError::Closed(_)) => break,
+        }
+    }
+}
+
+// swarmのイベントループ。supervisorからtokio::spawnされる。
+async fn run_swarm(
+    mut swarm: Swarm<MyBehaviour>,
+    ctx: Arc<Context>,
+    mut recorder: Option<Recorder>,
+) -> io::Result<()> {
+    // tokioのMutexはpanicしてもpoisonされないので、次のタスクがそのまま受け取れる
+    let mut lines = ctx.lines.lock().await;
+    let (state, topic, opts) = (&ctx.state, &ctx.topic, &ctx.opts);
+    let presence_topic = gossipsub::IdentTopic::new(presence::TOPIC);
+    let mut presence_tick = tokio::time::interval(presence::INTERVAL);
+    let mut redial_tick = tokio::time::interval(REDIAL_INTERVAL);
+    let mut reorder = Reorder::default();
+    let mut reorder_tick = tokio::time::interval(reorder::WINDOW / 4);
+    let mut schedule_tick = tokio::time::interval(Duration::from_secs(1));
+    // gossipsubの送信キューがいっぱいで送れなかった行をやり直す
+    let mut outbox_tick = tokio::time::interval(Duration::from_millis(100));
+    // 最初のtickはすぐに来るので、起動時にもbootstrapする
+    let mut bootstrap_tick = tokio::time::interval(opts.kad_bootstrap_interval());
+
     // gossipsubの仕様でmessageIdが同じになるとpublish()でDuplicateエラーになる。
     // message_id_fn の実装でmessageIdの計算方法を変更できる。
     loop {
         select! {
-            Ok(Some(line)) = stdin.next_line() => {
-                // 標準入力を取得したらpublishする
-                // 大文字に変換して送信させている
-                let line = line.to_uppercase();
-                if let Err(e) = swarm
-                    .behaviour_mut().gossipsub
-                    .publish(topic.clone(), line.as_bytes()) {
-                    println!("Publish error: {e:?}");
+            // outboxがいっぱいの間は読まない。標準入力のチャネルが詰まってread_stdinが待つ。
+            Some(line) = lines.recv(), if !lock(&state.outbox).is_full() => {
+                match Command::parse(&line) {
+                    Some(Ok(command)) => handle_command(&mut swarm, &ctx, command),
+                    Some(Err(e)) => println!("{e}"),
+                    None => {
+                        // 標準入力を取得したらpublishする
+                        // 大文字に変換して送信させている
+                        let line = line.to_uppercase();
+                        // 署名などで少し大きくなるので、本文だけで判定するのは目安
+                        if line.len() > opts.max_message_size() {
+                            println!("Message too large: {} bytes (max {})", line.len(), opts.max_message_size());
+                            continue;
+                        }
+                        lock(&state.outbox).push(line);
+                        flush_outbox(&mut swarm, &ctx);
+                    }
                 }
             }
-            event = swarm.select_next_some() => match event {
-                // 通信系イベント?
-
-                SwarmEvent::Behaviour(MyBehaviourEvent::Mdns(mdns::Event::Discovered(list))) => {
-                    for (peer_id, _multiaddr) in list {
-                        println!("mDNS discovered a new peer: {peer_id}");
-                        swarm.behaviour_mut().gossipsub.add_explicit_peer(&peer_id);
+            _ = outbox_tick.tick(), if lock(&state.outbox).is_ready() => flush_outbox(&mut swarm, &ctx),
+            Some(text) = recv_mqtt(ctx.mqtt.as_ref()) => {
+                // MQTTから来たメッセージをそのままpublishする
+                if let Err(e) = publish(&mut swarm, &ctx, topic, &text) {
+                    println!("Publish error for MQTT message: {e:?}");
+                }
+            }
+            Some(text) = recv_matrix(ctx.matrix.as_ref()) => {
+                if let Err(e) = publish(&mut swarm, &ctx, topic, &text) {
+                    println!("Publish error for Matrix message: {e:?}");
+                }
+            }
+            Some(request) = recv_irc(ctx.irc.as_ref()) => handle_irc(&mut swarm, &ctx, request),
+            _ = presence_tick.tick() => {
+                // 自分の状態を流し、しばらく来ていないpeerは退室扱いにする
+                let announcement = presence::Announcement {
+                    status: *lock(&state.status),
+                    nick: opts.nick.clone(),
+                };
+                match swarm.behaviour_mut().gossipsub.publish(presence_topic.clone(), announcement.encode()) {
+                    // まだ誰もいないときは送れないが問題ない
+                    Ok(_) | Err(gossipsub::PublishError::NoPeersSubscribedToTopic) => {}
+                    Err(e) => println!("Presence publish error: {e:?}"),
+                }
+                for (peer_id, entry) in lock(&state.presence).expire() {
+                    println!("* {} ({}) left", entry.nick.as_deref().unwrap_or("-"), name(state, &peer_id));
+                }
+                for peer_id in lock(&state.reputation).expire_bans() {
+                    unban(&mut swarm, state, peer_id);
+                }
+                if let Err(e) = lock(&state.peers).save() {
+                    println!("Peer store save error: {e:?}");
+                }
+            }
+            _ = reorder_tick.tick() => {
+                for delivery in reorder.flush() {
+                    deliver(&mut swarm, &ctx, delivery);
+                }
+            }
+            _ = schedule_tick.tick() => {
+                // 予約した時刻が来たメッセージを送る
+                let due = lock(&state.schedule).due();
+                match due {
+                    Ok(due) => {
+                        for entry in due {
+                            if let Err(e) = publish(&mut swarm, &ctx, topic, &entry.text) {
+                                println!("Publish error for scheduled message: {e:?}");
+                            }
+                        }
                     }
-                },
-                SwarmEvent::Behaviour(MyBehaviourEvent::Mdns(mdns::Event::Expired(list))) => {
-                    for (peer_id, _multiaddr) in list {
-                        println!("mDNS discover peer has expired: {peer_id}");
-                        swarm.behaviour_mut().gossipsub.remove_explicit_peer(&peer_id);
+                    Err(e) => println!("Schedule save error: {e:?}"),
+                }
+            }
+            _ = bootstrap_tick.tick() => bootstrap(&mut swarm, state),
+            _ = redial_tick.tick() => {
+                // reservationが切れて更新もできなかったら取り直す
+                if let Some(relay) = &opts.relay
+                    && lock(&state.relay).listener.is_none()
+                {
+                    println!("Requesting a new relay reservation from {relay}");
+                    if let Err(e) = listen_on_relay(&mut swarm, state, relay) {
+                        println!("Relay listen error: {e:?}");
                     }
-                },
-                SwarmEvent::Behaviour(MyBehaviourEvent::Gossipsub(gossipsub::Event::Message {
-                    propagation_source: peer_id,
-                    message_id: id,
-                    message,
-                })) => {
-                    let msg = String::from_utf8_lossy(&message.data);
-                    println!(
-                        "Got message: '{msg}' with id: {id} from peer: {peer_id}",
-                    );
-                    if msg == "HELLO" {
-                        if let Err(e) = swarm
-                            .behaviour_mut()
-                            .gossipsub
-                            .publish(topic.clone(), b"WORLD") {
-                            println!("Publish error after got message: {e:?}");
+                }
+                // 常につないでおくpeerが切れていたらつなぎ直す
+                for (peer_id, _) in &opts.permanent_peers {
+                    if swarm.is_connected(peer_id) || lock(&state.reputation).is_banned(peer_id) {
+                        continue;
+                    }
+                    println!("Redialing permanent peer {peer_id}");
+                    dial_peer(&mut swarm, state, *peer_id);
+                }
+            }
+            event = swarm.select_next_some() => {
+                if let Some(recorder) = recorder.as_mut() {
+                    recorder.record(&event)?;
+                }
+                track_peers(&mut lock(&state.peers), &event);
+                log_connections(&mut lock(&state.connlog), &event);
+                match event {
+                    // 通信系イベント?
+
+                    SwarmEvent::Behaviour(MyBehaviourEvent::Mdns(mdns::Event::Discovered(list))) => {
+                        for (peer_id, multiaddr) in list {
+                            if lock(&state.blocklist).contains(&peer_id)
+                                || lock(&state.reputation).is_banned(&peer_id)
+                            {
+                                continue;
+                            }
+                            println!("mDNS discovered a new peer: {}", name(state, &peer_id));
+                            tracing::info!(peer_id = %peer_id, "mdns discovered");
+                            swarm.behaviour_mut().gossipsub.add_explicit_peer(&peer_id);
+                            if let Some(kad) = swarm.behaviour_mut().kad.as_mut() {
+                                kad.add_address(&peer_id, multiaddr.clone());
+                            }
+                            lock(&state.known_peers).insert(peer_id, multiaddr);
+                        }
+                    },
+                    SwarmEvent::Behaviour(MyBehaviourEvent::Mdns(mdns::Event::Expired(list))) => {
+                        for (peer_id, _multiaddr) in list {
+                            println!("mDNS discover peer has expired: {peer_id}");
+                            tracing::info!(peer_id = %peer_id, "mdns expired");
+                            if !opts.is_permanent(&peer_id) {
+                                swarm.behaviour_mut().gossipsub.remove_explicit_peer(&peer_id);
+                            }
+                            lock(&state.known_peers).remove(&peer_id);
                         }
-                    } else if msg == "WORLD" {
-                        if let Err(e) = swarm
+                    },
+                    SwarmEvent::Behaviour(MyBehaviourEvent::Gossipsub(gossipsub::Event::Message {
+                        propagation_source: peer_id,
+                        message_id: id,
+                        message,
+                    })) => {
+                        tracing::info!(
+                            peer_id = %peer_id,
+                            topic = %message.topic,
+                            message_id = %id,
+                            "message received"
+                        );
+                        // 順番を揃えるための番号は外してから検証・表示する
+                        let (number, message) = match reorder::unstamp(&message.data) {
+                            Some((number, data)) => (Some(number), gossipsub::Message { data: data.to_vec(), ..message }),
+                            None => (None, message),
+                        };
+                        let offense = lock(&state.reputation).on_message(peer_id);
+                        if let Some(offense) = offense {
+                            penalize(&mut swarm, state, peer_id, offense);
+                        }
+                        // 在席情報はチャットとは別に処理する
+                        if message.topic == presence_topic.hash() {
+                            let acceptance = match (message.source, presence::Announcement::decode(&message.data)) {
+                                (Some(from), Some(announcement)) => {
+                                    if let Some(nick) = &announcement.nick {
+                                        match lock(&state.contacts).bind(nick, from) {
+                                            Ok(Binding::Conflict(known)) => {
+                                                println!("* WARNING: {from} claims the nick {nick}, which was first used by {known}");
+                                            }
+                                            Ok(_) => {}
+                                            Err(e) => println!("Contacts save error: {e:?}"),
+                                        }
+                                    }
+                                    let announced = announcement.nick.clone();
+                                    let nick = announced.clone().unwrap_or_else(|| "-".to_string());
+                                    let change = lock(&state.presence).update(from, announcement);
+                                    match change {
+                                        Some(presence::Change::Joined) => {
+                                            println!("* {nick} ({}) joined", name(state, &from));
+                                            run_bots(&mut swarm, &ctx, |bot| bot.on_peer_joined(&from, announced.as_deref()));
+                                        }
+                                        Some(presence::Change::StatusChanged(status)) => println!("* {nick} is now {status}"),
+                                        None => {}
+                                    }
+                                    gossipsub::MessageAcceptance::Accept
+                                }
+                                _ => {
+                                    penalize(&mut swarm, state, peer_id, Offense::InvalidMessage);
+                                    gossipsub::MessageAcceptance::Reject
+                                }
+                            };
+                            swarm
+                                .behaviour_mut()
+                                .gossipsub
+                                .report_message_validation_result(&id, &peer_id, acceptance);
+                            continue;
+                        }
+
+                        let from_mesh = swarm.behaviour().gossipsub.mesh_peers(&message.topic).any(|p| *p == peer_id);
+                        let known_peer = lock(&state.known_peers).contains_key(&peer_id) || opts.is_permanent(&peer_id);
+                        if recovered_via_gossip(&message, &peer_id, from_mesh, known_peer) {
+                            println!("Message with id: {id} was probably recovered via gossip from {}", name(state, &peer_id));
+                            tracing::info!(peer_id = %peer_id, message_id = %id, "recovered via gossip");
+                        }
+
+                        // validate_messages()にしているので、転送してよいかをここで決めて伝える
+                        let subscribed = swarm.behaviour().gossipsub.topics().any(|t| t == &message.topic);
+                        let verdict = validate(
+                            &message,
+                            subscribed,
+                            &lock(&state.blocklist),
+                            &ctx.filters,
+                        );
+                        let acceptance = match verdict {
+                            // 転送はしてよいが、表示やブリッジへの転送は1回だけ
+                            Ok(_) if !lock(&state.displayed).first_time(&dedup::key(message.source, message.sequence_number, &id)) => {
+                                tracing::debug!(message_id = %id, "already displayed");
+                                gossipsub::MessageAcceptance::Accept
+                            }
+                            Ok(msg) => {
+                                // 順番待ちの前に測る
+                                let msg = match (opts.measure, message.source) {
+                                    (true, Some(from)) => report_latency(&mut swarm, &id, from, peer_id, msg),
+                                    _ => msg,
+                                };
+                                let incoming = Incoming {
+                                    // 中継してくれたpeerではなく書いた人
+                                    from: message.source.unwrap_or(peer_id),
+                                    peer_id,
+                                    id: id.clone(),
+                                    topic: message.topic.clone(),
+                                    text: msg,
+                                };
+                                // 送り手とtopicごとに順番を揃えてから表示する
+                                let deliveries = match (message.source, number) {
+                                    (Some(from), Some(number)) => reorder.push(from, message.topic.clone(), number, Some(incoming)),
+                                    _ => vec![Delivery::Message(incoming)],
+                                };
+                                for delivery in deliveries {
+                                    deliver(&mut swarm, &ctx, delivery);
+                                }
+                                gossipsub::MessageAcceptance::Accept
+                            }
+                            Err((acceptance, reason)) => {
+                                println!("{acceptance:?} message with id: {id} from peer: {}: {reason}", name(state, &peer_id));
+                                if acceptance == gossipsub::MessageAcceptance::Reject {
+                                    penalize(&mut swarm, state, peer_id, Offense::InvalidMessage);
+                                }
+                                // 届いてはいるので、飛んだとは数えない
+                                if let (Some(from), Some(number)) = (message.source, number) {
+                                    for delivery in reorder.push(from, message.topic.clone(), number, None) {
+                                        deliver(&mut swarm, &ctx, delivery);
+                                    }
+                                }
+                                acceptance
+                            }
+                        };
+                        swarm
                             .behaviour_mut()
                             .gossipsub
-                            .publish(topic.clone(), b"HELLO") {
-                            println!("Publish error after got message: {e:?}");
+                            .report_message_validation_result(&id, &peer_id, acceptance);
+                    },
+                    SwarmEvent::NewListenAddr { address, .. } => {
+                        println!("Local node is listening on {address}");
+                        if is_relayed(&address) {
+                            lock(&state.relay).addresses.push(address);
+                        }
+                    }
+                    SwarmEvent::ConnectionEstablished { peer_id, endpoint, num_established, .. } => {
+                        let relayed = is_relayed(endpoint.get_remote_address());
+                        let address = endpoint.get_remote_address().clone();
+                        let _ = ctx.events.send(NodeEvent::PeerConnected { peer_id, address, relayed });
+                        // 最初の接続のときだけプロフィールを交換する
+                        if num_established.get() == 1 {
+                            swarm.behaviour_mut().profile.send_request(&peer_id, Profile::from_options(opts));
+                            let records = px_records(&swarm, &ctx, &peer_id);
+                            swarm.behaviour_mut().px.send_request(&peer_id, records);
+                        }
+                    }
+                    SwarmEvent::Behaviour(MyBehaviourEvent::Profile(event)) => handle_profile(&mut swarm, &ctx, event),
+                    SwarmEvent::Behaviour(MyBehaviourEvent::Px(event)) => handle_px(&mut swarm, &ctx, event),
+                    SwarmEvent::Behaviour(MyBehaviourEvent::Route(event)) => handle_route(&mut swarm, &ctx, event),
+                    SwarmEvent::Behaviour(MyBehaviourEvent::Measure(event)) => handle_measure(&mut swarm, &ctx, event),
+                    SwarmEvent::Behaviour(MyBehaviourEvent::RelayClient(
+                        relay::client::Event::ReservationReqAccepted { relay_peer_id, renewal, .. },
+                    )) => {
+                        if renewal {
+                            tracing::info!(peer_id = %relay_peer_id, "relay reservation renewed");
+                        } else {
+                            println!("Relay reservation accepted by {relay_peer_id}");
+                        }
+                        lock(&state.relay).accepted(relay_peer_id, renewal);
+                    }
+                    SwarmEvent::ListenerClosed { listener_id, reason, .. } => {
+                        let mut relay = lock(&state.relay);
+                        if relay.listener == Some(listener_id) {
+                            // 更新に失敗するとlistenerごと閉じる。次のredial_tickで取り直す。
+                            match reason {
+                                Ok(()) => println!("Relay listener closed"),
+                                Err(e) => {
+                                    relay.failures += 1;
+                                    println!("Relay reservation failed: {e:?}");
+                                    tracing::warn!(error = ?e, "relay reservation failed");
+                                }
+                            }
+                            relay.closed();
+                        }
+                    }
+                    SwarmEvent::ExpiredListenAddr { address, .. } => {
+                        lock(&state.relay).addresses.retain(|a| a != &address);
+                    }
+                    SwarmEvent::Behaviour(MyBehaviourEvent::Upnp(event)) => {
+                        let result = match event {
+                            upnp::Event::NewExternalAddr(addr) => format!("mapped external address: {addr}"),
+                            upnp::Event::ExpiredExternalAddr(addr) => format!("external address expired: {addr}"),
+                            upnp::Event::GatewayNotFound => "gateway not found".to_string(),
+                            upnp::Event::NonRoutableGateway => {
+                                "gateway is not exposed directly to the public network".to_string()
+                            }
+                        };
+                        println!("UPnP {result}");
+                        lock(&state.diag).upnp = Some(result);
+                    }
+                    SwarmEvent::Behaviour(MyBehaviourEvent::Identify(identify::Event::Received { peer_id, info, .. })) => {
+                        // 相手の待ち受けアドレスをDHTのルーティングテーブルに入れる
+                        if let Some(kad) = swarm.behaviour_mut().kad.as_mut() {
+                            for addr in info.listen_addrs {
+                                kad.add_address(&peer_id, addr);
+                            }
+                        }
+                        lock(&state.diag).observed(peer_id, info.observed_addr);
+                    }
+                    SwarmEvent::Behaviour(MyBehaviourEvent::Kad(event)) => handle_kad(&mut swarm, &ctx, event),
+                    SwarmEvent::Behaviour(MyBehaviourEvent::Blocks(event)) => handle_blocks(&mut swarm, &ctx, event),
+                    SwarmEvent::Behaviour(MyBehaviourEvent::Gossipsub(gossipsub::Event::Subscribed { peer_id, topic })) => {
+                        // 送る相手ができたので、持っていたメッセージを送る
+                        let held = topic == ctx.topic.hash() && std::mem::take(&mut lock(&state.outbox).held);
+                        lock(&state.subscribers).entry(topic.into_string()).or_default().insert(peer_id);
+                        if held {
+                            println!("{peer_id} subscribed, sending held messages");
+                            flush_outbox(&mut swarm, &ctx);
+                        }
+                    }
+                    SwarmEvent::Behaviour(MyBehaviourEvent::Gossipsub(gossipsub::Event::Unsubscribed { peer_id, topic })) => {
+                        if let Some(peers) = lock(&state.subscribers).get_mut(topic.as_str()) {
+                            peers.remove(&peer_id);
+                        }
+                    }
+                    SwarmEvent::Behaviour(MyBehaviourEvent::Direct(event)) => handle_direct(&mut swarm, &ctx, event),
+                    SwarmEvent::Behaviour(MyBehaviourEvent::Autonat(autonat::Event::StatusChanged { old, new })) => {
+                        let (old, new) = (format!("{old:?}"), format!("{new:?}"));
+                        let _ = ctx.events.send(NodeEvent::NatStatusChanged { old, new });
+                    }
+                    _ => {}
+                }
+            }
+        }
+    }
+}
+
+// 標準入力から来た行をBATCHずつpublishする。
+// gossipsubの送信キューがいっぱいなら残しておき、outbox_tickでやり直す。
+// 送る相手が誰もいなければ、topicをsubscribeするpeerが来るまで持っておく。
+fn flush_outbox(swarm: &mut Swarm<MyBehaviour>, ctx: &Context) {
+    for _ in 0..outbox::BATCH {
+        let Some(line) = lock(&ctx.state.outbox).pop() else {
+            return;
+        };
+        match publish(swarm, ctx, &ctx.topic, &line) {
+            Ok(()) => lock(&ctx.state.outbox).stalled = false,
+            Err(gossipsub::PublishError::NoPeersSubscribedToTopic) => {
+                let mut outbox = lock(&ctx.state.outbox);
+                outbox.retry(line);
+                outbox.held = true;
+                println!("No peers yet, holding {} messages until someone subscribes to {}", outbox.len(), ctx.topic);
+                return;
+            }
+            Err(gossipsub::PublishError::AllQueuesFull(_)) => {
+                let mut outbox = lock(&ctx.state.outbox);
+                outbox.retry(line);
+                if !outbox.stalled {
+                    println!("Send queues are full, {} messages waiting", outbox.len());
+                    outbox.stalled = true;
+                }
+                return;
+            }
+            Err(e) => println!("Publish error: {e:?}"),
+        }
+    }
+}
+
+// gossipsubでpublishする。meshがまだなくて送れないときは、
+// subscribeしていたpeerや見つけたpeerにrequest-responseで直接送る(2台だけのときによくある)。
+fn publish(
+    swarm: &mut Swarm<MyBehaviour>,
+    ctx: &Context,
+    topic: &gossipsub::IdentTopic,
+    text: &str,
+) -> Result<(), gossipsub::PublishError> {
+    // 直接送るときは時刻も番号も付けない(測るのはgossipsubの伝わり方で、直接送ったものは順番を揃えない)
+    let mut data = if ctx.opts.measure { measure::stamp(text) } else { text.to_string() };
+    if ctx.opts.ordered {
+        data = reorder::stamp(lock(&ctx.state.counters).peek(&topic.hash()), &data);
+    }
+    match swarm.behaviour_mut().gossipsub.publish(topic.clone(), data) {
+        Ok(id) => {
+            if ctx.opts.ordered {
+                lock(&ctx.state.counters).advance(&topic.hash());
+            }
+            let sent_to = gossipsub_recipients(swarm, ctx, &topic.hash());
+            let count = sent_to.len();
+            tracing::info!(topic = %topic, message_id = %id, peers = count, "published");
+            let number = lock(&ctx.state.sent).push(text, true, sent_to);
+            println!("Sent #{number} to {count} peers");
+            Ok(())
+        }
+        Err(gossipsub::PublishError::NoPeersSubscribedToTopic) => {
+            let state = &ctx.state;
+            let mut peers: HashSet<PeerId> = lock(&state.subscribers)
+                .get(topic.hash().as_str())
+                .cloned()
+                .unwrap_or_default();
+            peers.extend(lock(&state.known_peers).keys());
+            peers.extend(ctx.opts.permanent_peers.iter().map(|(p, _)| *p));
+            if peers.is_empty() {
+                return Err(gossipsub::PublishError::NoPeersSubscribedToTopic);
+            }
+            let mut sent = lock(&state.sent);
+            let number = sent.push(text, false, peers.iter().copied().collect());
+            for peer_id in &peers {
+                let request = DirectRequest::Publish {
+                    topic: topic.to_string(),
+                    text: text.to_string(),
+                };
+                let id = swarm.behaviour_mut().direct.send_request(peer_id, request);
+                sent.add_request(id, number);
+            }
+            println!("No gossipsub peers yet, sent #{number} directly to {} peers", peers.len());
+            Ok(())
+        }
+        Err(e) => Err(e),
+    }
+}
+
+// publishしたときにgossipsubが送る相手。flood publishならtopicをsubscribeしているpeerすべて、
+// そうでなければmeshのpeer。スコアが低いpeerには送らないこともあるので目安。
+fn gossipsub_recipients(swarm: &Swarm<MyBehaviour>, ctx: &Context, topic: &gossipsub::TopicHash) -> Vec<PeerId> {
+    let gossipsub = &swarm.behaviour().gossipsub;
+    if ctx.opts.flood_publish.unwrap_or(true) {
+        gossipsub
+            .all_peers()
+            .filter(|(_, topics)| topics.contains(&topic))
+            .map(|(peer_id, _)| *peer_id)
+            .collect()
+    } else {
+        gossipsub.mesh_peers(topic).copied().collect()
+    }
+}
+
+// 直接送られてきたメッセージ。gossipsubと同じように確かめてから表示する。
+fn handle_direct(
+    swarm: &mut Swarm<MyBehaviour>,
+    ctx: &Context,
+    event: request_response::Event<DirectRequest, DirectResponse>,
+) {
+    let state = &ctx.state;
+    match event {
+        request_response::Event::Message {
+            peer,
+            message: request_response::Message::Request { request_id, request, channel },
+            ..
+        } => {
+            let response = match request {
+                DirectRequest::Publish { topic, text } => {
+                    let topic = gossipsub::TopicHash::from_raw(topic);
+                    let subscribed = swarm.behaviour().gossipsub.topics().any(|t| t == &topic);
+                    let verdict = validate_direct(&peer, &topic, subscribed, &lock(&state.blocklist), &ctx.filters, text);
+                    match verdict {
+                        Ok(text) => {
+                            let incoming = Incoming {
+                                from: peer,
+                                peer_id: peer,
+                                id: gossipsub::MessageId::from(format!("direct-{request_id}")),
+                                topic,
+                                text,
+                            };
+                            deliver(swarm, ctx, Delivery::Message(incoming));
+                            DirectResponse::Accepted
+                        }
+                        Err(reason) => DirectResponse::Rejected(reason),
+                    }
+                }
+                DirectRequest::Dm { text } => {
+                    let verdict = validate_dm(&peer, &lock(&state.blocklist), &ctx.filters, text);
+                    match verdict {
+                        Ok(text) => {
+                            let _ = ctx.events.send(NodeEvent::Dm { from: peer, text: text.clone() });
+                            run_bots(swarm, ctx, |bot| bot.on_dm(&peer, &text));
+                            lock(&state.conversations).push(peer, false, text);
+                            DirectResponse::Accepted
+                        }
+                        Err(reason) => DirectResponse::Rejected(reason),
+                    }
+                }
+            };
+            if let Err(e) = swarm.behaviour_mut().direct.send_response(channel, response) {
+                println!("Direct response error: {e:?}");
+            }
+        }
+        request_response::Event::Message {
+            peer,
+            message: request_response::Message::Response { request_id, response },
+            ..
+        } => {
+            if let DirectResponse::Rejected(reason) = &response {
+                println!("{} rejected the direct message: {reason}", name(state, &peer));
+            }
+            answered(state, request_id, peer, response == DirectResponse::Accepted);
+        }
+        request_response::Event::OutboundFailure { peer, request_id, error, .. } => {
+            println!("Direct message to {} failed: {error}", name(state, &peer));
+            answered(state, request_id, peer, false);
+        }
+        _ => {}
+    }
+}
+
+// 直接送ったpublishの返事がそろったら、何台に届いたかを表示する
+fn answered(state: &State, request_id: request_response::OutboundRequestId, peer_id: PeerId, accepted: bool) {
+    let mut sent = lock(&state.sent);
+    if let Some(sent) = sent.answered(request_id, peer_id, accepted)
+        && sent.is_settled()
+    {
+        println!("#{} delivered to {}/{} peers", sent.number, sent.accepted.len(), sent.sent_to.len());
+    }
+}
+
+// 経路を指定したメッセージ。自分宛てなら表示し、そうでなければ経路の次のpeerへ渡す。
+fn handle_route(
+    swarm: &mut Swarm<MyBehaviour>,
+    ctx: &Context,
+    event: request_response::Event<routing::Routed, routing::RouteResponse>,
+) {
+    let state = &ctx.state;
+    match event {
+        request_response::Event::Message {
+            peer,
+            message: request_response::Message::Request { request, channel, .. },
+            ..
+        } => {
+            // 書いた人の署名を確かめてから、渡してきたpeerと書いた人のどちらもブロックしていないか見る
+            let source = request.verify();
+            let response = match source {
+                Err(reason) => routing::RouteResponse::Dropped(reason),
+                Ok(source) if lock(&state.blocklist).contains(&peer) || lock(&state.blocklist).contains(&source) => {
+                    routing::RouteResponse::Dropped("blocked".to_string())
+                }
+                Ok(source) => match request.step(swarm.local_peer_id(), &peer) {
+                    Ok(routing::Step::Deliver) => match ctx.filters.apply(request.text.clone()) {
+                        Ok(text) => {
+                            println!(
+                                "Routed message from {} via {}: {text}",
+                                name(state, &source),
+                                request.route.join(" -> ")
+                            );
+                            routing::RouteResponse::Delivered
                         }
+                        Err(reason) => routing::RouteResponse::Dropped(reason),
+                    },
+                    Ok(routing::Step::Forward(next)) if swarm.is_connected(&next) => {
+                        tracing::info!(from = %peer, next = %next, ttl = request.ttl, "forwarding routed message");
+                        swarm.behaviour_mut().route.send_request(&next, request.forwarded());
+                        routing::RouteResponse::Forwarded
                     }
+                    Ok(routing::Step::Forward(next)) => {
+                        routing::RouteResponse::Dropped(format!("not connected to the next hop {next}"))
+                    }
+                    Err(reason) => routing::RouteResponse::Dropped(reason),
                 },
-                SwarmEvent::NewListenAddr { address, .. } => {
-                    println!("Local node is listening on {address}");
+            };
+            if let routing::RouteResponse::Dropped(reason) = &response {
+                println!("Dropped a routed message from {}: {reason}", name(state, &peer));
+            }
+            if swarm.behaviour_mut().route.send_response(channel, response).is_err() {
+                println!("Route response to {} failed", name(state, &peer));
+            }
+        }
+        request_response::Event::Message {
+            peer,
+            message: request_response::Message::Response { response, .. },
+            ..
+        } => match response {
+            routing::RouteResponse::Delivered => println!("Routed message delivered to {}", name(state, &peer)),
+            routing::RouteResponse::Forwarded => tracing::info!(peer_id = %peer, "routed message forwarded"),
+            routing::RouteResponse::Dropped(reason) => {
+                println!("{} dropped the routed message: {reason}", name(state, &peer));
+            }
+        },
+        request_response::Event::OutboundFailure { peer, error, .. } => {
+            println!("Routed message to {} failed: {error}", name(state, &peer));
+        }
+        _ => {}
+    }
+}
+
+// --measure で送った時刻が付いていれば、かかった時間を書いた本人に知らせて本文だけを返す
+fn report_latency(
+    swarm: &mut Swarm<MyBehaviour>,
+    id: &gossipsub::MessageId,
+    from: PeerId,
+    peer_id: PeerId,
+    text: String,
+) -> String {
+    let Some((latency, body)) = measure::unstamp(&text) else {
+        return text;
+    };
+    let report = measure::Report {
+        message_id: id.to_string(),
+        latency_ms: latency.as_millis() as u64,
+        relayed: from != peer_id,
+    };
+    tracing::info!(message_id = %id, latency_ms = report.latency_ms, relayed = report.relayed, "latency measured");
+    swarm.behaviour_mut().measure.send_request(&from, report);
+    body.to_string()
+}
+
+// 受け取ったpeerからの測定を集める
+fn handle_measure(swarm: &mut Swarm<MyBehaviour>, ctx: &Context, event: request_response::Event<measure::Report, ()>) {
+    match event {
+        request_response::Event::Message {
+            peer,
+            message: request_response::Message::Request { request, channel, .. },
+            ..
+        } => {
+            tracing::info!(peer_id = %peer, message_id = %request.message_id, latency_ms = request.latency_ms, "latency reported");
+            lock(&ctx.state.measurements).push(peer, &request);
+            let _ = swarm.behaviour_mut().measure.send_response(channel, ());
+        }
+        request_response::Event::OutboundFailure { peer, error, .. } => {
+            tracing::info!(peer_id = %peer, error = %error, "latency report failed");
+        }
+        _ => {}
+    }
+}
+
+// 相手のプロフィールを覚え、頼まれたら自分のものを返す
+fn handle_profile(swarm: &mut Swarm<MyBehaviour>, ctx: &Context, event: request_response::Event<Profile, Profile>) {
+    let state = &ctx.state;
+    match event {
+        request_response::Event::Message {
+            peer,
+            message: request_response::Message::Request { request, channel, .. },
+            ..
+        } => {
+            tracing::info!(peer_id = %peer, profile = %request, "profile received");
+            lock(&state.peers).set_profile(peer, request);
+            let mine = Profile::from_options(&ctx.opts);
+            if swarm.behaviour_mut().profile.send_response(channel, mine).is_err() {
+                println!("Profile response to {} failed", name(state, &peer));
+            }
+        }
+        request_response::Event::Message {
+            peer,
+            message: request_response::Message::Response { response, .. },
+            ..
+        } => {
+            tracing::info!(peer_id = %peer, profile = %response, "profile received");
+            lock(&state.peers).set_profile(peer, response);
+        }
+        request_response::Event::OutboundFailure { peer, error, .. } => {
+            // 古いバージョンなど、プロフィールに対応していない相手もいる
+            tracing::info!(peer_id = %peer, error = %error, "profile exchange failed");
+        }
+        _ => {}
+    }
+}
+
+// 自分の署名つきレコードと、知っている他のpeerのレコード(相手自身のものは除く)
+fn px_records(swarm: &Swarm<MyBehaviour>, ctx: &Context, peer_id: &PeerId) -> px::Records {
+    let addresses: Vec<Multiaddr> = swarm.external_addresses().chain(swarm.listeners()).cloned().collect();
+    let mut records = Vec::new();
+    match px::sign(&ctx.keypair, addresses) {
+        Ok(mine) => records.push(mine),
+        Err(e) => println!("Peer record signing error: {e:?}"),
+    }
+    records.extend(lock(&ctx.state.peers).records(peer_id, px::MAX_RECORDS));
+    px::Records(records)
+}
+
+// レコードを交換し、署名が正しいものだけpeer storeとDHTに入れる
+fn handle_px(swarm: &mut Swarm<MyBehaviour>, ctx: &Context, event: request_response::Event<px::Records, px::Records>) {
+    let state = &ctx.state;
+    let (peer, px::Records(records)) = match event {
+        request_response::Event::Message {
+            peer,
+            message: request_response::Message::Request { request, channel, .. },
+            ..
+        } => {
+            let mine = px_records(swarm, ctx, &peer);
+            if swarm.behaviour_mut().px.send_response(channel, mine).is_err() {
+                println!("Peer exchange response to {} failed", name(state, &peer));
+            }
+            (peer, request)
+        }
+        request_response::Event::Message {
+            peer,
+            message: request_response::Message::Response { response, .. },
+            ..
+        } => (peer, response),
+        request_response::Event::OutboundFailure { peer, error, .. } => {
+            tracing::info!(peer_id = %peer, error = %error, "peer exchange failed");
+            return;
+        }
+        _ => return,
+    };
+    for data in records.into_iter().take(px::MAX_RECORDS) {
+        let Some((peer_id, addresses)) = lock(&state.peers).add_record(data) else {
+            continue;
+        };
+        if peer_id == *swarm.local_peer_id() {
+            continue;
+        }
+        tracing::info!(from = %peer, peer_id = %peer_id, addresses = addresses.len(), "peer record received");
+        for addr in addresses {
+            swarm.add_peer_address(peer_id, addr.clone());
+            if let Some(kad) = swarm.behaviour_mut().kad.as_mut() {
+                kad.add_address(&peer_id, addr);
+            }
+        }
+    }
+}
+
+// ルーティングテーブルを近くのpeerで埋め直す。長く動かしていても/getなどが届くように定期的に呼ぶ。
+fn bootstrap(swarm: &mut Swarm<MyBehaviour>, state: &State) {
+    let Some(kad) = swarm.behaviour_mut().kad.as_mut() else {
+        return;
+    };
+    let mut status = lock(&state.kad);
+    match kad.bootstrap() {
+        Ok(_) => status.pending = false,
+        Err(kad::NoKnownPeers()) => status.pending = true,
+    }
+}
+
+// /put と /get の結果
+fn handle_kad(swarm: &mut Swarm<MyBehaviour>, ctx: &Context, event: kad::Event) {
+    let result = match event {
+        kad::Event::OutboundQueryProgressed { result: kad::QueryResult::Bootstrap(result), step, .. } => {
+            // peerごとに進捗が来るので、最後のものだけ数える
+            if step.last {
+                let result = result.map(|_| ()).map_err(|e| format!("{e:?}"));
+                tracing::info!(ok = result.is_ok(), "kad bootstrap finished");
+                lock(&ctx.state.kad).finished(result);
+            }
+            return;
+        }
+        kad::Event::OutboundQueryProgressed { result, .. } => result,
+        kad::Event::InboundRequest { request } => {
+            store_inbound(swarm, ctx, request);
+            return;
+        }
+        kad::Event::RoutingUpdated { is_new_peer, old_peer, .. } => {
+            let pending = {
+                let mut status = lock(&ctx.state.kad);
+                if is_new_peer {
+                    status.added += 1;
+                }
+                if old_peer.is_some() {
+                    status.removed += 1;
+                }
+                status.pending
+            };
+            // 起動時は空でできなかったので、最初のpeerが入ったらすぐにやる
+            if pending {
+                bootstrap(swarm, &ctx.state);
+            }
+            return;
+        }
+        _ => return,
+    };
+    match result {
+        kad::QueryResult::PutRecord(Ok(kad::PutRecordOk { key })) => {
+            println!("Stored {}", String::from_utf8_lossy(key.as_ref()));
+        }
+        kad::QueryResult::PutRecord(Err(e)) => println!("Put error: {e:?}"),
+        kad::QueryResult::GetRecord(Ok(kad::GetRecordOk::FoundRecord(kad::PeerRecord { peer, record }))) => {
+            if let Some(nick) = nickname::nick_of(&record.key) {
+                found_nickname(swarm, ctx, &nick, &record.value);
+                return;
+            }
+            let from = peer.map(|p| p.to_string()).unwrap_or_else(|| "local store".to_string());
+            println!(
+                "{} = {} (from {from})",
+                String::from_utf8_lossy(record.key.as_ref()),
+                String::from_utf8_lossy(&record.value),
+            );
+        }
+        kad::QueryResult::GetRecord(Ok(kad::GetRecordOk::FinishedWithNoAdditionalRecord { .. })) => {}
+        kad::QueryResult::GetRecord(Err(kad::GetRecordError::NotFound { key, .. })) => {
+            // まだ誰も登録していなければ自分のものにする
+            if let Some(nick) = nickname::nick_of(&key)
+                && lock(&ctx.state.claims).remove(&nick)
+            {
+                put_nickname(swarm, ctx, &nick);
+                return;
+            }
+            println!("{} not found", String::from_utf8_lossy(key.as_ref()));
+        }
+        kad::QueryResult::GetRecord(Err(e)) => println!("Get error: {e:?}"),
+        kad::QueryResult::StartProviding(Ok(kad::AddProviderOk { key })) => {
+            println!("Now providing {}", String::from_utf8_lossy(key.as_ref()));
+        }
+        kad::QueryResult::StartProviding(Err(e)) => println!("Provide error: {e:?}"),
+        kad::QueryResult::GetProviders(Ok(kad::GetProvidersOk::FoundProviders { key, providers })) => {
+            let hash = String::from_utf8_lossy(key.as_ref()).to_string();
+            for peer_id in &providers {
+                println!("{hash} is provided by {peer_id}");
+            }
+            // /fetch 中なら、最初に見つかったpeerに頼む
+            let mut fetches = lock(&ctx.state.fetches);
+            if let Some(fetch) = fetches.get_mut(&hash)
+                && !fetch.requested
+                && let Some(peer_id) = providers.iter().find(|p| *p != swarm.local_peer_id())
+            {
+                println!("Fetching {hash} from {peer_id}");
+                swarm.behaviour_mut().blocks.send_request(peer_id, blocks::Want(hash.clone()));
+                fetch.requested = true;
+            }
+        }
+        kad::QueryResult::GetProviders(Err(e)) => println!("Find providers error: {e:?}"),
+        kad::QueryResult::GetProviders(Ok(kad::GetProvidersOk::FinishedWithNoAdditionalRecord { .. })) => {}
+        _ => {}
+    }
+}
+
+// DHTで預かってほしいと頼まれたもの。nickの登録は署名を確かめ、
+// 別のpeerがすでに登録しているnickなら預からない。
+fn store_inbound(swarm: &mut Swarm<MyBehaviour>, ctx: &Context, request: kad::InboundRequest) {
+    let state = &ctx.state;
+    let Some(kad) = swarm.behaviour_mut().kad.as_mut() else {
+        return;
+    };
+    let store = kad.store_mut();
+    let result = match request {
+        kad::InboundRequest::PutRecord { source, record: Some(record), .. } => {
+            if let Some(nick) = nickname::nick_of(&record.key) {
+                let Some(owner) = nickname::owner(&nick, &record.value) else {
+                    println!("Dropped a nick record for {nick} with an invalid signature from {}", name(state, &source));
+                    return;
+                };
+                if let Some(existing) = store.get(&record.key)
+                    && let Some(first) = nickname::owner(&nick, &existing.value)
+                    && first != owner
+                {
+                    println!("* WARNING: {owner} tried to claim the nick {nick}, which is registered to {first}");
+                    return;
                 }
-                _ => {}
             }
+            store.put(record)
+        }
+        kad::InboundRequest::AddProvider { record: Some(record) } => store.add_provider(record),
+        _ => return,
+    };
+    if let Err(e) = result {
+        println!("Record store error: {e:?}");
+    }
+}
+
+// /claim と /whois で見つかったnickの登録
+fn found_nickname(swarm: &mut Swarm<MyBehaviour>, ctx: &Context, nick: &str, value: &[u8]) {
+    let state = &ctx.state;
+    let Some(owner) = nickname::owner(nick, value) else {
+        println!("{nick}: the record has an invalid signature");
+        return;
+    };
+    if lock(&state.claims).remove(nick) {
+        if owner == *swarm.local_peer_id() {
+            // 自分の登録なら置き直して期限を延ばす
+            put_nickname(swarm, ctx, nick);
+        } else {
+            println!("{nick} is already claimed by {}", name(state, &owner));
+        }
+        return;
+    }
+    // 同じnickに違う持ち主が見つかったら、どこかで上書きされている
+    match lock(&state.whois).insert(nick.to_string(), owner) {
+        Some(first) if first != owner => {
+            println!("* WARNING: {nick} is claimed by both {first} and {owner}");
+        }
+        Some(_) => {}
+        None => println!("{nick} is {owner} ({})", nick_or_name(state, &owner)),
+    }
+}
+
+fn put_nickname(swarm: &mut Swarm<MyBehaviour>, ctx: &Context, nick: &str) {
+    let value = match nickname::sign(&ctx.keypair, nick) {
+        Ok(value) => value,
+        Err(e) => {
+            println!("Nick signing error: {e:?}");
+            return;
+        }
+    };
+    let record = kad::Record::new(nickname::key(nick), value);
+    let quorum = ctx.opts.kad_quorum.unwrap_or(kad::Quorum::One);
+    let Some(kad) = swarm.behaviour_mut().kad.as_mut() else {
+        return;
+    };
+    match kad.put_record(record, quorum) {
+        Ok(_) => println!("Claiming {nick}"),
+        Err(e) => println!("Put error: {e:?}"),
+    }
+}
+
+// ブロックを頼まれたら返し、もらったらハッシュを確かめて保存する
+fn handle_blocks(
+    swarm: &mut Swarm<MyBehaviour>,
+    ctx: &Context,
+    event: request_response::Event<blocks::Want, blocks::BlockResponse>,
+) {
+    let state = &ctx.state;
+    match event {
+        request_response::Event::Message {
+            message: request_response::Message::Request { request: blocks::Want(hash), channel, .. },
+            ..
+        } => {
+            let response = match lock(&state.blocks).get(&hash) {
+                Some(data) => blocks::BlockResponse::Block(data.clone()),
+                None => blocks::BlockResponse::NotFound,
+            };
+            if swarm.behaviour_mut().blocks.send_response(channel, response).is_err() {
+                println!("Block response for {hash} failed");
+            }
+        }
+        request_response::Event::Message {
+            peer,
+            message: request_response::Message::Response { response, .. },
+            ..
+        } => {
+            let blocks::BlockResponse::Block(data) = response else {
+                println!("{} does not have the block anymore", name(state, &peer));
+                return;
+            };
+            let hash = blocks::hash(&data);
+            // 頼んでいないブロックやハッシュが合わないものは捨てる
+            let Some(fetch) = lock(&state.fetches).remove(&hash) else {
+                println!("Dropped an unexpected block from {}", name(state, &peer));
+                return;
+            };
+            if let Some(output) = &fetch.output
+                && let Err(e) = std::fs::write(output, &data)
+            {
+                println!("Write error for {}: {e:?}", output.display());
+            }
+            match lock(&state.blocks).put(data) {
+                Ok(_) => println!("Fetched {hash} from {}", name(state, &peer)),
+                Err(e) => println!("Fetched {hash} but failed to store: {e:?}"),
+            }
+            // もらったら自分も提供する
+            if let Some(kad) = swarm.behaviour_mut().kad.as_mut()
+                && let Err(e) = kad.start_providing(kad::RecordKey::new(&hash))
+            {
+                println!("Provide error: {e:?}");
+            }
+        }
+        request_response::Event::OutboundFailure { peer, error, .. } => {
+            println!("Block request to {} failed: {error}", name(state, &peer));
         }
+        _ => {}
     }
 }
 
+// 検証を通って表示を待っているメッセージ
+struct Incoming {
+    // 書いた人
+    from: PeerId,
+    // 中継してくれたpeer
+    peer_id: PeerId,
+    id: gossipsub::MessageId,
+    topic: gossipsub::TopicHash,
+    text: String,
+}
+
+// 順番を揃えたメッセージを表示やブリッジに知らせ、botに渡す
+fn deliver(swarm: &mut Swarm<MyBehaviour>, ctx: &Context, delivery: Delivery<Incoming>) {
+    let Incoming { from, peer_id, id, topic, text } = match delivery {
+        Delivery::Message(incoming) => incoming,
+        Delivery::Missed { from, count } => {
+            let _ = ctx.events.send(NodeEvent::Missed { from, count });
+            return;
+        }
+    };
+    let event = NodeEvent::Message { from, peer_id, id, topic: topic.clone(), text: text.clone() };
+    let _ = ctx.events.send(event);
+    let message = bot::Message { from, topic: topic.as_str(), text: &text };
+    run_bots(swarm, ctx, |bot| bot.on_message(&message));
+}
+
+// 標準出力に表示する購読者
+async fn render(ctx: Arc<Context>, mut rx: broadcast::Receiver<NodeEvent>) {
+    let (state, opts) = (&ctx.state, &ctx.opts);
+    while let Some(event) = events::recv(&mut rx, "cli").await {
+        match event {
+            NodeEvent::Message { from, peer_id, id, text: msg, .. } => {
+                // 書いた人の鍵と名乗っているnickが合っているか
+                let badge = lock(&state.contacts).badge(&from, lock(&state.presence).nick(&from));
+                match opts.nick.as_deref() {
+                    Some(nick) if mention::is_mentioned(&msg, nick) => {
+                        println!(
+                            "Got message: '{}' with id: {id} from peer: {} [{badge}]",
+                            mention::highlight(&msg, nick),
+                            name(state, &peer_id),
+                        );
+                        lock(&state.mentions).push(Mention::new(from, msg.clone()));
+                    }
+                    _ => println!("Got message: '{msg}' with id: {id} from peer: {} [{badge}]", name(state, &peer_id)),
+                }
+            }
+            NodeEvent::Missed { from, count } => {
+                println!("* missed {count} messages from {}", nick_or_name(state, &from));
+            }
+            NodeEvent::Dm { from, text } => println!("DM from {}: {text}", nick_or_name(state, &from)),
+            NodeEvent::PeerConnected { peer_id, relayed, .. } => {
+                let kind = if relayed { "relayed" } else { "direct" };
+                println!("Connected to {} ({kind})", name(state, &peer_id));
+            }
+            NodeEvent::NatStatusChanged { old, new } => println!("NAT status changed: {old} -> {new}"),
+        }
+    }
+}
+
+// ブリッジ、webhook、フック、デスクトップ通知に流す購読者
+async fn forward(ctx: Arc<Context>, mut rx: broadcast::Receiver<NodeEvent>) {
+    let (state, opts) = (&ctx.state, &ctx.opts);
+    while let Some(event) = events::recv(&mut rx, "bridges").await {
+        match event {
+            NodeEvent::Message { from, id, topic, text: msg, .. } => {
+                if let Some(mqtt) = &ctx.mqtt {
+                    mqtt.forward(&msg);
+                }
+                if let Some(webhook) = &ctx.webhook {
+                    webhook.notify(topic.as_str(), &from.to_string(), &id.to_string(), &msg);
+                }
+                ctx.hooks.message(topic.as_str(), &from.to_string(), &id.to_string(), &msg);
+                if let Some(irc) = &ctx.irc {
+                    irc.deliver(topic.as_str(), &irc::nick_for(&from.to_string()), &msg);
+                }
+                if let Some(matrix) = &ctx.matrix {
+                    matrix.forward(&from.to_string(), &msg);
+                }
+                if opts.notify && !lock(&state.muted_topics).contains(topic.as_str()) {
+                    notify::show(&format!("chat: {topic}"), &msg);
+                }
+            }
+            NodeEvent::PeerConnected { peer_id, address, relayed } => {
+                ctx.hooks.connected(&peer_id.to_string(), &address.to_string(), relayed);
+            }
+            NodeEvent::NatStatusChanged { old, new } => ctx.hooks.nat_status(&old, &new),
+            NodeEvent::Dm { from, text } => {
+                if let Some(irc) = &ctx.irc {
+                    irc.deliver_dm(&irc::nick_for(&from.to_string()), &text);
+                }
+            }
+            NodeEvent::Missed { .. } => {}
+        }
+    }
+}
+
+// すべてのbotにイベントを渡し、返ってきたものを送る
+fn run_bots(swarm: &mut Swarm<MyBehaviour>, ctx: &Context, mut event: impl FnMut(&mut dyn ChatBot) -> Vec<Reply>) {
+    let replies: Vec<Reply> = lock(&ctx.bots).iter_mut().flat_map(|bot| event(bot.as_mut())).collect();
+    for reply in replies {
+        match reply {
+            Reply::Publish { topic, text } => {
+                if let Err(e) = publish(swarm, ctx, &gossipsub::IdentTopic::new(topic), &text) {
+                    println!("Publish error for bot reply: {e:?}");
+                }
+            }
+            Reply::Dm { to, text } => {
+                swarm.behaviour_mut().direct.send_request(&to, DirectRequest::Dm { text: text.clone() });
+                lock(&ctx.state.conversations).push(to, true, text);
+            }
+        }
+    }
+}
+
+fn listen_on_relay(
+    swarm: &mut Swarm<MyBehaviour>,
+    state: &State,
+    relay: &Multiaddr,
+) -> Result<(), libp2p::TransportError<io::Error>> {
+    let listener = swarm.listen_on(relay.clone().with(Protocol::P2pCircuit))?;
+    lock(&state.relay).listener = Some(listener);
+    Ok(())
+}
+
+// 知っているアドレスすべてに同時にダイヤルし、最初につながったものを使う。
+// 同時に試す数は --dial-concurrency。どれが勝ったかは /peers に出る。
+fn dial_peer(swarm: &mut Swarm<MyBehaviour>, state: &State, peer_id: PeerId) {
+    let addresses = lock(&state.peers).dial_addresses(&peer_id);
+    let count = addresses.len();
+    let opts = DialOpts::peer_id(peer_id)
+        .addresses(addresses)
+        // kadなど、behaviourが知っているアドレスも足す
+        .extend_addresses_through_behaviour()
+        .condition(PeerCondition::DisconnectedAndNotDialing)
+        .build();
+    match swarm.dial(opts) {
+        Ok(()) => tracing::info!(peer_id = %peer_id, addresses = count, "dialing"),
+        Err(e) => println!("Dial error: {peer_id}: {e:?}"),
+    }
+}
+
+// つながったアドレスのトランスポート
+fn transport(addr: &Multiaddr) -> &'static str {
+    if is_relayed(addr) {
+        return "relay";
+    }
+    if addr.iter().any(|p| matches!(p, Protocol::QuicV1)) {
+        "quic"
+    } else if addr.iter().any(|p| matches!(p, Protocol::Tcp(_))) {
+        "tcp"
+    } else {
+        "other"
+    }
+}
+
+// /p2p-circuit を含むアドレスはrelay経由
+fn is_relayed(addr: &Multiaddr) -> bool {
+    addr.iter().any(|p| matches!(p, Protocol::P2pCircuit))
+}
+
+// どのイベントでも関係するpeerの最終時刻を更新する
+fn track_peers(peers: &mut PeerStore, event: &SwarmEvent<MyBehaviourEvent>) {
+    match event {
+        SwarmEvent::ConnectionEstablished { peer_id, endpoint, .. } => {
+            let addr = endpoint.get_remote_address();
+            peers.connected(*peer_id, is_relayed(addr), transport(addr));
+        }
+        SwarmEvent::ConnectionClosed { peer_id, .. } => peers.disconnected(*peer_id),
+        SwarmEvent::Behaviour(MyBehaviourEvent::Mdns(mdns::Event::Discovered(list))) => {
+            for (peer_id, addr) in list {
+                peers.touch(*peer_id);
+                peers.add_address(*peer_id, addr.clone());
+            }
+        }
+        SwarmEvent::Behaviour(MyBehaviourEvent::Identify(identify::Event::Received { peer_id, info, .. })) => {
+            for addr in &info.listen_addrs {
+                peers.add_address(*peer_id, addr.clone());
+            }
+        }
+        SwarmEvent::Behaviour(MyBehaviourEvent::Gossipsub(event)) => match event {
+            gossipsub::Event::Message { propagation_source, message, .. } => {
+                peers.touch(*propagation_source);
+                if let Some(source) = message.source {
+                    peers.touch(source);
+                }
+            }
+            gossipsub::Event::Subscribed { peer_id, .. }
+            | gossipsub::Event::Unsubscribed { peer_id, .. } => peers.touch(*peer_id),
+            _ => {}
+        },
+        _ => {}
+    }
+}
+
+// 接続の出来事を /connlog のために覚えておく
+fn log_connections(log: &mut connlog::ConnLog, event: &SwarmEvent<MyBehaviourEvent>) {
+    match event {
+        SwarmEvent::ConnectionEstablished { peer_id, endpoint, .. } => {
+            let address = endpoint.get_remote_address().clone();
+            let relayed = is_relayed(&address);
+            log.push(Some(*peer_id), connlog::Kind::Established { address, relayed });
+        }
+        SwarmEvent::ConnectionClosed { peer_id, cause, .. } => {
+            let cause = cause.as_ref().map(|c| c.to_string());
+            log.push(Some(*peer_id), connlog::Kind::Closed { cause });
+        }
+        SwarmEvent::OutgoingConnectionError { peer_id, error, .. } => {
+            log.push(*peer_id, connlog::Kind::DialFailed { error: error.to_string() });
+        }
+        SwarmEvent::IncomingConnectionError { send_back_addr, error, .. } => {
+            let kind = connlog::Kind::IncomingFailed { address: send_back_addr.clone(), error: error.to_string() };
+            log.push(None, kind);
+        }
+        _ => {}
+    }
+}
+
+// MQTTブリッジがなければずっと待つ
+async fn recv_mqtt(mqtt: Option<&mqtt::Bridge>) -> Option<String> {
+    match mqtt {
+        Some(mqtt) => mqtt.recv().await,
+        None => std::future::pending().await,
+    }
+}
+
+async fn recv_matrix(matrix: Option<&matrix::Bridge>) -> Option<String> {
+    match matrix {
+        Some(matrix) => matrix.recv().await,
+        None => std::future::pending().await,
+    }
+}
+
+async fn recv_irc(irc: Option<&irc::Gateway>) -> Option<irc::Request> {
+    match irc {
+        Some(irc) => irc.recv().await,
+        None => std::future::pending().await,
+    }
+}
+
+fn handle_irc(swarm: &mut Swarm<MyBehaviour>, ctx: &Context, request: irc::Request) {
+    let state = &ctx.state;
+    match request {
+        irc::Request::Join(name) => {
+            if let Err(e) = swarm.behaviour_mut().gossipsub.subscribe(&gossipsub::IdentTopic::new(&name)) {
+                println!("Subscribe error for {name}: {e:?}");
+                return;
+            }
+            lock(&state.irc_topics).insert(name);
+        }
+        irc::Request::Publish { topic, text } => {
+            if let Err(e) = publish(swarm, ctx, &gossipsub::IdentTopic::new(topic), &text) {
+                println!("Publish error for IRC message: {e:?}");
+            }
+        }
+        irc::Request::Dm { to, text } => {
+            // IRCクライアントにはnick_forで短くしたnickを見せているので、つながっているpeerから探す
+            let peer_id = swarm
+                .connected_peers()
+                .find(|peer_id| irc::nick_for(&peer_id.to_string()) == to)
+                .copied()
+                .map_or_else(|| resolve_peer(state, &to), Ok);
+            match peer_id {
+                Ok(peer_id) => {
+                    swarm.behaviour_mut().direct.send_request(&peer_id, DirectRequest::Dm { text: text.clone() });
+                    lock(&state.conversations).push(peer_id, true, text);
+                }
+                Err(e) => println!("IRC DM error: {e}"),
+            }
+        }
+    }
+}
+
+// 減点し、しきい値を下回ったら切断してしばらく接続を拒否する
+fn penalize(swarm: &mut Swarm<MyBehaviour>, state: &State, peer_id: PeerId, offense: Offense) {
+    println!("Penalized {peer_id}: {offense}");
+    let banned = lock(&state.reputation).penalize(peer_id, offense);
+    if !banned {
+        return;
+    }
+    println!("Banned {peer_id} for {} minutes", reputation::BAN_DURATION.as_secs() / 60);
+    swarm.behaviour_mut().blocked.block_peer(peer_id);
+    swarm.behaviour_mut().gossipsub.blacklist_peer(&peer_id);
+    swarm.behaviour_mut().gossipsub.remove_explicit_peer(&peer_id);
+    let _ = swarm.disconnect_peer_id(peer_id);
+}
+
+// banが解けたpeer。/block したpeerはそのまま。
+fn unban(swarm: &mut Swarm<MyBehaviour>, state: &State, peer_id: PeerId) {
+    if lock(&state.blocklist).contains(&peer_id) {
+        return;
+    }
+    println!("Unbanned {peer_id}");
+    swarm.behaviour_mut().blocked.unblock_peer(peer_id);
+    swarm.behaviour_mut().gossipsub.remove_blacklisted_peer(&peer_id);
+    if lock(&state.known_peers).contains_key(&peer_id) {
+        swarm.behaviour_mut().gossipsub.add_explicit_peer(&peer_id);
+        dial_peer(swarm, state, peer_id);
+    }
+}
+
+// 「なぜ相手からつながらないのか」を調べるための情報をまとめて表示する
+fn print_diag(swarm: &Swarm<MyBehaviour>, state: &State) {
+    println!("== reachability ==");
+    let nat_status = match swarm.behaviour().autonat.as_ref() {
+        Some(autonat) => autonat.nat_status(),
+        None => {
+            println!("AutoNAT: disabled");
+            autonat::NatStatus::Unknown
+        }
+    };
+    match &nat_status {
+        autonat::NatStatus::Public(addr) => println!("AutoNAT: public ({addr})"),
+        autonat::NatStatus::Private => println!("AutoNAT: private (other peers could not dial back)"),
+        autonat::NatStatus::Unknown if swarm.behaviour().autonat.is_enabled() => {
+            println!("AutoNAT: unknown (needs connected peers to probe)")
+        }
+        autonat::NatStatus::Unknown => {}
+    }
+    println!("listen addresses:");
+    for addr in swarm.listeners() {
+        println!("  {addr}");
+    }
+    println!("external addresses:");
+    for addr in swarm.external_addresses() {
+        println!("  {addr}");
+    }
+    let diag = lock(&state.diag);
+    println!("observed by other peers (identify):");
+    for (peer_id, addr) in &diag.observed {
+        println!("  {addr} (seen by {peer_id})");
+    }
+    println!("UPnP: {}", diag.upnp.as_deref().unwrap_or("no result yet"));
+    let relay = lock(&state.relay);
+    match &relay.reservation {
+        Some(reservation) => println!("relay: {reservation}"),
+        None => println!("relay: no reservation"),
+    }
+
+    // よくある原因
+    if matches!(nat_status, autonat::NatStatus::Private) && relay.reservation.is_none() {
+        println!("hint: you are behind NAT. Forward a port (--external-address), enable UPnP on the router, or use --relay.");
+    }
+    let behind_nat = diag.observed.iter().any(|(_, observed)| !swarm.listeners().any(|l| l == observed));
+    if behind_nat && swarm.external_addresses().next().is_none() {
+        println!("hint: other peers see a different address than you listen on, so you are probably behind NAT.");
+    }
+}
+
+// 表示するpeerの名前。/alias で付けた名前があればそれ。
+fn name(state: &State, peer_id: &PeerId) -> String {
+    lock(&state.aliases).get(peer_id).map(String::from).unwrap_or_else(|| peer_id.to_string())
+}
+
+// /alias の名前、在席情報のnick、PeerIdの順
+fn nick_or_name(state: &State, peer_id: &PeerId) -> String {
+    if let Some(alias) = lock(&state.aliases).get(peer_id) {
+        return alias.to_string();
+    }
+    lock(&state.presence).nick(peer_id).map(String::from).unwrap_or_else(|| peer_id.to_string())
+}
+
+// /dm の宛先。peer idでなければ /alias の名前か在席情報のnickから探す。
+fn resolve_peer(state: &State, name: &str) -> Result<PeerId, String> {
+    if let Ok(peer_id) = name.parse() {
+        return Ok(peer_id);
+    }
+    if let Some(peer_id) = lock(&state.aliases).find(name) {
+        return Ok(peer_id);
+    }
+    lock(&state.presence).find(name).ok_or_else(|| format!("unknown nick: {name}"))
+}
+
+fn handle_command(swarm: &mut Swarm<MyBehaviour>, ctx: &Context, command: Command) {
+    let (state, opts) = (&ctx.state, &ctx.opts);
+    match command {
+        Command::Block(peer_id) => {
+            swarm.behaviour_mut().blocked.block_peer(peer_id);
+            swarm.behaviour_mut().gossipsub.blacklist_peer(&peer_id);
+            swarm.behaviour_mut().gossipsub.remove_explicit_peer(&peer_id);
+            let _ = swarm.disconnect_peer_id(peer_id);
+            lock(&state.known_peers).remove(&peer_id);
+            match lock(&state.blocklist).insert(peer_id) {
+                Ok(true) => println!("Blocked {peer_id}"),
+                Ok(false) => println!("{peer_id} is already blocked"),
+                Err(e) => println!("Blocked {peer_id} but failed to save: {e:?}"),
+            }
+        }
+        Command::Unblock(peer_id) => {
+            swarm.behaviour_mut().blocked.unblock_peer(peer_id);
+            swarm.behaviour_mut().gossipsub.remove_blacklisted_peer(&peer_id);
+            match lock(&state.blocklist).remove(&peer_id) {
+                Ok(true) => println!("Unblocked {peer_id}"),
+                Ok(false) => println!("{peer_id} is not blocked"),
+                Err(e) => println!("Unblocked {peer_id} but failed to save: {e:?}"),
+            }
+        }
+        Command::Mute(topic) => {
+            println!("Muted notifications for {topic}");
+            lock(&state.muted_topics).insert(topic);
+        }
+        Command::Unmute(topic) => {
+            println!("Unmuted notifications for {topic}");
+            lock(&state.muted_topics).remove(&topic);
+        }
+        Command::Peers => {
+            for (peer_id, info) in lock(&state.peers).sorted() {
+                let status = if info.is_connected() { "connected" } else { "disconnected" };
+                match info.transport {
+                    Some(transport) if info.is_connected() => {
+                        println!("{}: {status} via {transport}, {}", name(state, &peer_id), info.seen_ago())
+                    }
+                    _ => println!("{}: {status}, {}", name(state, &peer_id), info.seen_ago()),
+                }
+                if let Some(profile) = &info.profile {
+                    println!("  {profile}");
+                }
+                for addr in &info.addresses {
+                    println!("  {addr}");
+                }
+            }
+        }
+        Command::Sent => {
+            let sent = lock(&state.sent);
+            if sent.iter().next().is_none() {
+                println!("Nothing sent yet");
+            }
+            for s in sent.iter() {
+                println!("{s}");
+            }
+        }
+        Command::Measure => {
+            let stats = lock(&state.measurements);
+            if stats.is_empty() {
+                println!("No measurements yet (start every node with --measure and send some messages)");
+                return;
+            }
+            for (hops, summary) in stats.by_hops() {
+                if let Some(summary) = summary {
+                    println!("{hops}: {summary}");
+                }
+            }
+            for (peer_id, summary) in stats.by_peer() {
+                println!("  {}: {summary}", name(state, &peer_id));
+            }
+        }
+        Command::Connlog => {
+            let log = lock(&state.connlog);
+            if log.iter().next().is_none() {
+                println!("No connection events yet");
+            }
+            for entry in log.iter() {
+                println!("{entry}");
+            }
+        }
+        Command::Reputation => {
+            let list = lock(&state.reputation).list();
+            if list.is_empty() {
+                println!("No penalized peers");
+            }
+            for (peer_id, score, remaining) in list {
+                match remaining {
+                    Some(remaining) => {
+                        println!("{}: {score:.1} (banned, {}s left)", name(state, &peer_id), remaining.as_secs());
+                    }
+                    None => println!("{}: {score:.1}", name(state, &peer_id)),
+                }
+            }
+        }
+        Command::Diag => print_diag(swarm, state),
+        Command::Relay => {
+            let relay = lock(&state.relay);
+            match (&relay.reservation, relay.listener) {
+                (Some(reservation), _) => println!("reservation: {reservation}"),
+                (None, Some(_)) => println!("reservation: pending"),
+                (None, None) => println!("reservation: none (use --relay <multiaddr>)"),
+            }
+            for addr in &relay.addresses {
+                println!("  {addr}");
+            }
+            if relay.failures > 0 {
+                println!("{} failures so far", relay.failures);
+            }
+        }
+        Command::Mesh => {
+            // topicごとのmeshのpeer。--mesh-n などを変えたときの違いを確かめる。
+            let topics: Vec<_> = swarm.behaviour().gossipsub.topics().cloned().collect();
+            for topic in topics {
+                let mesh: Vec<_> = swarm.behaviour().gossipsub.mesh_peers(&topic).collect();
+                println!("{topic}: {} mesh peers", mesh.len());
+                let peers = lock(&state.peers);
+                for peer_id in mesh {
+                    let kind = match peers.get(peer_id) {
+                        Some(info) if info.relayed => "relayed",
+                        _ => "direct",
+                    };
+                    println!("  {} ({kind})", name(state, peer_id));
+                }
+            }
+            println!("{} peers known to gossipsub", swarm.behaviour().gossipsub.all_peers().count());
+        }
+        Command::SendAt(at, text) => {
+            // 普通に入力したときと同じく大文字にして送る
+            let entry = schedule::Scheduled { at, text: text.to_uppercase() };
+            let secs = entry.secs();
+            match lock(&state.schedule).push(entry) {
+                Ok(()) => println!("Scheduled at {secs} (UNIX time)"),
+                Err(e) => println!("Schedule save error: {e:?}"),
+            }
+        }
+        // 相手は --max-message-size を超えるとデコードする前に捨てるので送らない(自分の設定で判定するのは目安)
+        Command::Dm(_, text) | Command::Route(_, text) if text.len() > opts.max_message_size() => {
+            println!("Message too large: {} bytes (max {})", text.len(), opts.max_message_size());
+        }
+        Command::Dm(to, text) => match resolve_peer(state, &to) {
+            Ok(peer_id) => {
+                swarm.behaviour_mut().direct.send_request(&peer_id, DirectRequest::Dm { text: text.clone() });
+                lock(&state.conversations).push(peer_id, true, text);
+            }
+            Err(e) => println!("{e}"),
+        },
+        Command::Route(route, text) => {
+            let route = match route.iter().map(|hop| resolve_peer(state, hop)).collect::<Result<Vec<_>, _>>() {
+                Ok(route) => route,
+                Err(e) => {
+                    println!("{e}");
+                    return;
+                }
+            };
+            match routing::Routed::new(&ctx.keypair, &route, text) {
+                // 最初のpeerにだけ送る。あとは経路に沿って渡してもらう。
+                Ok(routed) => {
+                    swarm.behaviour_mut().route.send_request(&route[0], routed);
+                }
+                Err(e) => println!("Route error: {e}"),
+            }
+        }
+        Command::Conv(with) => match resolve_peer(state, &with) {
+            Ok(peer_id) => {
+                let conversations = lock(&state.conversations);
+                let messages = conversations.get(&peer_id);
+                if messages.is_empty() {
+                    println!("No messages with {with}");
+                }
+                for m in messages {
+                    println!("{m}");
+                }
+            }
+            Err(e) => println!("{e}"),
+        },
+        Command::Verify(name, confirmed) => match resolve_peer(state, &name) {
+            Ok(peer_id) if confirmed => match lock(&state.contacts).verify(peer_id) {
+                Ok(_) => println!("Marked {peer_id} as verified"),
+                Err(e) => println!("Verified {peer_id} but failed to save: {e:?}"),
+            },
+            Ok(peer_id) => {
+                // 相手も /verify <自分> で同じものを見ているはずなので読み合わせる
+                println!("{name}: {}", contacts::fingerprint(&peer_id));
+                println!("me: {}", contacts::fingerprint(swarm.local_peer_id()));
+                println!("If they match what the other side sees, run /verify {name} ok");
+            }
+            Err(e) => println!("{e}"),
+        },
+        Command::Alias(peer_id, alias) => match lock(&state.aliases).set(peer_id, alias.clone()) {
+            Ok(()) if alias.is_empty() => println!("Removed the alias of {peer_id}"),
+            Ok(()) => println!("{peer_id} is now shown as {alias}"),
+            Err(e) => println!("Set the alias of {peer_id} but failed to save: {e:?}"),
+        },
+        Command::Put(..)
+        | Command::Get(_)
+        | Command::Claim(_)
+        | Command::Whois(_)
+        | Command::Provide(_)
+        | Command::FindProviders(_)
+        | Command::Add(_)
+        | Command::Fetch(..)
+        | Command::KadStats => match swarm.behaviour_mut().kad.as_mut() {
+            Some(kad) => handle_kad_command(kad, ctx, command),
+            None => println!("This command needs Kademlia, which is disabled (--disable kad)"),
+        },
+        Command::Who => {
+            let presence = lock(&state.presence);
+            println!("me: {}", lock(&state.status));
+            for (peer_id, entry) in presence.iter() {
+                println!("{}: {entry}", name(state, peer_id));
+            }
+        }
+        Command::Status(status) => {
+            // 次の通知で他のpeerに伝わる
+            *lock(&state.status) = status;
+            println!("Status: {status}");
+        }
+        Command::Mentions => {
+            let mentions = lock(&state.mentions);
+            if mentions.is_empty() {
+                println!("No mentions");
+            }
+            for m in mentions.iter() {
+                println!("{m}");
+            }
+        }
+    }
+}
+
+// DHTを使うコマンド
+fn handle_kad_command(kad: &mut kad::Behaviour<kad::store::MemoryStore>, ctx: &Context, command: Command) {
+    let (state, opts) = (&ctx.state, &ctx.opts);
+    match command {
+        Command::Put(key, value) => {
+            let record = kad::Record::new(kad::RecordKey::new(&key), value.into_bytes());
+            let quorum = opts.kad_quorum.unwrap_or(kad::Quorum::One);
+            if let Err(e) = kad.put_record(record, quorum) {
+                println!("Put error: {e:?}");
+            }
+        }
+        Command::Get(key) => {
+            kad.get_record(kad::RecordKey::new(&key));
+        }
+        Command::Claim(nick) => {
+            // 先に誰かが登録していないか調べてから置く
+            lock(&state.claims).insert(nick.clone());
+            kad.get_record(nickname::key(&nick));
+        }
+        Command::Whois(nick) => {
+            lock(&state.whois).remove(&nick);
+            kad.get_record(nickname::key(&nick));
+        }
+        Command::Provide(cid) => {
+            // 中身ではなく「自分が持っている」という記録(provider record)だけをDHTに置く
+            if let Err(e) = kad.start_providing(kad::RecordKey::new(&cid)) {
+                println!("Provide error: {e:?}");
+            }
+        }
+        Command::FindProviders(cid) => {
+            kad.get_providers(kad::RecordKey::new(&cid));
+        }
+        Command::Add(path) => {
+            let hash = std::fs::read(&path).map_err(|e| e.to_string()).and_then(|data| {
+                // これより大きいと相手がデコードする前に捨てる
+                if data.len() > blocks::MAX_BLOCK_SIZE {
+                    return Err(format!("too large: {} bytes (max {})", data.len(), blocks::MAX_BLOCK_SIZE));
+                }
+                lock(&state.blocks).put(data).map_err(|e| e.to_string())
+            });
+            match hash {
+                Ok(hash) => {
+                    println!("Added {path} as {hash}");
+                    if let Err(e) = kad.start_providing(kad::RecordKey::new(&hash)) {
+                        println!("Provide error: {e:?}");
+                    }
+                }
+                Err(e) => println!("Add error for {path}: {e}"),
+            }
+        }
+        Command::Fetch(hash, output) => {
+            if lock(&state.blocks).get(&hash).is_some() {
+                println!("{hash} is already here");
+                return;
+            }
+            // 持っているpeerを探し、見つかったらhandle_kadで頼む
+            let fetch = Fetch {
+                output: output.map(PathBuf::from),
+                requested: false,
+            };
+            lock(&state.fetches).insert(hash.clone(), fetch);
+            kad.get_providers(kad::RecordKey::new(&hash));
+        }
+        Command::KadStats => {
+            let (mut peers, mut buckets) = (0, 0);
+            for bucket in kad.kbuckets() {
+                peers += bucket.num_entries();
+                buckets += 1;
+            }
+            println!("routing table: {peers} peers in {buckets} buckets");
+            let store = kad.store_mut();
+            println!("stored: {} records, {} provided keys", store.records().count(), store.provided().count());
+            let status = lock(&state.kad);
+            println!("routing updates: {} added, {} evicted", status.added, status.removed);
+            match &status.last {
+                Some((at, Ok(()))) => println!("last bootstrap: ok, {}s ago", at.elapsed().as_secs()),
+                Some((at, Err(e))) => println!("last bootstrap: failed {}s ago: {e}", at.elapsed().as_secs()),
+                None if status.pending => println!("last bootstrap: waiting for the first peer"),
+                None => println!("last bootstrap: running"),
+            }
+            println!(
+                "{} bootstraps, {} failures, every {}s",
+                status.bootstraps,
+                status.failures,
+                opts.kad_bootstrap_interval().as_secs()
+            );
+        }
+        _ => {}
+    }
+}
+
+// --log-format json ならJSON形式で出力する。
+// JSONのときはpeer_id, topic, message_idなどのフィールドがそのままキーになる。
+// --log-file があればコンソールとは別にファイルにも出力し、--log-rotation の間隔で切り替える。
+fn init_tracing(opts: &Options) -> Result<Option<WorkerGuard>, Box<dyn Error>> {
+    let console = match opts.log_format {
+        LogFormat::Text => fmt::layer().boxed(),
+        LogFormat::Json => fmt::layer().json().boxed(),
+    };
+
+    let (file, guard) = match &opts.log_file {
+        Some(path) => {
+            let dir = match path.parent() {
+                Some(dir) if !dir.as_os_str().is_empty() => dir,
+                _ => Path::new("."),
+            };
+            let prefix = path.file_name().ok_or("--log-file needs a file name")?;
+            // 実際のファイル名は <prefix>.<日時> になる
+            let appender = tracing_appender::rolling::Builder::new()
+                .rotation(opts.log_rotation.clone().unwrap_or(Rotation::DAILY))
+                .filename_prefix(prefix.to_string_lossy())
+                .build(dir)?;
+            let (writer, guard) = tracing_appender::non_blocking(appender);
+            // ファイルにはエスケープシーケンスを入れない
+            let layer = fmt::layer().with_writer(writer).with_ansi(false);
+            let layer = match opts.log_format {
+                LogFormat::Text => layer.boxed(),
+                LogFormat::Json => layer.json().boxed(),
+            };
+            (Some(layer), Some(guard))
+        }
+        None => (None, None),
+    };
+
+    let _ = tracing_subscriber::registry()
+        .with(EnvFilter::from_default_env())
+        .with(console)
+        .with(file)
+        .try_init();
+    Ok(guard)
+}
+
+// --record で保存したイベントを読み込み、受信時と同じ判断をして結果を表示する。
+// 実際にはpublishしないのでネットワークなしでデバッグできる。
+fn replay(path: &Path) -> Result<(), Box<dyn Error>> {
+    // 作り直しをまたいだ記録だと同じメッセージが何度か入っている
+    let mut displayed = Displayed::default();
+    let mut hello = bot::HelloBot;
+    for (time, recorded) in record::load(path)? {
+        match recorded {
+            Recorded::Discovered(peer_id, _) => println!("[{time}] mDNS discovered a new peer: {peer_id}"),
+            Recorded::Expired(peer_id, _) => println!("[{time}] mDNS discover peer has expired: {peer_id}"),
+            Recorded::Message { source, id, key, data } => {
+                if !displayed.first_time(&key) {
+                    continue;
+                }
+                let msg = String::from_utf8_lossy(&data);
+                println!("[{time}] Got message: '{msg}' with id: {id} from peer: {source}");
+                let message = bot::Message { from: source, topic: TOPIC, text: &msg };
+                for reply in hello.on_message(&message) {
+                    println!("[{time}]   -> would send: {reply:?}");
+                }
+            }
+            Recorded::NewListenAddr(address) => println!("[{time}] Local node is listening on {address}"),
+            Recorded::Other(s) => println!("[{time}] {s}"),
+        }
+    }
+    Ok(())
+}
+
 // QUICの有無を分けたかったら元から分けるのが一番楽。
 // ちなみに私はQUICプロトコルのことを知らない。
 //  https://ja.wikipedia.org/wiki/QUIC
-fn swarm_with_quic() -> Result<Swarm<MyBehaviour>, Box<dyn Error>> {
-    let swarm = libp2p::SwarmBuilder::with_new_identity()
+fn swarm_with_quic(keypair: Keypair, opts: &Options) -> Result<Swarm<MyBehaviour>, Box<dyn Error>> {
+    let swarm = libp2p::SwarmBuilder::with_existing_identity(keypair)
         .with_tokio()
-        .with_tcp(
-            tcp::Config::default(),
-            noise::Config::new, // noise, tls, plaintext(for test), ...
-            yamux::Config::default, // yamux, mplex, ...
-        )?
-        .with_quic()
-        .with_behaviour(my_behaviour)?
+        .with_quic_config(|mut cfg| {
+            if let Some(timeout) = opts.handshake_timeout {
+                cfg.handshake_timeout = timeout;
+            }
+            cfg
+        })
+        .with_other_transport(|key| tcp_transport(key, opts))?
+        .with_relay_client(noise::Config::new, yamux::Config::default)?
+        .with_behaviour(|key, relay_client| my_behaviour(key, relay_client, opts))?
+        .with_swarm_config(|cfg| swarm_config(cfg, opts))
         .build();
     Ok(swarm)
 }
 
+// with_tcpと同じくTCPの上にnoiseとyamuxを重ねる。時間の上限を付けられるよう自分で組み立てる。
+//  --connect-timeout   : TCPの接続ができるまで
+//  --handshake-timeout : 接続してからnoiseとyamuxのネゴシエーションが終わるまで(TCPの接続の時間も含む)
+// 遅いpeerや悪意のあるpeerがハンドシェイクの途中で止まっても、いつまでも接続の枠を使わせない。
+fn tcp_transport(key: &Keypair, opts: &Options) -> Result<Boxed<(PeerId, StreamMuxerBox)>, noise::Error> {
+    let tcp = tcp::tokio::Transport::new(tcp_config(opts));
+    let tcp = match opts.connect_timeout {
+        Some(timeout) => TransportTimeout::with_outgoing_timeout(tcp, timeout).boxed(),
+        None => tcp.boxed(),
+    };
+    let transport = tcp
+        .upgrade(upgrade::Version::V1Lazy)
+        .authenticate(noise::Config::new(key)?) // noise, tls, plaintext(for test), ...
+        .multiplex(yamux::Config::default()); // yamux, mplex, ...
+    Ok(match opts.handshake_timeout {
+        Some(timeout) => transport.timeout(timeout).boxed(),
+        None => transport.boxed(),
+    })
+}
+
+// --tcp-nodelay などのソケットの設定
+fn tcp_config(opts: &Options) -> tcp::Config {
+    let mut cfg = tcp::Config::default();
+    if let Some(nodelay) = opts.tcp_nodelay {
+        cfg = cfg.nodelay(nodelay);
+    }
+    if let Some(backlog) = opts.tcp_backlog {
+        cfg = cfg.listen_backlog(backlog);
+    }
+    if let Some(ttl) = opts.tcp_ttl {
+        cfg = cfg.ttl(ttl);
+    }
+    cfg
+}
+
 fn listen_with_quic(swarm: &mut Swarm<MyBehaviour>) -> Result<(), Box<dyn Error>> {
     swarm.listen_on("/ip4/0.0.0.0/udp/0/quic-v1".parse()?)?;
     swarm.listen_on("/ip4/0.0.0.0/tcp/0".parse()?)?;
     Ok(())
 }
 
-fn swarm_without_quic() -> Result<Swarm<MyBehaviour>, Box<dyn Error>> {
-    let swarm = libp2p::SwarmBuilder::with_new_identity()
+fn swarm_without_quic(keypair: Keypair, opts: &Options) -> Result<Swarm<MyBehaviour>, Box<dyn Error>> {
+    let swarm = libp2p::SwarmBuilder::with_existing_identity(keypair)
         .with_tokio()
-        .with_tcp(
-            tcp::Config::default(),
-            noise::Config::new, // noise, tls, plaintext(for test), ...
-            yamux::Config::default, // yamux, mplex, ...
-        )?
-        .with_behaviour(my_behaviour)?
+        .with_other_transport(|key| tcp_transport(key, opts))?
+        .with_relay_client(noise::Config::new, yamux::Config::default)?
+        .with_behaviour(|key, relay_client| my_behaviour(key, relay_client, opts))?
+        .with_swarm_config(|cfg| swarm_config(cfg, opts))
         .build();
     Ok(swarm)
 }
@@ -180,7 +2196,7 @@ fn listen_without_quic(swarm: &mut Swarm<MyBehaviour>) -> Result<(), Box<dyn Err
 
 fn get_swarm_fn(use_quic: bool) ->
     (
-        fn() -> Result<Swarm<MyBehaviour>, Box<dyn Error>>,
+        fn(Keypair, &Options) -> Result<Swarm<MyBehaviour>, Box<dyn Error>>,
         fn(&mut Swarm<MyBehaviour>) -> Result<(), Box<dyn Error>>,
     )
 {
@@ -191,11 +2207,35 @@ fn get_swarm_fn(use_quic: bool) ->
     }
 }
 
-fn my_behaviour(key: &Keypair) -> MyBehaviour {
-    behaviour(key).expect("build behaviour for MyBehaviour")
+// 接続を切るまでの時間などswarm全体の設定
+fn swarm_config(cfg: swarm::Config, opts: &Options) -> swarm::Config {
+    let cfg = match opts.idle_timeout {
+        Some(timeout) => cfg.with_idle_connection_timeout(timeout),
+        None => cfg,
+    };
+    let cfg = match opts.dial_concurrency {
+        Some(n) => cfg.with_dial_concurrency_factor(n),
+        None => cfg,
+    };
+    let cfg = match opts.notify_handler_buffer {
+        Some(n) => cfg.with_notify_handler_buffer_size(n),
+        None => cfg,
+    };
+    match opts.connection_event_buffer {
+        Some(n) => cfg.with_per_connection_event_buffer_size(n),
+        None => cfg,
+    }
+}
+
+fn my_behaviour(key: &Keypair, relay_client: relay::client::Behaviour, opts: &Options) -> MyBehaviour {
+    behaviour(key, relay_client, opts).expect("build behaviour for MyBehaviour")
 }
 
-fn behaviour(key: &Keypair) -> Result<MyBehaviour, Box<dyn Error>> {
+fn behaviour(
+    key: &Keypair,
+    relay_client: relay::client::Behaviour,
+    opts: &Options,
+) -> Result<MyBehaviour, Box<dyn Error>> {
     // ここでMessageIdを計算している。
     // GossipSubは同じMessageIdのブロードキャストをエラーにするので暫定で時間要素を入れている
     let message_id_fn = |message: &gossipsub::Message| {
@@ -214,22 +2254,89 @@ fn behaviour(key: &Keypair) -> Result<MyBehaviour, Box<dyn Error>> {
     };
 
     // Set a custom gossipsub configuration
-    let gossipsub_config = gossipsub::ConfigBuilder::default()
+    let mut builder = gossipsub::ConfigBuilder::default();
+    builder
         .heartbeat_interval(Duration::from_secs(10)) // This is set to aid debugging by not cluttering the log space
         .validation_mode(gossipsub::ValidationMode::Strict) // This sets the kind of message validation. The default is Strict (enforce message
         // signing)
         .message_id_fn(message_id_fn) // content-address messages. No two messages of the same content will be propagated.
+        .validate_messages() // 受信したメッセージはアプリがフィルタを通してから転送する
+        .max_transmit_size(opts.max_message_size()); // これを超えるメッセージはデコードする前に捨てられる
+    // 伝わりやすさと帯域のかね合いを試せるようにする。/mesh で結果を見られる。
+    if let Some(flood_publish) = opts.flood_publish {
+        builder.flood_publish(flood_publish);
+    }
+    if let Some(n) = opts.mesh_n {
+        builder.mesh_n(n);
+    }
+    if let Some(n) = opts.mesh_n_low {
+        builder.mesh_n_low(n);
+    }
+    if let Some(n) = opts.mesh_n_high {
+        builder.mesh_n_high(n);
+    }
+    if let Some(ttl) = opts.fanout_ttl {
+        builder.fanout_ttl(ttl);
+    }
+    // IHAVE/IWANTで取りこぼしを拾う範囲
+    if let Some(n) = opts.history_length {
+        builder.history_length(n);
+    }
+    if let Some(n) = opts.history_gossip {
+        builder.history_gossip(n);
+    }
+    if let Some(time) = opts.duplicate_cache_time {
+        builder.duplicate_cache_time(time);
+    }
+    let gossipsub_config = builder
         .build()
         .map_err(io::Error::other)?; // Temporary hack because `build` does not return a proper `std::error::Error`.
         //(Copilot提案) .map_err(|e| Box::<dyn Error>::from(e))?; // Map build error into boxed error.
 
     // build a gossipsub network behaviour
-    let gossipsub = gossipsub::Behaviour::new(
+    let gossipsub = gossipsub::Behaviour::new_with_subscription_filter(
         gossipsub::MessageAuthenticity::Signed(key.clone()),
         gossipsub_config,
+        SubscriptionFilter::new(opts.allow_topics.clone()),
     )?;
 
-    let mdns =
-        mdns::tokio::Behaviour::new(mdns::Config::default(), key.public().to_peer_id())?;
-    Ok(MyBehaviour { gossipsub, mdns })
+    // 止めたものは作らない(mDNSは作るとソケットを開く)
+    let mdns = if opts.enabled("mdns") {
+        Some(mdns::tokio::Behaviour::new(mdns::Config::default(), key.public().to_peer_id())?)
+    } else {
+        None
+    };
+    let upnp = upnp::tokio::Behaviour::default();
+    let identify = identify::Behaviour::new(identify::Config::new("/chat/1.0.0".to_string(), key.public()));
+    let autonat = autonat::Behaviour::new(key.public().to_peer_id(), autonat::Config::default());
+    let peer_id = key.public().to_peer_id();
+    let mut kad_config = kad::Config::new(kad::PROTOCOL_NAME);
+    if let Some(ttl) = opts.record_ttl {
+        kad_config.set_record_ttl(Some(ttl));
+    }
+    // 預かる前にnickの登録が重なっていないか確かめる(store_inbound)
+    kad_config.set_record_filtering(kad::StoreInserts::FilterBoth);
+    // bootstrapはbootstrap_tickで自分でやり、/kad stats で結果を見られるようにする
+    kad_config.set_periodic_bootstrap_interval(None);
+    let mut kad = kad::Behaviour::with_config(peer_id, kad::store::MemoryStore::new(peer_id), kad_config);
+    // request-responseのプロトコルもgossipsubと同じ上限にする
+    let max_message_size = opts.max_message_size() as u64;
+    // 外部アドレスがないとclientモードになりレコードを預からないので、LANでも使えるようserverにする
+    kad.set_mode(Some(kad::Mode::Server));
+    Ok(MyBehaviour {
+        gossipsub,
+        mdns: Toggle::from(mdns),
+        upnp: Toggle::from(opts.enabled("upnp").then_some(upnp)),
+        blocked: Default::default(),
+        relay_client,
+        identify: Toggle::from(opts.enabled("identify").then_some(identify)),
+        autonat: Toggle::from(opts.enabled("autonat").then_some(autonat)),
+        direct: direct::behaviour(max_message_size),
+        profile: profile::behaviour(max_message_size),
+        kad: Toggle::from(opts.enabled("kad").then_some(kad)),
+        blocks: blocks::behaviour(max_message_size),
+        px: px::behaviour(max_message_size),
+        route: routing::behaviour(max_message_size),
+        measure: measure::behaviour(max_message_size),
+    })
 }
@@ -0,0 +1,205 @@
+// gossipsubとMatrixのroomの橋渡し。`matrix` featureを付けてビルドしたときだけ使える。
+//  export MATRIX_ACCESS_TOKEN=...
+//  cargo run -p chat --features matrix -- --matrix-homeserver https://matrix.example.org --matrix-room '!abc:example.org'
+//
+// Matrixのclient-server APIを直接呼んでいる。
+//  受信 : /sync をlong pollingしてroomの m.room.message を取り出す
+//  送信 : /rooms/{roomId}/send/m.room.message/{txnId}
+// 行ったり来たりしないよう、Matrixから来たものには MATRIX_TAG を付け、それはMatrixに戻さない。
+// Matrix側で自分(ブリッジのユーザ)が送ったものも無視する。
+
+#[cfg(feature = "matrix")]
+pub use imp::Bridge;
+#[cfg(not(feature = "matrix"))]
+pub use stub::Bridge;
+
+#[cfg(feature = "matrix")]
+mod imp {
+    use std::{
+        error::Error,
+        sync::atomic::{AtomicU64, Ordering},
+        time::Duration,
+    };
+
+    use serde_json::{Value, json};
+    use tokio::sync::{Mutex, mpsc};
+
+    const MATRIX_TAG: &str = "[matrix] ";
+
+    pub struct Bridge {
+        http: reqwest::Client,
+        homeserver: String,
+        room: String,
+        token: String,
+        // 送信ごとに変えるトランザクションID
+        txn: AtomicU64,
+        incoming: Mutex<mpsc::Receiver<String>>,
+    }
+
+    impl Bridge {
+        pub async fn connect(homeserver: &str, room: String, token: String) -> Result<Self, Box<dyn Error>> {
+            let homeserver = homeserver.trim_end_matches('/').to_string();
+            let http = reqwest::Client::new();
+
+            let whoami: Value = http
+                .get(format!("{homeserver}/_matrix/client/v3/account/whoami"))
+                .bearer_auth(&token)
+                .send()
+                .await?
+                .error_for_status()?
+                .json()
+                .await?;
+            let user_id = whoami["user_id"].as_str().ok_or("whoami: no user_id")?.to_string();
+            println!("Matrix bridge as {user_id} in {room}");
+
+            let (tx, rx) = mpsc::channel(64);
+            tokio::spawn(sync_loop(
+                http.clone(),
+                homeserver.clone(),
+                room.clone(),
+                token.clone(),
+                user_id,
+                tx,
+            ));
+
+            Ok(Bridge {
+                http,
+                homeserver,
+                room,
+                token,
+                txn: AtomicU64::new(0),
+                incoming: Mutex::new(rx),
+            })
+        }
+
+        // Matrixから来たメッセージ。gossipsubにはこのままpublishすればよい。
+        pub async fn recv(&self) -> Option<String> {
+            self.incoming.lock().await.recv().await
+        }
+
+        // gossipsubで受け取ったメッセージをroomに送る。Matrixから来たものは戻さない。
+        // swarmのループを止めないよう別タスクで送る。
+        pub fn forward(&self, from: &str, text: &str) {
+            if text.starts_with(MATRIX_TAG) {
+                return;
+            }
+            let txn = self.txn.fetch_add(1, Ordering::Relaxed);
+            let url = format!(
+                "{}/_matrix/client/v3/rooms/{}/send/m.room.message/chat-{}-{txn}",
+                self.homeserver,
+                encode(&self.room),
+                std::process::id(),
+            );
+            let request = self
+                .http
+                .put(url)
+                .bearer_auth(&self.token)
+                .json(&json!({ "msgtype": "m.text", "body": format!("{from}: {text}") }));
+            tokio::spawn(async move {
+                if let Err(e) = request.send().await.and_then(|r| r.error_for_status()) {
+                    println!("Matrix send error: {e:?}");
+                }
+            });
+        }
+    }
+
+    async fn sync_loop(
+        http: reqwest::Client,
+        homeserver: String,
+        room: String,
+        token: String,
+        user_id: String,
+        tx: mpsc::Sender<String>,
+    ) {
+        // 最初の /sync は過去のメッセージなので流さず、続きの位置だけもらう
+        let mut since: Option<String> = None;
+        loop {
+            let mut query = vec![("timeout", "30000".to_string())];
+            if let Some(since) = &since {
+                query.push(("since", since.clone()));
+            }
+            let response = http
+                .get(format!("{homeserver}/_matrix/client/v3/sync"))
+                .bearer_auth(&token)
+                .query(&query)
+                .send()
+                .await
+                .and_then(|r| r.error_for_status());
+            let body: Value = match response {
+                Ok(r) => match r.json().await {
+                    Ok(body) => body,
+                    Err(e) => {
+                        println!("Matrix sync error: {e:?}");
+                        continue;
+                    }
+                },
+                Err(e) => {
+                    println!("Matrix sync error: {e:?}");
+                    tokio::time::sleep(Duration::from_secs(5)).await;
+                    continue;
+                }
+            };
+
+            let first = since.is_none();
+            since = body["next_batch"].as_str().map(String::from);
+            if first {
+                continue;
+            }
+
+            let events = body["rooms"]["join"][&room]["timeline"]["events"].as_array();
+            for event in events.into_iter().flatten() {
+                if event["type"] != "m.room.message" || event["sender"] == user_id.as_str() {
+                    continue;
+                }
+                let (Some(sender), Some(body)) =
+                    (event["sender"].as_str(), event["content"]["body"].as_str())
+                else {
+                    continue;
+                };
+                // @alice:example.org -> alice
+                let nick = sender
+                    .trim_start_matches('@')
+                    .split(':')
+                    .next()
+                    .unwrap_or(sender);
+                if tx.send(format!("{MATRIX_TAG}{nick}: {body}")).await.is_err() {
+                    return;
+                }
+            }
+        }
+    }
+
+    // room ID(!abc:example.org)をURLのパスに入れられるようにする
+    fn encode(s: &str) -> String {
+        s.bytes()
+            .map(|b| match b {
+                b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'_' | b'.' | b'~' => {
+                    (b as char).to_string()
+                }
+                _ => format!("%{b:02X}"),
+            })
+            .collect()
+    }
+}
+
+#[cfg(not(feature = "matrix"))]
+mod stub {
+    use std::{convert::Infallible, error::Error};
+
+    // featureなしでは作れない型
+    pub struct Bridge(Infallible);
+
+    impl Bridge {
+        pub async fn connect(_homeserver: &str, _room: String, _token: String) -> Result<Self, Box<dyn Error>> {
+            Err("--matrix-homeserver needs the `matrix` feature".into())
+        }
+
+        pub async fn recv(&self) -> Option<String> {
+            match self.0 {}
+        }
+
+        pub fn forward(&self, _from: &str, _text: &str) {
+            match self.0 {}
+        }
+    }
+}
@@ -0,0 +1,181 @@
+// --measure のときは送る本文の前に送った時刻を付け、受け取った側はかかった時間を送り手に知らせる。
+//  [t=<UNIX時刻(ミリ秒)>] 本文
+// 送り手と受け手の時計の差がそのまま誤差になるので、同じマシンかNTPで合わせたマシンで測る。
+
+use std::{
+    collections::{HashMap, VecDeque},
+    fmt,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use libp2p::{
+    PeerId, StreamProtocol,
+    request_response::{self, ProtocolSupport},
+};
+use serde::{Deserialize, Serialize};
+
+// 受け取った側から送り手に、かかった時間を知らせる
+pub const PROTOCOL: StreamProtocol = StreamProtocol::new("/chat/measure/1");
+
+// 覚えておく測定の数。古いものから捨てる。
+const MAX_SAMPLES: usize = 10000;
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Report {
+    pub message_id: String,
+    pub latency_ms: u64,
+    // 書いた本人以外から受け取った(他のpeerが転送した)ならtrue
+    pub relayed: bool,
+}
+
+pub type Behaviour = request_response::cbor::Behaviour<Report, ()>;
+
+// 相手から来るものは信用できないので、--max-message-size を超えたらデコードする前に捨てる
+pub fn behaviour(max_message_size: u64) -> Behaviour {
+    let codec = request_response::cbor::codec::Codec::<Report, ()>::default()
+        .set_request_size_maximum(max_message_size)
+        .set_response_size_maximum(max_message_size);
+    Behaviour::with_codec(codec, [(PROTOCOL, ProtocolSupport::Full)], request_response::Config::default())
+}
+
+pub fn stamp(text: &str) -> String {
+    let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default();
+    format!("[t={}] {text}", now.as_millis())
+}
+
+// 時刻が付いていれば、送ってからかかった時間と本文
+pub fn unstamp(text: &str) -> Option<(Duration, &str)> {
+    let (millis, text) = text.strip_prefix("[t=")?.split_once("] ")?;
+    // 大きすぎる値で足し算があふれないように
+    let sent = UNIX_EPOCH.checked_add(Duration::from_millis(millis.parse().ok()?))?;
+    // 相手の時計が進んでいると負になるので0にする
+    Some((SystemTime::now().duration_since(sent).unwrap_or_default(), text))
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Sample {
+    peer_id: PeerId,
+    latency: Duration,
+    relayed: bool,
+}
+
+// 受け取ったpeerから届いた測定。/measure で表示する。
+#[derive(Debug, Default)]
+pub struct Stats {
+    samples: VecDeque<Sample>,
+}
+
+impl Stats {
+    pub fn push(&mut self, peer_id: PeerId, report: &Report) {
+        if self.samples.len() == MAX_SAMPLES {
+            self.samples.pop_front();
+        }
+        self.samples.push_back(Sample {
+            peer_id,
+            latency: Duration::from_millis(report.latency_ms),
+            relayed: report.relayed,
+        });
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.samples.is_empty()
+    }
+
+    // すべて、書いた本人から直接(1ホップ)、他のpeerの転送(2ホップ以上)
+    pub fn by_hops(&self) -> [(&'static str, Option<Summary>); 3] {
+        [
+            ("all", Summary::of(self.samples.iter())),
+            ("direct", Summary::of(self.samples.iter().filter(|s| !s.relayed))),
+            ("relayed", Summary::of(self.samples.iter().filter(|s| s.relayed))),
+        ]
+    }
+
+    pub fn by_peer(&self) -> Vec<(PeerId, Summary)> {
+        let mut peers: HashMap<PeerId, Vec<&Sample>> = HashMap::new();
+        for sample in &self.samples {
+            peers.entry(sample.peer_id).or_default().push(sample);
+        }
+        let mut summaries: Vec<_> = peers
+            .into_iter()
+            .filter_map(|(peer_id, samples)| Some((peer_id, Summary::of(samples.into_iter())?)))
+            .collect();
+        summaries.sort_by_key(|(_, summary)| summary.p50);
+        summaries
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct Summary {
+    pub count: usize,
+    pub min: Duration,
+    pub p50: Duration,
+    pub p95: Duration,
+    pub max: Duration,
+}
+
+impl Summary {
+    fn of<'a>(samples: impl Iterator<Item = &'a Sample>) -> Option<Self> {
+        let mut latencies: Vec<Duration> = samples.map(|s| s.latency).collect();
+        latencies.sort();
+        let (min, max) = (*latencies.first()?, *latencies.last()?);
+        let percentile = |p: usize| latencies[(latencies.len() - 1) * p / 100];
+        Some(Summary {
+            count: latencies.len(),
+            min,
+            p50: percentile(50),
+            p95: percentile(95),
+            max,
+        })
+    }
+}
+
+impl fmt::Display for Summary {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} samples, min {}ms, p50 {}ms, p95 {}ms, max {}ms",
+            self.count,
+            self.min.as_millis(),
+            self.p50.as_millis(),
+            self.p95.as_millis(),
+            self.max.as_millis()
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn unstamp_round_trip() {
+        let (latency, text) = unstamp(&stamp("hello")).unwrap();
+        assert_eq!(text, "hello");
+        assert!(latency < Duration::from_secs(5));
+    }
+
+    #[test]
+    fn unstamp_rejects_malformed() {
+        assert_eq!(unstamp("hello"), None);
+        assert_eq!(unstamp("[t=123"), None);
+        assert_eq!(unstamp("[t=123]hello"), None);
+        assert_eq!(unstamp("[t=abc] hello"), None);
+        assert_eq!(unstamp("[t=-1] hello"), None);
+        assert_eq!(unstamp("[t=99999999999999999999999] hello"), None);
+    }
+
+    #[test]
+    fn unstamp_future_and_overflow() {
+        // 相手の時計が進んでいるときは0
+        let tomorrow = SystemTime::now().duration_since(UNIX_EPOCH).unwrap() + Duration::from_secs(86400);
+        let stamped = format!("[t={}] x", tomorrow.as_millis());
+        assert_eq!(unstamp(&stamped), Some((Duration::ZERO, "x")));
+        // SystemTimeに足せない値でもpanicしない
+        let _ = unstamp(&format!("[t={}] x", u64::MAX));
+    }
+
+    #[test]
+    fn summary_of_empty_is_none() {
+        assert!(Summary::of(std::iter::empty::<&Sample>()).is_none());
+    }
+}
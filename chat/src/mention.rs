@@ -0,0 +1,76 @@
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use libp2p::PeerId;
+
+// 自分宛て(@nick)のメッセージ。/mentions で一覧を表示する。
+#[derive(Debug, Clone)]
+pub struct Mention {
+    pub from: PeerId,
+    pub text: String,
+    pub at: SystemTime,
+}
+
+impl Mention {
+    pub fn new(from: PeerId, text: String) -> Self {
+        Mention {
+            from,
+            text,
+            at: SystemTime::now(),
+        }
+    }
+}
+
+impl std::fmt::Display for Mention {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let secs = self.at.duration_since(UNIX_EPOCH).unwrap_or_default().as_secs() % 86400;
+        write!(
+            f,
+            "{:02}:{:02}:{:02} UTC {}: {}",
+            secs / 3600,
+            secs / 60 % 60,
+            secs % 60,
+            self.from,
+            self.text
+        )
+    }
+}
+
+// textの中で @nick が出てくる位置(バイト単位の範囲)。
+// 送信側で大文字にしているので大文字小文字は区別しない。@nickname のような前方一致は数えない。
+fn find(text: &str, nick: &str) -> Vec<(usize, usize)> {
+    let target = format!("@{}", nick.to_uppercase());
+    let upper = text.to_uppercase();
+    // to_uppercase()で長さが変わる文字があると位置がずれるので、そのときは探さない
+    if upper.len() != text.len() {
+        return Vec::new();
+    }
+    upper
+        .match_indices(&target)
+        .map(|(start, m)| (start, start + m.len()))
+        .filter(|&(_, end)| {
+            !upper[end..]
+                .chars()
+                .next()
+                .is_some_and(|c| c.is_alphanumeric() || c == '_')
+        })
+        .collect()
+}
+
+pub fn is_mentioned(text: &str, nick: &str) -> bool {
+    !find(text, nick).is_empty()
+}
+
+// @nick の部分を太字の黄色にする
+pub fn highlight(text: &str, nick: &str) -> String {
+    let mut out = String::with_capacity(text.len());
+    let mut last = 0;
+    for (start, end) in find(text, nick) {
+        out.push_str(&text[last..start]);
+        out.push_str("\x1b[1;33m");
+        out.push_str(&text[start..end]);
+        out.push_str("\x1b[0m");
+        last = end;
+    }
+    out.push_str(&text[last..]);
+    out
+}
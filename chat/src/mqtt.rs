@@ -0,0 +1,118 @@
+// gossipsubとMQTTの橋渡し。`mqtt` featureを付けてビルドしたときだけ使える。
+//  cargo run -p chat --features mqtt -- --mqtt localhost:1883
+//
+// ブリッジが複数あってもメッセージが行ったり来たりしないよう、転送したものには印を付ける。
+//  MQTT -> gossipsub : 先頭に MQTT_TAG を付ける。ブリッジはこれをMQTTに戻さない。
+//  gossipsub -> MQTT : 先頭に P2P_TAG を付ける。ブリッジはこれをgossipsubに戻さない。
+
+#[cfg(feature = "mqtt")]
+pub use imp::Bridge;
+#[cfg(not(feature = "mqtt"))]
+pub use stub::Bridge;
+
+#[cfg(feature = "mqtt")]
+mod imp {
+    use std::{error::Error, time::Duration};
+
+    use rumqttc::{AsyncClient, Event, MqttOptions, Packet, QoS};
+    use tokio::sync::{Mutex, mpsc};
+
+    const MQTT_TAG: &str = "[mqtt] ";
+    const P2P_TAG: &str = "[p2p] ";
+
+    pub struct Bridge {
+        client: AsyncClient,
+        topic: String,
+        incoming: Mutex<mpsc::Receiver<String>>,
+    }
+
+    impl Bridge {
+        // brokerは host:port
+        pub fn connect(broker: &str, topic: String, client_id: String) -> Result<Self, Box<dyn Error>> {
+            let (host, port) = broker.rsplit_once(':').unwrap_or((broker, "1883"));
+            let mut options = MqttOptions::new(client_id, host, port.parse()?);
+            options.set_keep_alive(Duration::from_secs(30));
+            let (client, mut eventloop) = AsyncClient::new(options, 64);
+
+            // MQTTの受信は別タスク。接続が切れてもpoll()し続ければ再接続される。
+            let (tx, rx) = mpsc::channel(64);
+            let (sub_client, sub_topic) = (client.clone(), topic.clone());
+            tokio::spawn(async move {
+                loop {
+                    match eventloop.poll().await {
+                        // 再接続のたびにsubscribeし直す
+                        Ok(Event::Incoming(Packet::ConnAck(_))) => {
+                            println!("MQTT connected, subscribing {sub_topic}");
+                            if let Err(e) = sub_client.try_subscribe(&sub_topic, QoS::AtLeastOnce) {
+                                println!("MQTT subscribe error: {e:?}");
+                            }
+                        }
+                        Ok(Event::Incoming(Packet::Publish(publish))) => {
+                            let text = String::from_utf8_lossy(&publish.payload).into_owned();
+                            if text.starts_with(P2P_TAG) {
+                                continue;
+                            }
+                            if tx.send(text).await.is_err() {
+                                break;
+                            }
+                        }
+                        Ok(_) => {}
+                        Err(e) => {
+                            println!("MQTT connection error: {e:?}");
+                            tokio::time::sleep(Duration::from_secs(1)).await;
+                        }
+                    }
+                }
+            });
+
+            Ok(Bridge {
+                client,
+                topic,
+                incoming: Mutex::new(rx),
+            })
+        }
+
+        // MQTTから来たメッセージ。gossipsubにはこのままpublishすればよい。
+        pub async fn recv(&self) -> Option<String> {
+            let text = self.incoming.lock().await.recv().await?;
+            Some(format!("{MQTT_TAG}{text}"))
+        }
+
+        // gossipsubで受け取ったメッセージをMQTTに流す。MQTTから来たものは戻さない。
+        pub fn forward(&self, text: &str) {
+            if text.starts_with(MQTT_TAG) {
+                return;
+            }
+            let payload = format!("{P2P_TAG}{text}");
+            // swarmのループを止めないよう待たずに送る
+            if let Err(e) = self
+                .client
+                .try_publish(&self.topic, QoS::AtLeastOnce, false, payload)
+            {
+                println!("MQTT publish error: {e:?}");
+            }
+        }
+    }
+}
+
+#[cfg(not(feature = "mqtt"))]
+mod stub {
+    use std::{convert::Infallible, error::Error};
+
+    // featureなしでは作れない型
+    pub struct Bridge(Infallible);
+
+    impl Bridge {
+        pub fn connect(_broker: &str, _topic: String, _client_id: String) -> Result<Self, Box<dyn Error>> {
+            Err("--mqtt needs the `mqtt` feature".into())
+        }
+
+        pub async fn recv(&self) -> Option<String> {
+            match self.0 {}
+        }
+
+        pub fn forward(&self, _text: &str) {
+            match self.0 {}
+        }
+    }
+}
@@ -0,0 +1,72 @@
+use libp2p::{
+    PeerId,
+    core::SignedEnvelope,
+    identity::{Keypair, SigningError},
+    kad,
+};
+
+// nickの登録をDHTに置くときのkeyの頭
+const PREFIX: &str = "nick/";
+// 署名の用途。他の署名と取り違えられないようにする。
+const DOMAIN: &str = "libp2p-tutorial-chat-nickname";
+const PAYLOAD_TYPE: &[u8] = b"/chat/nickname/1";
+
+// "nick/<小文字のnick>"。大文字小文字だけ違うnickは同じものとして扱う。
+pub fn key(nick: &str) -> kad::RecordKey {
+    kad::RecordKey::new(&format!("{PREFIX}{}", nick.to_lowercase()))
+}
+
+// nickの登録のkeyならnickを返す
+pub fn nick_of(key: &kad::RecordKey) -> Option<String> {
+    std::str::from_utf8(key.as_ref()).ok()?.strip_prefix(PREFIX).map(String::from)
+}
+
+// nickに署名したSignedEnvelope。これをvalueとして置く。
+pub fn sign(keypair: &Keypair, nick: &str) -> Result<Vec<u8>, SigningError> {
+    let envelope = SignedEnvelope::new(
+        keypair,
+        DOMAIN.to_string(),
+        PAYLOAD_TYPE.to_vec(),
+        nick.to_lowercase().into_bytes(),
+    )?;
+    Ok(envelope.into_protobuf_encoding())
+}
+
+// 署名を確かめて持ち主を返す。別のnickへの署名を使い回したものもNone。
+pub fn owner(nick: &str, value: &[u8]) -> Option<PeerId> {
+    let envelope = SignedEnvelope::from_protobuf_encoding(value).ok()?;
+    let (payload, key) = envelope.payload_and_signing_key(DOMAIN.to_string(), PAYLOAD_TYPE).ok()?;
+    (payload == nick.to_lowercase().as_bytes()).then(|| key.to_peer_id())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn owner_of_signed_nick() {
+        let keypair = Keypair::generate_ed25519();
+        let value = sign(&keypair, "Alice").unwrap();
+        assert_eq!(owner("alice", &value), Some(keypair.public().to_peer_id()));
+        // 別のnickへの署名は使い回せない
+        assert_eq!(owner("bob", &value), None);
+    }
+
+    #[test]
+    fn owner_rejects_malformed() {
+        let value = sign(&Keypair::generate_ed25519(), "alice").unwrap();
+        assert_eq!(owner("alice", b""), None);
+        assert_eq!(owner("alice", &value[..value.len() / 2]), None);
+        assert_eq!(owner("alice", &[0xff; 32]), None);
+        let mut flipped = value.clone();
+        *flipped.last_mut().unwrap() ^= 1;
+        assert_eq!(owner("alice", &flipped), None);
+    }
+
+    #[test]
+    fn nick_of_key() {
+        assert_eq!(nick_of(&key("Alice")).as_deref(), Some("alice"));
+        assert_eq!(nick_of(&kad::RecordKey::new(&"other/alice")), None);
+        assert_eq!(nick_of(&kad::RecordKey::new(&[0xffu8, 0xfe])), None);
+    }
+}
@@ -0,0 +1,21 @@
+// デスクトップ通知。`notify` featureを付けてビルドしたときだけ使える。
+//  cargo run -p chat --features notify -- --notify
+
+#[cfg(feature = "notify")]
+pub const AVAILABLE: bool = true;
+#[cfg(not(feature = "notify"))]
+pub const AVAILABLE: bool = false;
+
+#[cfg(feature = "notify")]
+pub fn show(summary: &str, body: &str) {
+    if let Err(e) = notify_rust::Notification::new()
+        .summary(summary)
+        .body(body)
+        .show()
+    {
+        tracing::warn!("desktop notification failed: {e}");
+    }
+}
+
+#[cfg(not(feature = "notify"))]
+pub fn show(_summary: &str, _body: &str) {}
@@ -0,0 +1,304 @@
+use std::{error::Error, num::{NonZeroU8, NonZeroUsize}, path::PathBuf, str::FromStr, time::Duration};
+
+use libp2p::{Multiaddr, PeerId, kad::Quorum, multiaddr::Protocol};
+use regex::Regex;
+use tracing_appender::rolling::Rotation;
+
+use crate::{identity::KeyType, kad_status, notify};
+
+// コマンドライン引数
+//  chat [quic] [--nick <name>] [--avatar-hash <hash>] [--notify] [--measure] [--ordered] [--log-format text|json] [--log-file <path>] [--log-rotation minutely|hourly|daily|never]
+//       [--key-type ed25519|secp256k1|ecdsa] [--identity <path>] [--import-key <path>]
+//       [--export-key <path> | --export-key-base64]
+//       [--max-message-size <bytes>] [--no-flood-publish] [--mesh-n <n>] [--mesh-n-low <n>] [--mesh-n-high <n>] [--fanout-ttl <secs>]
+//       [--history-length <n>] [--history-gossip <n>] [--duplicate-cache-time <secs>]
+//       [--disable mdns|upnp|identify|autonat|kad]...
+//       [--kad-quorum one|majority|all|<n>] [--record-ttl <secs>] [--kad-bootstrap-interval <secs>]
+//       [--allow-topic <regex>]... [--filter-max-length <n>] [--filter-words <path>] [--filter-deny <regex>]...
+//       [--mqtt <host:port>] [--mqtt-topic <topic>] [--matrix-homeserver <url> --matrix-room <room id>]
+//       [--peer-store <path>] [--contacts <path>] [--aliases <path>] [--blocks <dir>] [--schedule <path>] [--irc <addr>] [--webhook <url> [--webhook-match <regex>]]
+//       [--on-message <command> [--on-message-match <regex>]] [--on-connect <command>] [--on-nat-status <command>]
+//       [--blocklist <path>] [--idle-timeout <secs> | --keep-alive] [--dial-concurrency <n>] [--connect-timeout <secs>] [--handshake-timeout <secs>] [--tcp-nodelay true|false] [--tcp-backlog <n>] [--tcp-ttl <n>] [--notify-handler-buffer <n>] [--connection-event-buffer <n>] [--external-address <multiaddr>]... [--peer <multiaddr>/p2p/<peer id>]... [--relay <multiaddr>/p2p/<peer id>] [--record <path>] [--replay <path>]
+#[derive(Debug, Default)]
+pub struct Options {
+    pub use_quic: bool,
+    // 自分の名前。@name を含むメッセージを強調表示する。
+    pub nick: Option<String>,
+    // プロフィールで教えるアイコン画像のハッシュ
+    pub avatar_hash: Option<String>,
+    // メッセージを受信したらデスクトップ通知を出す(notify feature)
+    pub notify: bool,
+    // 送った時刻を付けて送り、伝わるまでにかかった時間を集める
+    pub measure: bool,
+    // topicごとの番号を付けて送る。受け取った側は番号で順番を揃える。付けていないpeerには番号がそのまま見える。
+    pub ordered: bool,
+    pub log_format: LogFormat,
+    // コンソールとは別にログをファイルにも書き出す
+    pub log_file: Option<PathBuf>,
+    // ログファイルを切り替える間隔。指定がなければ日ごと。
+    pub log_rotation: Option<Rotation>,
+    // 新しく鍵を作るときの種類
+    pub key_type: KeyType,
+    // 鍵を保存するファイル。指定がなければ起動のたびに新しいPeerIdになる。
+    pub identity: Option<PathBuf>,
+    // 他の実装で作った鍵を使う。--identity があればそこに保存する。
+    pub import_key: Option<PathBuf>,
+    // 鍵をprotobuf形式で書き出して終了する
+    pub export_key: Option<PathBuf>,
+    // 鍵をbase64で表示して終了する
+    pub export_key_base64: bool,
+    // gossipsubで送受信する最大バイト数。超えたものは送らず、受信もデコード前に捨てる。
+    pub max_message_size: Option<usize>,
+    // gossipsubの調整。指定がなければlibp2pのデフォルト。
+    // falseにすると自分のメッセージをmeshのpeerにだけ送る(帯域は減るが届きにくくなる)
+    pub flood_publish: Option<bool>,
+    // meshに入れるpeerの数(目標・下限・上限)
+    pub mesh_n: Option<usize>,
+    pub mesh_n_low: Option<usize>,
+    pub mesh_n_high: Option<usize>,
+    // subscribeしていないtopicに送るときのpeer(fanout)を覚えておく時間
+    pub fanout_ttl: Option<Duration>,
+    // メッセージを覚えておくheartbeatの回数と、そのうちIHAVEで知らせる回数
+    pub history_length: Option<usize>,
+    pub history_gossip: Option<usize>,
+    // 受け取ったMessageIdを重複として覚えておく時間
+    pub duplicate_cache_time: Option<Duration>,
+    // /put で何台のpeerに保存できたら成功とするか
+    pub kad_quorum: Option<Quorum>,
+    // DHTに置いたレコードの有効期間。指定がなければlibp2pのデフォルト(36時間)。
+    pub record_ttl: Option<Duration>,
+    // DHTのbootstrapをやり直す間隔
+    pub kad_bootstrap_interval: Option<Duration>,
+    // 使わないbehaviour(TOGGLESのどれか)
+    pub disabled: Vec<String>,
+    // subscribeを受け付けるtopic(正規表現)。指定がなければすべて。
+    pub allow_topics: Vec<Regex>,
+    // 受信メッセージのフィルタ。文字数の上限。
+    pub filter_max_length: Option<usize>,
+    // 伏せ字にする単語の一覧(1行1語)
+    pub filter_words: Option<PathBuf>,
+    // マッチしたら拒否する正規表現
+    pub filter_deny: Vec<String>,
+    // gossipsubのメッセージを流すMQTTブローカ(mqtt feature)
+    pub mqtt: Option<String>,
+    // MQTT側のtopic。指定がなければ libp2p/<gossipsubのtopic>
+    pub mqtt_topic: Option<String>,
+    // Matrixのhomeserverとroom(matrix feature)。アクセストークンは環境変数MATRIX_ACCESS_TOKENで渡す。
+    pub matrix_homeserver: Option<String>,
+    pub matrix_room: Option<String>,
+    // 見たことのあるpeerと最後に見た時刻を保存するファイル
+    pub peer_store: Option<PathBuf>,
+    // nickとPeerIdの組と /verify したpeerを保存するファイル
+    pub contacts: Option<PathBuf>,
+    // /alias で付けた名前を保存するファイル
+    pub aliases: Option<PathBuf>,
+    // /add したブロックを保存するディレクトリ。起動時に読み込んで提供し直す。
+    pub blocks: Option<PathBuf>,
+    // /send-at で予約したメッセージを保存するファイル
+    pub schedule: Option<PathBuf>,
+    // IRCクライアントを受け付けるアドレス(127.0.0.1:6667など)
+    pub irc: Option<String>,
+    // 受信したらPOSTするURL(webhook feature)。署名用の鍵は環境変数WEBHOOK_SECRETで渡す。
+    pub webhook: Option<String>,
+    // この正規表現にマッチしたメッセージだけPOSTする
+    pub webhook_match: Option<String>,
+    // イベントが起きたら実行するコマンド。イベントのJSONを標準入力で渡す。
+    pub on_message: Option<String>,
+    // この正規表現にマッチしたメッセージのときだけ実行する
+    pub on_message_match: Option<String>,
+    pub on_connect: Option<String>,
+    pub on_nat_status: Option<String>,
+    // /block したpeerを保存するファイル
+    pub blocklist: Option<PathBuf>,
+    // 通信がない接続を閉じるまでの時間。指定がなければlibp2pのデフォルト。
+    pub idle_timeout: Option<Duration>,
+    // 1つのpeerのアドレスに同時にダイヤルする数。指定がなければlibp2pのデフォルト(8)。
+    pub dial_concurrency: Option<NonZeroU8>,
+    // TCPの接続ができるまでの上限と、noise・yamux(QUICならQUIC)のハンドシェイクが終わるまでの上限
+    pub connect_timeout: Option<Duration>,
+    pub handshake_timeout: Option<Duration>,
+    // TCPのソケットの設定。指定がなければlibp2pのデフォルト(nodelayは有効、backlogは1024、TTLはOSのデフォルト)。
+    pub tcp_nodelay: Option<bool>,
+    pub tcp_backlog: Option<u32>,
+    pub tcp_ttl: Option<u32>,
+    // behaviourから各接続のハンドラへ送るイベントと、接続からswarmへ送るイベントを溜めておける数。
+    // いっぱいになると送る側が待つ。指定がなければlibp2pのデフォルト(32と7)。
+    pub notify_handler_buffer: Option<NonZeroUsize>,
+    pub connection_event_buffer: Option<usize>,
+    // ポートフォワードなどで外から届くアドレス。複数指定できる。
+    pub external_addresses: Vec<Multiaddr>,
+    // 常につないでおくpeer。gossipsubのexplicit peerにし、切れたらつなぎ直す。
+    pub permanent_peers: Vec<(PeerId, Multiaddr)>,
+    // NATの内側にいるとき、このrelay経由でも待ち受ける
+    pub relay: Option<Multiaddr>,
+    // 受信したSwarmEventをファイルに記録する
+    pub record: Option<PathBuf>,
+    // 記録したファイルを読み込んで再生する(ネットワークには接続しない)
+    pub replay: Option<PathBuf>,
+}
+
+impl Options {
+    pub fn parse() -> Result<Self, Box<dyn Error>> {
+        let mut opts = Options::default();
+        let mut args = std::env::args().skip(1);
+        while let Some(arg) = args.next() {
+            match arg.as_str() {
+                "quic" => opts.use_quic = true,
+                "--nick" => opts.nick = Some(value(&mut args, &arg)?),
+                "--avatar-hash" => opts.avatar_hash = Some(value(&mut args, &arg)?),
+                "--measure" => opts.measure = true,
+                "--ordered" => opts.ordered = true,
+                "--notify" if notify::AVAILABLE => opts.notify = true,
+                "--notify" => return Err("--notify needs the `notify` feature".into()),
+                "--log-format" => opts.log_format = value(&mut args, &arg)?.parse()?,
+                "--log-file" => opts.log_file = Some(value(&mut args, &arg)?.into()),
+                "--log-rotation" => opts.log_rotation = Some(rotation(&value(&mut args, &arg)?)?),
+                "--key-type" => opts.key_type = value(&mut args, &arg)?.parse()?,
+                "--identity" => opts.identity = Some(value(&mut args, &arg)?.into()),
+                "--import-key" => opts.import_key = Some(value(&mut args, &arg)?.into()),
+                "--export-key" => opts.export_key = Some(value(&mut args, &arg)?.into()),
+                "--export-key-base64" => opts.export_key_base64 = true,
+                "--max-message-size" => opts.max_message_size = Some(value(&mut args, &arg)?.parse()?),
+                "--no-flood-publish" => opts.flood_publish = Some(false),
+                "--mesh-n" => opts.mesh_n = Some(value(&mut args, &arg)?.parse()?),
+                "--mesh-n-low" => opts.mesh_n_low = Some(value(&mut args, &arg)?.parse()?),
+                "--mesh-n-high" => opts.mesh_n_high = Some(value(&mut args, &arg)?.parse()?),
+                "--fanout-ttl" => opts.fanout_ttl = Some(seconds(&value(&mut args, &arg)?)?),
+                "--history-length" => opts.history_length = Some(value(&mut args, &arg)?.parse()?),
+                "--history-gossip" => opts.history_gossip = Some(value(&mut args, &arg)?.parse()?),
+                "--duplicate-cache-time" => opts.duplicate_cache_time = Some(seconds(&value(&mut args, &arg)?)?),
+                "--disable" => {
+                    let name = value(&mut args, &arg)?;
+                    if !TOGGLES.contains(&name.as_str()) {
+                        return Err(format!("--disable takes one of {}: {name}", TOGGLES.join(", ")).into());
+                    }
+                    opts.disabled.push(name);
+                }
+                "--kad-quorum" => opts.kad_quorum = Some(quorum(&value(&mut args, &arg)?)?),
+                "--record-ttl" => opts.record_ttl = Some(seconds(&value(&mut args, &arg)?)?),
+                "--kad-bootstrap-interval" => opts.kad_bootstrap_interval = Some(seconds(&value(&mut args, &arg)?)?),
+                "--allow-topic" => opts.allow_topics.push(Regex::new(&value(&mut args, &arg)?)?),
+                "--filter-max-length" => opts.filter_max_length = Some(value(&mut args, &arg)?.parse()?),
+                "--filter-words" => opts.filter_words = Some(value(&mut args, &arg)?.into()),
+                "--filter-deny" => opts.filter_deny.push(value(&mut args, &arg)?),
+                "--mqtt" => opts.mqtt = Some(value(&mut args, &arg)?),
+                "--mqtt-topic" => opts.mqtt_topic = Some(value(&mut args, &arg)?),
+                "--matrix-homeserver" => opts.matrix_homeserver = Some(value(&mut args, &arg)?),
+                "--matrix-room" => opts.matrix_room = Some(value(&mut args, &arg)?),
+                "--peer-store" => opts.peer_store = Some(value(&mut args, &arg)?.into()),
+                "--contacts" => opts.contacts = Some(value(&mut args, &arg)?.into()),
+                "--aliases" => opts.aliases = Some(value(&mut args, &arg)?.into()),
+                "--blocks" => opts.blocks = Some(value(&mut args, &arg)?.into()),
+                "--schedule" => opts.schedule = Some(value(&mut args, &arg)?.into()),
+                "--irc" => opts.irc = Some(value(&mut args, &arg)?),
+                "--webhook" => opts.webhook = Some(value(&mut args, &arg)?),
+                "--webhook-match" => opts.webhook_match = Some(value(&mut args, &arg)?),
+                "--on-message" => opts.on_message = Some(value(&mut args, &arg)?),
+                "--on-message-match" => opts.on_message_match = Some(value(&mut args, &arg)?),
+                "--on-connect" => opts.on_connect = Some(value(&mut args, &arg)?),
+                "--on-nat-status" => opts.on_nat_status = Some(value(&mut args, &arg)?),
+                "--blocklist" => opts.blocklist = Some(value(&mut args, &arg)?.into()),
+                "--dial-concurrency" => opts.dial_concurrency = Some(value(&mut args, &arg)?.parse()?),
+                "--connect-timeout" => opts.connect_timeout = Some(seconds(&value(&mut args, &arg)?)?),
+                "--handshake-timeout" => opts.handshake_timeout = Some(seconds(&value(&mut args, &arg)?)?),
+                "--tcp-nodelay" => opts.tcp_nodelay = Some(value(&mut args, &arg)?.parse()?),
+                "--tcp-backlog" => opts.tcp_backlog = Some(value(&mut args, &arg)?.parse()?),
+                "--tcp-ttl" => opts.tcp_ttl = Some(value(&mut args, &arg)?.parse()?),
+                "--notify-handler-buffer" => opts.notify_handler_buffer = Some(value(&mut args, &arg)?.parse()?),
+                "--connection-event-buffer" => opts.connection_event_buffer = Some(value(&mut args, &arg)?.parse()?),
+                "--idle-timeout" => opts.idle_timeout = Some(seconds(&value(&mut args, &arg)?)?),
+                // 長時間チャットするときは接続を閉じないようにする
+                "--keep-alive" => opts.idle_timeout = Some(Duration::from_secs(u64::MAX)),
+                "--external-address" => opts.external_addresses.push(value(&mut args, &arg)?.parse()?),
+                "--peer" => opts.permanent_peers.push(peer_address(&value(&mut args, &arg)?, &arg)?),
+                "--relay" => opts.relay = Some(peer_address(&value(&mut args, &arg)?, &arg)?.1),
+                "--record" => opts.record = Some(value(&mut args, &arg)?.into()),
+                "--replay" => opts.replay = Some(value(&mut args, &arg)?.into()),
+                _ => return Err(format!("unknown argument: {arg}").into()),
+            }
+        }
+        Ok(opts)
+    }
+
+    pub fn is_permanent(&self, peer_id: &PeerId) -> bool {
+        self.permanent_peers.iter().any(|(p, _)| p == peer_id)
+    }
+
+    // 指定がなければgossipsubのデフォルト(64KiB)
+    pub fn max_message_size(&self) -> usize {
+        self.max_message_size.unwrap_or(DEFAULT_MAX_MESSAGE_SIZE)
+    }
+
+    pub fn kad_bootstrap_interval(&self) -> Duration {
+        self.kad_bootstrap_interval.unwrap_or(kad_status::DEFAULT_BOOTSTRAP_INTERVAL)
+    }
+
+    // --disable で止めていなければtrue
+    pub fn enabled(&self, behaviour: &str) -> bool {
+        !self.disabled.iter().any(|d| d == behaviour)
+    }
+}
+
+pub const DEFAULT_MAX_MESSAGE_SIZE: usize = 65536;
+
+// --disable で止められるbehaviour。gossipsubとrequest-responseはチャットそのものなので止められない。
+// relayのclientは --relay を付けなければ何もしない。
+pub const TOGGLES: [&str; 5] = ["mdns", "upnp", "identify", "autonat", "kad"];
+
+// ログの出力形式
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum LogFormat {
+    // 人が読む形式(tracing-subscriberのデフォルト)
+    #[default]
+    Text,
+    // 1行1JSON。Loki/Elasticなどにそのまま入れられる。
+    Json,
+}
+
+impl FromStr for LogFormat {
+    type Err = Box<dyn Error>;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "text" => Ok(LogFormat::Text),
+            "json" => Ok(LogFormat::Json),
+            _ => Err(format!("unknown log format: {s}").into()),
+        }
+    }
+}
+
+fn seconds(s: &str) -> Result<Duration, Box<dyn Error>> {
+    Ok(Duration::from_secs(s.parse()?))
+}
+
+// explicit peerやrelayにはPeerIdが要るので /p2p/<peer id> で終わるアドレスにしてもらう
+fn peer_address(s: &str, name: &str) -> Result<(PeerId, Multiaddr), Box<dyn Error>> {
+    let addr: Multiaddr = s.parse()?;
+    match addr.iter().last() {
+        Some(Protocol::P2p(peer_id)) => Ok((peer_id, addr)),
+        _ => Err(format!("{name} needs /p2p/<peer id> at the end: {s}").into()),
+    }
+}
+
+fn quorum(s: &str) -> Result<Quorum, Box<dyn Error>> {
+    match s {
+        "one" => Ok(Quorum::One),
+        "majority" => Ok(Quorum::Majority),
+        "all" => Ok(Quorum::All),
+        n => Ok(Quorum::N(n.parse()?)),
+    }
+}
+
+fn rotation(s: &str) -> Result<Rotation, Box<dyn Error>> {
+    match s {
+        "minutely" => Ok(Rotation::MINUTELY),
+        "hourly" => Ok(Rotation::HOURLY),
+        "daily" => Ok(Rotation::DAILY),
+        "never" => Ok(Rotation::NEVER),
+        _ => Err(format!("unknown log rotation: {s}").into()),
+    }
+}
+
+fn value(args: &mut impl Iterator<Item = String>, name: &str) -> Result<String, Box<dyn Error>> {
+    args.next().ok_or_else(|| format!("{name} needs a value").into())
+}
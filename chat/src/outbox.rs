@@ -0,0 +1,48 @@
+use std::collections::VecDeque;
+
+// publishを待っている行の上限。いっぱいの間は標準入力を読まないので、入力側が待たされる。
+pub const CAPACITY: usize = 1000;
+// 1回に送る数。多すぎるとその間swarmのイベントが処理されない。
+pub const BATCH: usize = 100;
+
+// 標準入力から来てまだpublishしていない行
+#[derive(Debug, Default)]
+pub struct Outbox {
+    queue: VecDeque<String>,
+    // gossipsubの送信キューがいっぱいで止まっている。警告は止まったときに1回だけ出す。
+    pub stalled: bool,
+    // まだ送る相手が誰もいない。topicをsubscribeするpeerが来るまで送らずに持っておく。
+    pub held: bool,
+}
+
+impl Outbox {
+    pub fn is_full(&self) -> bool {
+        self.queue.len() >= CAPACITY
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.queue.is_empty()
+    }
+
+    // outbox_tickでやり直すものがあるか。持っている間はsubscribeされるまで待つ。
+    pub fn is_ready(&self) -> bool {
+        !self.queue.is_empty() && !self.held
+    }
+
+    pub fn len(&self) -> usize {
+        self.queue.len()
+    }
+
+    pub fn push(&mut self, text: String) {
+        self.queue.push_back(text);
+    }
+
+    pub fn pop(&mut self) -> Option<String> {
+        self.queue.pop_front()
+    }
+
+    // 送れなかったものを先頭に戻す。順番は変えない。
+    pub fn retry(&mut self, text: String) {
+        self.queue.push_front(text);
+    }
+}
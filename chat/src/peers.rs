@@ -0,0 +1,211 @@
+use std::{
+    collections::HashMap,
+    error::Error,
+    fs, io,
+    path::PathBuf,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use base64::{Engine, engine::general_purpose::STANDARD};
+use libp2p::{Multiaddr, PeerId};
+
+use crate::{profile::Profile, px};
+
+#[derive(Debug, Clone)]
+pub struct PeerInfo {
+    // 最後に何かイベントがあった時刻
+    pub last_seen: SystemTime,
+    // 今つながっている接続の数
+    pub connections: u32,
+    // 最後の接続がrelay経由か
+    pub relayed: bool,
+    // 最後につながったトランスポート(tcp、quic、relay)。いくつものアドレスに同時にダイヤルしたときに勝ったもの。
+    pub transport: Option<&'static str>,
+    // mDNS、identify、--peer で知ったアドレス。ダイヤルするときはすべて試す(保存はしない)。
+    pub seen_addresses: Vec<Multiaddr>,
+    // 接続したときに教えてもらったプロフィール(保存はしない)
+    pub profile: Option<Profile>,
+    // 本人が署名したアドレス(peer exchangeで受け取ったもの)とその署名つきのレコード
+    pub addresses: Vec<Multiaddr>,
+    pub record: Option<Record>,
+}
+
+#[derive(Debug, Clone)]
+pub struct Record {
+    pub seq: u64,
+    // 他のpeerにそのまま渡せるSignedEnvelope
+    pub data: Vec<u8>,
+}
+
+impl PeerInfo {
+    fn new() -> Self {
+        PeerInfo {
+            last_seen: SystemTime::now(),
+            connections: 0,
+            relayed: false,
+            transport: None,
+            seen_addresses: Vec::new(),
+            profile: None,
+            addresses: Vec::new(),
+            record: None,
+        }
+    }
+
+    pub fn is_connected(&self) -> bool {
+        self.connections > 0
+    }
+
+    // "seen 3 minutes ago" のような表示
+    pub fn seen_ago(&self) -> String {
+        let secs = self.last_seen.elapsed().unwrap_or_default().as_secs();
+        match secs {
+            0..60 => format!("seen {secs} seconds ago"),
+            60..3600 => format!("seen {} minutes ago", secs / 60),
+            3600..86400 => format!("seen {} hours ago", secs / 3600),
+            _ => format!("seen {} days ago", secs / 86400),
+        }
+    }
+}
+
+// これまでに見たpeerと最後に見た時刻。
+// ファイルを指定すれば "<peer id>\t<UNIX秒>[\t<署名つきレコードのbase64>]" の行で保存し、次の起動で読み込む。
+#[derive(Debug, Default)]
+pub struct PeerStore {
+    peers: HashMap<PeerId, PeerInfo>,
+    path: Option<PathBuf>,
+}
+
+impl PeerStore {
+    pub fn load(path: Option<PathBuf>) -> Result<Self, Box<dyn Error>> {
+        let mut peers = HashMap::new();
+        if let Some(path) = &path
+            && path.exists()
+        {
+            for line in fs::read_to_string(path)?.lines() {
+                let mut fields = line.split('\t');
+                let (Some(peer_id), Some(secs)) = (fields.next(), fields.next()) else {
+                    continue;
+                };
+                let peer_id: PeerId = peer_id.parse()?;
+                let mut info = PeerInfo {
+                    last_seen: UNIX_EPOCH + Duration::from_secs(secs.parse()?),
+                    ..PeerInfo::new()
+                };
+                // 保存したファイルでも署名は確かめ直す
+                if let Some(data) = fields.next().and_then(|b| STANDARD.decode(b).ok())
+                    && let Some(record) = px::verify(&data)
+                    && record.peer_id() == peer_id
+                {
+                    info.addresses = record.addresses().to_vec();
+                    info.record = Some(Record { seq: record.seq(), data });
+                }
+                peers.insert(peer_id, info);
+            }
+        }
+        Ok(PeerStore { peers, path })
+    }
+
+    // イベントがあったpeerの最終時刻を更新する
+    pub fn touch(&mut self, peer_id: PeerId) {
+        self.entry(peer_id).last_seen = SystemTime::now();
+    }
+
+    pub fn connected(&mut self, peer_id: PeerId, relayed: bool, transport: &'static str) {
+        let info = self.entry(peer_id);
+        info.connections += 1;
+        info.relayed = relayed;
+        info.transport = Some(transport);
+        info.last_seen = SystemTime::now();
+    }
+
+    pub fn disconnected(&mut self, peer_id: PeerId) {
+        let info = self.entry(peer_id);
+        info.connections = info.connections.saturating_sub(1);
+        info.last_seen = SystemTime::now();
+    }
+
+    // peer exchangeで受け取ったレコード。署名が正しく、前のものより新しければ覚えて、
+    // 誰のどのアドレスかを返す。
+    pub fn add_record(&mut self, data: Vec<u8>) -> Option<(PeerId, Vec<Multiaddr>)> {
+        let record = px::verify(&data)?;
+        let peer_id = record.peer_id();
+        let info = self.peers.entry(peer_id).or_insert_with(PeerInfo::new);
+        if info.record.as_ref().is_some_and(|r| r.seq >= record.seq()) {
+            return None;
+        }
+        info.addresses = record.addresses().to_vec();
+        info.record = Some(Record { seq: record.seq(), data });
+        Some((peer_id, info.addresses.clone()))
+    }
+
+    // 他のpeerに渡すレコード。最近見たpeerのものから。
+    pub fn records(&self, except: &PeerId, limit: usize) -> Vec<Vec<u8>> {
+        self.sorted()
+            .into_iter()
+            .filter(|(peer_id, _)| peer_id != except)
+            .filter_map(|(_, info)| info.record.map(|r| r.data))
+            .take(limit)
+            .collect()
+    }
+
+    pub fn add_address(&mut self, peer_id: PeerId, addr: Multiaddr) {
+        let info = self.entry(peer_id);
+        if !info.seen_addresses.contains(&addr) {
+            info.seen_addresses.push(addr);
+        }
+    }
+
+    // ダイヤルするときに試すアドレス。自分で見つけたものと、本人が署名したもの。
+    pub fn dial_addresses(&self, peer_id: &PeerId) -> Vec<Multiaddr> {
+        let Some(info) = self.peers.get(peer_id) else {
+            return Vec::new();
+        };
+        let mut addresses = info.seen_addresses.clone();
+        addresses.extend(info.addresses.iter().filter(|a| !info.seen_addresses.contains(a)).cloned());
+        addresses
+    }
+
+    pub fn set_profile(&mut self, peer_id: PeerId, profile: Profile) {
+        self.entry(peer_id).profile = Some(profile);
+    }
+
+    // swarmを作り直したときは接続がすべてなくなっている
+    pub fn reset_connections(&mut self) {
+        for info in self.peers.values_mut() {
+            info.connections = 0;
+        }
+    }
+
+    pub fn get(&self, peer_id: &PeerId) -> Option<&PeerInfo> {
+        self.peers.get(peer_id)
+    }
+
+    // 最近見た順
+    pub fn sorted(&self) -> Vec<(PeerId, PeerInfo)> {
+        let mut peers: Vec<_> = self.peers.iter().map(|(p, i)| (*p, i.clone())).collect();
+        peers.sort_by(|a, b| b.1.last_seen.cmp(&a.1.last_seen));
+        peers
+    }
+
+    pub fn save(&self) -> io::Result<()> {
+        let Some(path) = &self.path else {
+            return Ok(());
+        };
+        let text: String = self
+            .peers
+            .iter()
+            .map(|(peer_id, info)| {
+                let secs = info.last_seen.duration_since(UNIX_EPOCH).unwrap_or_default().as_secs();
+                match &info.record {
+                    Some(record) => format!("{peer_id}\t{secs}\t{}\n", STANDARD.encode(&record.data)),
+                    None => format!("{peer_id}\t{secs}\n"),
+                }
+            })
+            .collect();
+        fs::write(path, text)
+    }
+
+    fn entry(&mut self, peer_id: PeerId) -> &mut PeerInfo {
+        self.peers.entry(peer_id).or_insert_with(PeerInfo::new)
+    }
+}
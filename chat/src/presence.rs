@@ -0,0 +1,173 @@
+use std::{
+    collections::HashMap,
+    fmt,
+    time::{Duration, Instant},
+};
+
+use libp2p::PeerId;
+
+// 在席情報を流すtopic。チャットのtopicとは分けている。
+pub const TOPIC: &str = "test-net-presence";
+// この間隔で自分の状態を流す
+pub const INTERVAL: Duration = Duration::from_secs(30);
+// これだけ何も来なければいなくなったとみなす
+pub const TIMEOUT: Duration = Duration::from_secs(90);
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum Status {
+    #[default]
+    Online,
+    Away,
+}
+
+impl Status {
+    pub fn parse(s: &str) -> Option<Status> {
+        match s {
+            "online" => Some(Status::Online),
+            "away" => Some(Status::Away),
+            _ => None,
+        }
+    }
+}
+
+impl fmt::Display for Status {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Status::Online => write!(f, "online"),
+            Status::Away => write!(f, "away"),
+        }
+    }
+}
+
+// 流す内容。"<status>\t<nick>" の1行(nickは空でもよい)
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Announcement {
+    pub status: Status,
+    pub nick: Option<String>,
+}
+
+impl Announcement {
+    pub fn encode(&self) -> Vec<u8> {
+        format!("{}\t{}", self.status, self.nick.as_deref().unwrap_or_default()).into_bytes()
+    }
+
+    pub fn decode(data: &[u8]) -> Option<Announcement> {
+        let text = std::str::from_utf8(data).ok()?;
+        let (status, nick) = text.split_once('\t')?;
+        Some(Announcement {
+            status: Status::parse(status)?,
+            nick: (!nick.is_empty()).then(|| nick.to_string()),
+        })
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Entry {
+    pub nick: Option<String>,
+    pub status: Status,
+    pub last_seen: Instant,
+}
+
+impl fmt::Display for Entry {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} ({}, seen {}s ago)",
+            self.nick.as_deref().unwrap_or("-"),
+            self.status,
+            self.last_seen.elapsed().as_secs()
+        )
+    }
+}
+
+// 受け取ったときの変化。表示に使う。
+#[derive(Debug, PartialEq, Eq)]
+pub enum Change {
+    Joined,
+    StatusChanged(Status),
+}
+
+// 他のpeerの在席状況
+#[derive(Debug, Default)]
+pub struct Presence {
+    peers: HashMap<PeerId, Entry>,
+}
+
+impl Presence {
+    pub fn update(&mut self, peer_id: PeerId, announcement: Announcement) -> Option<Change> {
+        let entry = Entry {
+            nick: announcement.nick,
+            status: announcement.status,
+            last_seen: Instant::now(),
+        };
+        let change = match self.peers.get(&peer_id) {
+            None => Some(Change::Joined),
+            Some(old) if old.status != entry.status => Some(Change::StatusChanged(entry.status)),
+            Some(_) => None,
+        };
+        self.peers.insert(peer_id, entry);
+        change
+    }
+
+    // TIMEOUTを過ぎたpeerを取り除いて返す
+    pub fn expire(&mut self) -> Vec<(PeerId, Entry)> {
+        let expired: Vec<PeerId> = self
+            .peers
+            .iter()
+            .filter(|(_, e)| e.last_seen.elapsed() > TIMEOUT)
+            .map(|(p, _)| *p)
+            .collect();
+        expired
+            .into_iter()
+            .filter_map(|p| self.peers.remove(&p).map(|e| (p, e)))
+            .collect()
+    }
+
+    pub fn nick(&self, peer_id: &PeerId) -> Option<&str> {
+        self.peers.get(peer_id)?.nick.as_deref()
+    }
+
+    // nickからpeerを探す。大文字小文字は区別しない。
+    pub fn find(&self, nick: &str) -> Option<PeerId> {
+        self.peers
+            .iter()
+            .find(|(_, e)| e.nick.as_deref().is_some_and(|n| n.eq_ignore_ascii_case(nick)))
+            .map(|(p, _)| *p)
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (&PeerId, &Entry)> {
+        self.peers.iter()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn decode_round_trip() {
+        for announcement in [
+            Announcement { status: Status::Away, nick: Some("alice".to_string()) },
+            Announcement { status: Status::Online, nick: None },
+        ] {
+            assert_eq!(Announcement::decode(&announcement.encode()), Some(announcement));
+        }
+    }
+
+    #[test]
+    fn decode_rejects_malformed() {
+        assert_eq!(Announcement::decode(b""), None);
+        // タブがない
+        assert_eq!(Announcement::decode(b"online"), None);
+        assert_eq!(Announcement::decode(b"busy\talice"), None);
+        assert_eq!(Announcement::decode(b"online\t\xff\xfe"), None);
+        // 途中で切れた
+        assert_eq!(Announcement::decode(b"onl"), None);
+    }
+
+    #[test]
+    fn decode_keeps_tabs_in_nick() {
+        let announcement = Announcement::decode(b"away\ta\tb").unwrap();
+        assert_eq!(announcement.nick.as_deref(), Some("a\tb"));
+    }
+}
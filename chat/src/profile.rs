@@ -0,0 +1,60 @@
+use libp2p::{
+    StreamProtocol,
+    request_response::{self, ProtocolSupport},
+};
+use serde::{Deserialize, Serialize};
+
+use crate::options::Options;
+
+// 接続したら自分のプロフィールを送り、相手のものを返してもらう
+pub const PROTOCOL: StreamProtocol = StreamProtocol::new("/chat/profile/1");
+
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Profile {
+    pub nick: Option<String>,
+    // アイコン画像のハッシュ。画像そのものは別に取りに行く想定。
+    pub avatar_hash: Option<String>,
+    // このノードで使える機能
+    pub capabilities: Vec<String>,
+}
+
+impl Profile {
+    pub fn from_options(opts: &Options) -> Self {
+        let mut capabilities = vec!["direct".to_string(), "dm".to_string(), "presence".to_string()];
+        let optional = [
+            ("relay", opts.relay.is_some()),
+            ("irc", opts.irc.is_some()),
+            ("mqtt", opts.mqtt.is_some()),
+            ("matrix", opts.matrix_homeserver.is_some()),
+            ("webhook", opts.webhook.is_some()),
+            ("measure", opts.measure),
+            ("ordered", opts.ordered),
+        ];
+        capabilities.extend(optional.iter().filter(|(_, on)| *on).map(|(name, _)| name.to_string()));
+        Profile {
+            nick: opts.nick.clone(),
+            avatar_hash: opts.avatar_hash.clone(),
+            capabilities,
+        }
+    }
+}
+
+impl std::fmt::Display for Profile {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "nick: {}", self.nick.as_deref().unwrap_or("-"))?;
+        if let Some(hash) = &self.avatar_hash {
+            write!(f, ", avatar: {hash}")?;
+        }
+        write!(f, ", capabilities: {}", self.capabilities.join(","))
+    }
+}
+
+pub type Behaviour = request_response::cbor::Behaviour<Profile, Profile>;
+
+// 相手から来るものは信用できないので、--max-message-size を超えたらデコードする前に捨てる
+pub fn behaviour(max_message_size: u64) -> Behaviour {
+    let codec = request_response::cbor::codec::Codec::<Profile, Profile>::default()
+        .set_request_size_maximum(max_message_size)
+        .set_response_size_maximum(max_message_size);
+    Behaviour::with_codec(codec, [(PROTOCOL, ProtocolSupport::Full)], request_response::Config::default())
+}
@@ -0,0 +1,63 @@
+use libp2p::{
+    Multiaddr, StreamProtocol,
+    core::{PeerRecord, SignedEnvelope},
+    identity::{Keypair, SigningError},
+    request_response::{self, ProtocolSupport},
+};
+use serde::{Deserialize, Serialize};
+
+// 接続したら知っているpeerのアドレスを教え合う(peer exchange)
+pub const PROTOCOL: StreamProtocol = StreamProtocol::new("/chat/px/1");
+// 1回に送るレコードの上限
+pub const MAX_RECORDS: usize = 20;
+
+// 署名つきのpeer record(SignedEnvelopeのprotobuf)を並べたもの。
+// 最初は送った本人のもの。他人のものも署名ごと渡すので、中継したpeerには書き換えられない。
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Records(pub Vec<Vec<u8>>);
+
+pub type Behaviour = request_response::cbor::Behaviour<Records, Records>;
+
+// 相手から来るものは信用できないので、--max-message-size を超えたらデコードする前に捨てる
+pub fn behaviour(max_message_size: u64) -> Behaviour {
+    let codec = request_response::cbor::codec::Codec::<Records, Records>::default()
+        .set_request_size_maximum(max_message_size)
+        .set_response_size_maximum(max_message_size);
+    Behaviour::with_codec(codec, [(PROTOCOL, ProtocolSupport::Full)], request_response::Config::default())
+}
+
+// 自分のアドレスに署名する
+pub fn sign(keypair: &Keypair, addresses: Vec<Multiaddr>) -> Result<Vec<u8>, SigningError> {
+    Ok(PeerRecord::new(keypair, addresses)?.into_signed_envelope().into_protobuf_encoding())
+}
+
+// 署名を確かめる。壊れていたり署名が合わなければNone。
+pub fn verify(data: &[u8]) -> Option<PeerRecord> {
+    let envelope = SignedEnvelope::from_protobuf_encoding(data).ok()?;
+    PeerRecord::from_signed_envelope(envelope).ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn verify_signed_record() {
+        let keypair = Keypair::generate_ed25519();
+        let address: Multiaddr = "/ip4/127.0.0.1/tcp/4001".parse().unwrap();
+        let record = verify(&sign(&keypair, vec![address.clone()]).unwrap()).unwrap();
+        assert_eq!(record.peer_id(), keypair.public().to_peer_id());
+        assert_eq!(record.addresses(), [address]);
+    }
+
+    #[test]
+    fn verify_rejects_malformed() {
+        let data = sign(&Keypair::generate_ed25519(), Vec::new()).unwrap();
+        assert!(verify(b"").is_none());
+        assert!(verify(&data[..data.len() / 2]).is_none());
+        assert!(verify(&[0xff; 32]).is_none());
+        let mut flipped = data.clone();
+        *flipped.last_mut().unwrap() ^= 1;
+        assert!(verify(&flipped).is_none());
+    }
+}
@@ -0,0 +1,157 @@
+use std::{
+    error::Error,
+    fs::{File, OpenOptions},
+    io::{self, BufRead, BufReader, BufWriter, Write},
+    path::Path,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use libp2p::{Multiaddr, PeerId, gossipsub, mdns, swarm::SwarmEvent};
+
+use crate::{MyBehaviourEvent, dedup};
+
+// 記録・再生するイベント
+// SwarmEventそのものはシリアライズも生成もできないので、アプリで使う中身だけ取り出して保存する
+#[derive(Debug)]
+pub enum Recorded {
+    Discovered(PeerId, Multiaddr),
+    Expired(PeerId, Multiaddr),
+    Message {
+        source: PeerId,
+        id: String,
+        // 表示の重複を見分けるキー(dedup::key)
+        key: String,
+        data: Vec<u8>,
+    },
+    NewListenAddr(Multiaddr),
+    // アプリで使わないイベントはDebug出力だけ残す
+    Other(String),
+}
+
+impl Recorded {
+    pub fn from_event(event: &SwarmEvent<MyBehaviourEvent>) -> Vec<Recorded> {
+        match event {
+            SwarmEvent::Behaviour(MyBehaviourEvent::Mdns(mdns::Event::Discovered(list))) => list
+                .iter()
+                .map(|(peer_id, addr)| Recorded::Discovered(*peer_id, addr.clone()))
+                .collect(),
+            SwarmEvent::Behaviour(MyBehaviourEvent::Mdns(mdns::Event::Expired(list))) => list
+                .iter()
+                .map(|(peer_id, addr)| Recorded::Expired(*peer_id, addr.clone()))
+                .collect(),
+            SwarmEvent::Behaviour(MyBehaviourEvent::Gossipsub(gossipsub::Event::Message {
+                propagation_source,
+                message_id,
+                message,
+            })) => vec![Recorded::Message {
+                source: *propagation_source,
+                id: message_id.to_string(),
+                key: dedup::key(message.source, message.sequence_number, message_id),
+                data: message.data.clone(),
+            }],
+            SwarmEvent::NewListenAddr { address, .. } => {
+                vec![Recorded::NewListenAddr(address.clone())]
+            }
+            other => vec![Recorded::Other(format!("{other:?}"))],
+        }
+    }
+
+    // 1行1イベント、タブ区切り
+    fn to_line(&self) -> String {
+        match self {
+            Recorded::Discovered(peer_id, addr) => format!("discovered\t{peer_id}\t{addr}"),
+            Recorded::Expired(peer_id, addr) => format!("expired\t{peer_id}\t{addr}"),
+            Recorded::Message { source, id, key, data } => {
+                format!("message\t{source}\t{id}\t{}\t{key}", to_hex(data))
+            }
+            Recorded::NewListenAddr(addr) => format!("listen\t{addr}"),
+            Recorded::Other(s) => format!("other\t{}", s.replace(['\t', '\n'], " ")),
+        }
+    }
+
+    fn from_line(line: &str) -> Result<Recorded, Box<dyn Error>> {
+        let fields: Vec<&str> = line.split('\t').collect();
+        let recorded = match fields.as_slice() {
+            ["discovered", peer_id, addr] => Recorded::Discovered(peer_id.parse()?, addr.parse()?),
+            ["expired", peer_id, addr] => Recorded::Expired(peer_id.parse()?, addr.parse()?),
+            ["message", source, id, data, key] => Recorded::Message {
+                source: source.parse()?,
+                id: id.to_string(),
+                key: key.to_string(),
+                data: from_hex(data)?,
+            },
+            // キーがない古い記録はMessageIdで代用する
+            ["message", source, id, data] => Recorded::Message {
+                source: source.parse()?,
+                id: id.to_string(),
+                key: id.to_string(),
+                data: from_hex(data)?,
+            },
+            ["listen", addr] => Recorded::NewListenAddr(addr.parse()?),
+            ["other", s] => Recorded::Other(s.to_string()),
+            _ => return Err(format!("invalid record: {line}").into()),
+        };
+        Ok(recorded)
+    }
+}
+
+// 受信したSwarmEventを時刻(UNIX時間のミリ秒)付きでファイルに書き出す
+pub struct Recorder {
+    writer: BufWriter<File>,
+}
+
+impl Recorder {
+    pub fn create(path: &Path) -> io::Result<Self> {
+        let writer = BufWriter::new(File::create(path)?);
+        Ok(Recorder { writer })
+    }
+
+    // 既存の記録に追記する
+    pub fn append(path: &Path) -> io::Result<Self> {
+        let file = OpenOptions::new().create(true).append(true).open(path)?;
+        Ok(Recorder {
+            writer: BufWriter::new(file),
+        })
+    }
+
+    pub fn record(&mut self, event: &SwarmEvent<MyBehaviourEvent>) -> io::Result<()> {
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_err(io::Error::other)?
+            .as_millis();
+        for recorded in Recorded::from_event(event) {
+            writeln!(self.writer, "{now}\t{}", recorded.to_line())?;
+        }
+        // 落ちたときにも残っているよう毎回flushする
+        self.writer.flush()?;
+        Ok(())
+    }
+}
+
+// 記録したファイルを読み込む
+pub fn load(path: &Path) -> Result<Vec<(u128, Recorded)>, Box<dyn Error>> {
+    let mut events = Vec::new();
+    for line in BufReader::new(File::open(path)?).lines() {
+        let line = line?;
+        let Some((time, rest)) = line.split_once('\t') else {
+            return Err(format!("invalid record: {line}").into());
+        };
+        events.push((time.parse()?, Recorded::from_line(rest)?));
+    }
+    Ok(events)
+}
+
+fn to_hex(data: &[u8]) -> String {
+    data.iter().map(|b| format!("{b:02x}")).collect()
+}
+
+fn from_hex(s: &str) -> Result<Vec<u8>, Box<dyn Error>> {
+    if !s.is_ascii() || s.len() % 2 != 0 {
+        return Err(format!("invalid hex: {s}").into());
+    }
+    (0..s.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(&s[i..i + 2], 16))
+        .collect::<Result<Vec<u8>, _>>()
+        .map_err(Into::into)
+}
@@ -0,0 +1,69 @@
+use std::{
+    fmt,
+    time::{Duration, Instant},
+};
+
+use libp2p::{Multiaddr, PeerId, core::transport::ListenerId};
+
+// relayがreservationを何時間持ってくれるかはlibp2pのイベントでは分からないので、
+// relayサーバのデフォルト(1時間)として期限の目安にする。更新はrelay clientが自動で行う。
+const RESERVATION_DURATION: Duration = Duration::from_secs(60 * 60);
+
+#[derive(Debug)]
+pub struct Reservation {
+    pub relay: PeerId,
+    // 最後に受け付けられた(更新された)時刻
+    pub accepted: Instant,
+    pub renewals: u32,
+}
+
+impl fmt::Display for Reservation {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let left = RESERVATION_DURATION.saturating_sub(self.accepted.elapsed());
+        write!(
+            f,
+            "{} (accepted {}s ago, renewed {} times, expires in ~{}m)",
+            self.relay,
+            self.accepted.elapsed().as_secs(),
+            self.renewals,
+            left.as_secs() / 60
+        )
+    }
+}
+
+// --relay で取ったreservationの状態。/relay で表示する。
+#[derive(Debug, Default)]
+pub struct RelayStatus {
+    // /p2p-circuit で待ち受けているlistener。閉じたらNoneにしてつなぎ直す。
+    pub listener: Option<ListenerId>,
+    pub reservation: Option<Reservation>,
+    // relay経由で待ち受けているアドレス
+    pub addresses: Vec<Multiaddr>,
+    // reservationの取得・更新に失敗した回数
+    pub failures: u32,
+}
+
+impl RelayStatus {
+    pub fn accepted(&mut self, relay: PeerId, renewal: bool) {
+        match &mut self.reservation {
+            Some(reservation) if renewal => {
+                reservation.accepted = Instant::now();
+                reservation.renewals += 1;
+            }
+            _ => {
+                self.reservation = Some(Reservation {
+                    relay,
+                    accepted: Instant::now(),
+                    renewals: 0,
+                })
+            }
+        }
+    }
+
+    // listenerが閉じた。reservationも失われている。
+    pub fn closed(&mut self) {
+        self.listener = None;
+        self.reservation = None;
+        self.addresses.clear();
+    }
+}
@@ -0,0 +1,234 @@
+// --ordered のときはチャットのメッセージに送り手がtopicごとに数える番号を付ける。
+//  [n=<番号>] 本文
+// gossipsubのシーケンス番号は送り手ごとにtopicをまたいで増えるので、自分がsubscribeしていない
+// topicに送られた分が飛んだように見えてしまう。topicごとの番号なら飛んだのは届かなかったものだけになる。
+// 本文の前に付けるので、番号を知らないpeer(--ordered のないもの)にはそのまま見える。
+
+use std::{
+    collections::{BTreeMap, HashMap},
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+
+use libp2p::{PeerId, gossipsub::TopicHash};
+
+// 順番が前後したメッセージを待つ時間
+pub const WINDOW: Duration = Duration::from_secs(2);
+// これ以上飛んでいたら送り手が再起動したとみなす(番号は起動時刻から始まる)
+const MAX_GAP: u64 = 1000;
+// これだけ何も来ない送り手とtopicの組は忘れる。次に来たときはその番号から数え直す。
+const IDLE: Duration = Duration::from_secs(10 * 60);
+
+pub fn stamp(number: u64, text: &str) -> String {
+    format!("[n={number}] {text}")
+}
+
+// 番号が付いていれば、番号と本文。古いpeerや他の実装からのものには付いていない。
+pub fn unstamp(data: &[u8]) -> Option<(u64, &[u8])> {
+    let rest = data.strip_prefix(b"[n=")?;
+    let end = rest.iter().position(|&b| b == b']')?;
+    let number = std::str::from_utf8(&rest[..end]).ok()?.parse().ok()?;
+    Some((number, rest[end + 1..].strip_prefix(b" ")?))
+}
+
+// publishするときに付けるtopicごとの番号。gossipsubのシーケンス番号と同じく起動時刻(ナノ秒)から始める。
+#[derive(Debug)]
+pub struct Counters {
+    start: u64,
+    next: HashMap<TopicHash, u64>,
+}
+
+impl Default for Counters {
+    fn default() -> Self {
+        let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default();
+        Counters {
+            start: now.as_nanos() as u64,
+            next: HashMap::new(),
+        }
+    }
+}
+
+impl Counters {
+    // 次に付ける番号。送れたらadvanceで進める(送れなかった番号を飛ばさない)。
+    pub fn peek(&self, topic: &TopicHash) -> u64 {
+        self.next.get(topic).copied().unwrap_or(self.start)
+    }
+
+    pub fn advance(&mut self, topic: &TopicHash) {
+        let next = self.peek(topic) + 1;
+        self.next.insert(topic.clone(), next);
+    }
+}
+
+// 表示する順に返すもの
+#[derive(Debug)]
+pub enum Delivery<T> {
+    Message(T),
+    // WINDOWを待っても来なかったメッセージの数
+    Missed { from: PeerId, count: u64 },
+}
+
+#[derive(Debug)]
+struct Sender<T> {
+    next: u64,
+    // 最後に受け取った時刻
+    last: Instant,
+    // 番号が飛んでいて待っているもの。Noneは表示しないメッセージ(在席情報など)。
+    pending: BTreeMap<u64, (Instant, Option<T>)>,
+}
+
+impl<T> Sender<T> {
+    // 次の番号から続いている分を取り出す
+    fn drain(&mut self, out: &mut Vec<Delivery<T>>) {
+        while let Some(entry) = self.pending.first_entry()
+            && *entry.key() == self.next
+        {
+            let (_, item) = entry.remove();
+            out.extend(item.map(Delivery::Message));
+            self.next += 1;
+        }
+    }
+}
+
+// 送り手がtopicごとに付けた番号で、送り手とtopicの組ごとに順番を揃える。
+// gossipsubは順番を保証しないので、その上に信頼性を足す例。
+#[derive(Debug)]
+pub struct Reorder<T> {
+    senders: HashMap<(PeerId, TopicHash), Sender<T>>,
+}
+
+impl<T> Default for Reorder<T> {
+    fn default() -> Self {
+        Reorder {
+            senders: HashMap::new(),
+        }
+    }
+}
+
+impl<T> Reorder<T> {
+    // 受け取ったメッセージを入れ、表示できるようになったものを返す
+    pub fn push(&mut self, from: PeerId, topic: TopicHash, seqno: u64, item: Option<T>) -> Vec<Delivery<T>> {
+        let mut out = Vec::new();
+        let sender = self.senders.entry((from, topic)).or_insert_with(|| Sender {
+            next: seqno,
+            last: Instant::now(),
+            pending: BTreeMap::new(),
+        });
+        sender.last = Instant::now();
+        if seqno < sender.next {
+            // 待ちきれずに飛ばした後で届いた。遅れてでも表示する。
+            out.extend(item.map(Delivery::Message));
+            return out;
+        }
+        if seqno - sender.next > MAX_GAP {
+            // 送り手が再起動した。待っていた分は出して数え直す。
+            let pending = std::mem::take(&mut sender.pending);
+            out.extend(pending.into_values().filter_map(|(_, item)| item).map(Delivery::Message));
+            sender.next = seqno;
+        }
+        sender.pending.insert(seqno, (Instant::now(), item));
+        sender.drain(&mut out);
+        out
+    }
+
+    // WINDOWを過ぎても前が来ないものは、飛んだ数を知らせてから出す。しばらく来ない送り手は忘れる。
+    pub fn flush(&mut self) -> Vec<Delivery<T>> {
+        let mut out = Vec::new();
+        for ((from, _), sender) in self.senders.iter_mut() {
+            while let Some((&seqno, (received, _))) = sender.pending.first_key_value()
+                && received.elapsed() >= WINDOW
+            {
+                out.push(Delivery::Missed {
+                    from: *from,
+                    count: seqno - sender.next,
+                });
+                sender.next = seqno;
+                sender.drain(&mut out);
+            }
+        }
+        self.forget(IDLE);
+        out
+    }
+
+    // idleより長く何も来ていない送り手を忘れる。待っているものがあれば残す。
+    fn forget(&mut self, idle: Duration) {
+        self.senders.retain(|_, sender| !sender.pending.is_empty() || sender.last.elapsed() < idle);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn topic(name: &str) -> TopicHash {
+        TopicHash::from_raw(name)
+    }
+
+    fn messages(out: Vec<Delivery<u64>>) -> Vec<u64> {
+        out.into_iter()
+            .map(|d| match d {
+                Delivery::Message(n) => n,
+                Delivery::Missed { .. } => panic!("unexpected missed"),
+            })
+            .collect()
+    }
+
+    #[test]
+    fn stamp_round_trip() {
+        let stamped = stamp(42, "hello [n=1] x");
+        assert_eq!(unstamp(stamped.as_bytes()), Some((42, "hello [n=1] x".as_bytes())));
+        assert_eq!(unstamp(b"hello"), None);
+        assert_eq!(unstamp(b"[n=x] hello"), None);
+    }
+
+    #[test]
+    fn forgets_idle_senders() {
+        let mut reorder = Reorder::default();
+        let from = PeerId::random();
+        reorder.push(from, topic("a"), 1, Some(1));
+        reorder.push(from, topic("b"), 5, Some(2));
+        // 待っているものがあるうちは忘れない
+        assert!(reorder.push(from, topic("a"), 3, Some(3)).is_empty());
+        reorder.flush();
+        assert_eq!(reorder.senders.len(), 2);
+        reorder.forget(Duration::ZERO);
+        assert_eq!(reorder.senders.len(), 1);
+    }
+
+    #[test]
+    fn unstamp_rejects_malformed() {
+        assert_eq!(unstamp(b""), None);
+        assert_eq!(unstamp(b"[n="), None);
+        assert_eq!(unstamp(b"[n=12"), None);
+        assert_eq!(unstamp(b"[n=12]"), None);
+        assert_eq!(unstamp(b"[n=12]x"), None);
+        assert_eq!(unstamp(b"[n=] x"), None);
+        assert_eq!(unstamp(b"[n=-1] x"), None);
+        assert_eq!(unstamp(b"[n=\xff] x"), None);
+        assert_eq!(unstamp(b"[n=99999999999999999999999] x"), None);
+        // 本文はUTF-8でなくてもよい(検証はこの後でする)
+        assert_eq!(unstamp(b"[n=1] \xff"), Some((1, &b"\xff"[..])));
+    }
+
+    #[test]
+    fn counters_are_per_topic() {
+        let mut counters = Counters::default();
+        let (a, b) = (topic("a"), topic("b"));
+        let start = counters.peek(&a);
+        counters.advance(&a);
+        counters.advance(&a);
+        assert_eq!(counters.peek(&a), start + 2);
+        assert_eq!(counters.peek(&b), start);
+    }
+
+    #[test]
+    fn topics_are_ordered_separately() {
+        let mut reorder = Reorder::default();
+        let from = PeerId::random();
+        assert_eq!(messages(reorder.push(from, topic("a"), 10, Some(1))), vec![1]);
+        // 別のtopicは別に数えるので、aの続きを待たない
+        assert_eq!(messages(reorder.push(from, topic("b"), 50, Some(2))), vec![2]);
+        assert!(reorder.push(from, topic("a"), 12, Some(3)).is_empty());
+        assert_eq!(messages(reorder.push(from, topic("a"), 11, Some(4))), vec![4, 3]);
+        assert_eq!(messages(reorder.push(from, topic("b"), 51, Some(5))), vec![5]);
+    }
+}
@@ -0,0 +1,145 @@
+use std::{
+    collections::HashMap,
+    fmt,
+    time::{Duration, Instant},
+};
+
+use libp2p::PeerId;
+
+// これを下回ったら切断してしばらく接続を拒否する
+pub const BAN_THRESHOLD: f64 = -10.0;
+pub const BAN_DURATION: Duration = Duration::from_secs(10 * 60);
+// スコアは1分に1点ずつ0に戻っていく
+const RECOVERY_PER_SEC: f64 = 1.0 / 60.0;
+// RATE_WINDOWの間にRATE_LIMITを超えてメッセージを送ってきたら減点する
+const RATE_WINDOW: Duration = Duration::from_secs(10);
+const RATE_LIMIT: u32 = 50;
+
+// 減点の理由
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Offense {
+    // 検証でRejectしたメッセージ
+    InvalidMessage,
+    // 送ってくるメッセージが多すぎる
+    ExcessiveRate,
+}
+
+impl Offense {
+    fn penalty(self) -> f64 {
+        match self {
+            Offense::InvalidMessage => 5.0,
+            Offense::ExcessiveRate => 2.0,
+        }
+    }
+}
+
+impl fmt::Display for Offense {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Offense::InvalidMessage => write!(f, "invalid message"),
+            Offense::ExcessiveRate => write!(f, "excessive rate"),
+        }
+    }
+}
+
+#[derive(Debug)]
+struct Entry {
+    score: f64,
+    updated: Instant,
+    window_start: Instant,
+    count: u32,
+}
+
+impl Entry {
+    fn new(now: Instant) -> Self {
+        Entry {
+            score: 0.0,
+            updated: now,
+            window_start: now,
+            count: 0,
+        }
+    }
+
+    // 前回から経った時間の分だけ0に近づける
+    fn decay(&mut self, now: Instant) {
+        let recovered = now.duration_since(self.updated).as_secs_f64() * RECOVERY_PER_SEC;
+        self.score = (self.score + recovered).min(0.0);
+        self.updated = now;
+    }
+}
+
+// アプリ側で付けるpeerの評判。gossipsubのpeer scoreとは別物。
+#[derive(Debug, Default)]
+pub struct Reputation {
+    peers: HashMap<PeerId, Entry>,
+    // banが解ける時刻
+    banned: HashMap<PeerId, Instant>,
+}
+
+impl Reputation {
+    // 減点する。しきい値を下回って新しくbanしたらtrue。
+    pub fn penalize(&mut self, peer_id: PeerId, offense: Offense) -> bool {
+        let now = Instant::now();
+        let entry = self.peers.entry(peer_id).or_insert_with(|| Entry::new(now));
+        entry.decay(now);
+        entry.score -= offense.penalty();
+        if entry.score >= BAN_THRESHOLD || self.banned.contains_key(&peer_id) {
+            return false;
+        }
+        self.banned.insert(peer_id, now + BAN_DURATION);
+        true
+    }
+
+    // メッセージを受け取るたびに呼ぶ。多すぎれば減点する。
+    pub fn on_message(&mut self, peer_id: PeerId) -> Option<Offense> {
+        let now = Instant::now();
+        let entry = self.peers.entry(peer_id).or_insert_with(|| Entry::new(now));
+        if now.duration_since(entry.window_start) > RATE_WINDOW {
+            entry.window_start = now;
+            entry.count = 0;
+        }
+        entry.count += 1;
+        (entry.count == RATE_LIMIT + 1).then_some(Offense::ExcessiveRate)
+    }
+
+    pub fn is_banned(&self, peer_id: &PeerId) -> bool {
+        self.banned.contains_key(peer_id)
+    }
+
+    pub fn banned(&self) -> impl Iterator<Item = &PeerId> {
+        self.banned.keys()
+    }
+
+    // 期限が来たbanを解いて、そのpeerを返す
+    pub fn expire_bans(&mut self) -> Vec<PeerId> {
+        let now = Instant::now();
+        let expired: Vec<PeerId> = self
+            .banned
+            .iter()
+            .filter(|(_, until)| **until <= now)
+            .map(|(peer_id, _)| *peer_id)
+            .collect();
+        for peer_id in &expired {
+            self.banned.remove(peer_id);
+            // 解いた直後にまたbanされないよう点数も戻す
+            self.peers.remove(peer_id);
+        }
+        expired
+    }
+
+    // (peer, 今のスコア, banが解けるまでの時間)
+    pub fn list(&mut self) -> Vec<(PeerId, f64, Option<Duration>)> {
+        let now = Instant::now();
+        let mut list: Vec<_> = self
+            .peers
+            .iter_mut()
+            .map(|(peer_id, entry)| {
+                entry.decay(now);
+                let remaining = self.banned.get(peer_id).map(|until| until.saturating_duration_since(now));
+                (*peer_id, entry.score, remaining)
+            })
+            .collect();
+        list.sort_by(|a, b| a.1.total_cmp(&b.1));
+        list
+    }
+}
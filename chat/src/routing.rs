@@ -0,0 +1,212 @@
+use libp2p::{
+    PeerId, StreamProtocol,
+    core::SignedEnvelope,
+    identity::Keypair,
+    request_response::{self, ProtocolSupport},
+};
+use serde::{Deserialize, Serialize};
+
+// 直接つながっていないpeerへ、つながっているpeerを順に経由して届ける(source routing)
+// 2から書いた人の署名を付けた。1のpeerとはやり取りしない。
+pub const PROTOCOL: StreamProtocol = StreamProtocol::new("/chat/route/2");
+// 経由できるpeerの数の上限
+pub const MAX_HOPS: u8 = 8;
+// 署名の用途。他の署名と取り違えられないようにする。
+const DOMAIN: &str = "libp2p-tutorial-chat-route";
+const PAYLOAD_TYPE: &[u8] = b"/chat/route/2";
+
+// PeerIdはserdeに対応していないので文字列で持つ
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Routed {
+    // 書いた人
+    pub source: String,
+    // 送り手が決めた経路。最後が宛先。
+    pub route: Vec<String>,
+    // あと何回転送できるか。転送するたびに減らす。
+    pub ttl: u8,
+    pub text: String,
+    // source, route, text への書いた人の署名(SignedEnvelopeのprotobuf)。途中のpeerには書き換えられない。
+    pub signature: Vec<u8>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub enum RouteResponse {
+    Delivered,
+    // 次のpeerに渡した(届いたかどうかは分からない)
+    Forwarded,
+    Dropped(String),
+}
+
+// 受け取ったpeerがすること
+#[derive(Debug, PartialEq, Eq)]
+pub enum Step {
+    // 自分宛て
+    Deliver,
+    // 次のpeerへ
+    Forward(PeerId),
+}
+
+impl Routed {
+    pub fn new(keypair: &Keypair, route: &[PeerId], text: String) -> Result<Self, String> {
+        let mut routed = Routed {
+            source: keypair.public().to_peer_id().to_string(),
+            route: route.iter().map(|p| p.to_string()).collect(),
+            ttl: MAX_HOPS,
+            text,
+            signature: Vec::new(),
+        };
+        routed.peers()?;
+        let envelope = SignedEnvelope::new(keypair, DOMAIN.to_string(), PAYLOAD_TYPE.to_vec(), routed.signed_payload())
+            .map_err(|e| format!("signing failed: {e}"))?;
+        routed.signature = envelope.into_protobuf_encoding();
+        Ok(routed)
+    }
+
+    // 署名するもの。ttlは転送するたびに変わるので含めない。
+    fn signed_payload(&self) -> Vec<u8> {
+        serde_json::to_vec(&(&self.source, &self.route, &self.text)).unwrap_or_default()
+    }
+
+    // 署名が書いた人(source)のもので、source, route, text が書き換えられていないか
+    pub fn verify(&self) -> Result<PeerId, String> {
+        let (source, _) = self.peers()?;
+        let envelope = SignedEnvelope::from_protobuf_encoding(&self.signature).map_err(|_| "unsigned")?;
+        let (payload, key) = envelope
+            .payload_and_signing_key(DOMAIN.to_string(), PAYLOAD_TYPE)
+            .map_err(|_| "invalid signature")?;
+        if key.to_peer_id() != source || payload != self.signed_payload().as_slice() {
+            return Err("signature does not match the source".to_string());
+        }
+        Ok(source)
+    }
+
+    // 自分(me)が経路のどこにいるかを見て、次にすることを決める。
+    // from は渡してきたpeer。経路で自分の1つ前(最初なら書いた人)でなければ受け付けない。
+    pub fn step(&self, me: &PeerId, from: &PeerId) -> Result<Step, String> {
+        let (source, route) = self.peers()?;
+        let position = route.iter().position(|p| p == me).ok_or("not on the route")?;
+        let previous = if position == 0 { source } else { route[position - 1] };
+        if previous != *from {
+            return Err(format!("expected from {previous}"));
+        }
+        match route.get(position + 1) {
+            None => Ok(Step::Deliver),
+            Some(_) if self.ttl == 0 => Err("hop limit exceeded".to_string()),
+            Some(next) => Ok(Step::Forward(*next)),
+        }
+    }
+
+    // 次のpeerに渡すもの
+    pub fn forwarded(&self) -> Routed {
+        Routed { ttl: self.ttl.saturating_sub(1), ..self.clone() }
+    }
+
+    // 書いた人と経路。同じpeerを2回通る経路はループになるので受け付けない。
+    pub fn peers(&self) -> Result<(PeerId, Vec<PeerId>), String> {
+        let source: PeerId = self.source.parse().map_err(|e| format!("invalid source: {e}"))?;
+        let route = self
+            .route
+            .iter()
+            .map(|p| p.parse().map_err(|e| format!("invalid peer id {p}: {e}")))
+            .collect::<Result<Vec<PeerId>, String>>()?;
+        if route.is_empty() {
+            return Err("empty route".to_string());
+        }
+        if route.len() > MAX_HOPS as usize {
+            return Err(format!("too many hops (max {MAX_HOPS})"));
+        }
+        let mut seen = vec![source];
+        for peer_id in &route {
+            if seen.contains(peer_id) {
+                return Err(format!("loop at {peer_id}"));
+            }
+            seen.push(*peer_id);
+        }
+        Ok((source, route))
+    }
+}
+
+pub type Behaviour = request_response::cbor::Behaviour<Routed, RouteResponse>;
+
+// 相手から来るものは信用できないので、--max-message-size を超えたらデコードする前に捨てる
+pub fn behaviour(max_message_size: u64) -> Behaviour {
+    let codec = request_response::cbor::codec::Codec::<Routed, RouteResponse>::default()
+        .set_request_size_maximum(max_message_size)
+        .set_response_size_maximum(max_message_size);
+    Behaviour::with_codec(codec, [(PROTOCOL, ProtocolSupport::Full)], request_response::Config::default())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn routed(hops: usize) -> (Keypair, Vec<PeerId>, Routed) {
+        let keypair = Keypair::generate_ed25519();
+        let route: Vec<PeerId> = (0..hops).map(|_| PeerId::random()).collect();
+        let routed = Routed::new(&keypair, &route, "hello".to_string()).unwrap();
+        (keypair, route, routed)
+    }
+
+    #[test]
+    fn verify_accepts_signed() {
+        let (keypair, _, routed) = routed(2);
+        assert_eq!(routed.verify(), Ok(keypair.public().to_peer_id()));
+        // ttlは署名に含めない
+        assert!(routed.forwarded().verify().is_ok());
+    }
+
+    #[test]
+    fn verify_rejects_tampering() {
+        let (_, route, routed) = routed(2);
+        let text = Routed { text: "evil".to_string(), ..routed.clone() };
+        assert!(text.verify().is_err());
+        let source = Routed { source: route[0].to_string(), route: vec![route[1].to_string()], ..routed.clone() };
+        assert!(source.verify().is_err());
+        let unsigned = Routed { signature: Vec::new(), ..routed };
+        assert!(unsigned.verify().is_err());
+    }
+
+    #[test]
+    fn verify_rejects_malformed() {
+        let (_, _, routed) = routed(2);
+        let truncated = Routed { signature: routed.signature[..routed.signature.len() / 2].to_vec(), ..routed.clone() };
+        assert!(truncated.verify().is_err());
+        let garbage = Routed { signature: vec![0xff; 64], ..routed.clone() };
+        assert!(garbage.verify().is_err());
+        let source = Routed { source: "not a peer id".to_string(), ..routed.clone() };
+        assert!(source.verify().is_err());
+        let hop = Routed { route: vec!["\u{0}".to_string()], ..routed.clone() };
+        assert!(hop.verify().is_err());
+    }
+
+    #[test]
+    fn peers_rejects_bad_routes() {
+        let (keypair, route, routed) = routed(2);
+        let source = keypair.public().to_peer_id().to_string();
+        let empty = Routed { route: Vec::new(), ..routed.clone() };
+        assert!(empty.peers().is_err());
+        let long = Routed { route: vec![route[0].to_string(); MAX_HOPS as usize + 1], ..routed.clone() };
+        assert!(long.peers().is_err());
+        // 書いた人に戻るのもループ
+        let back = Routed { route: vec![route[0].to_string(), source], ..routed };
+        assert!(back.peers().is_err());
+    }
+
+    #[test]
+    fn step_follows_route() {
+        let (keypair, route, routed) = routed(2);
+        let source = keypair.public().to_peer_id();
+        assert_eq!(routed.step(&route[0], &source), Ok(Step::Forward(route[1])));
+        assert_eq!(routed.step(&route[1], &route[0]), Ok(Step::Deliver));
+    }
+
+    #[test]
+    fn step_rejects_wrong_previous_hop() {
+        let (keypair, route, routed) = routed(2);
+        let source = keypair.public().to_peer_id();
+        // 経路を飛ばして書いた人から直接宛先へ
+        assert!(routed.step(&route[1], &source).is_err());
+        assert!(routed.step(&route[0], &PeerId::random()).is_err());
+        assert!(routed.step(&PeerId::random(), &source).is_err());
+    }
+}
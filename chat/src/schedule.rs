@@ -0,0 +1,88 @@
+use std::{
+    error::Error,
+    fs, io,
+    path::PathBuf,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+// /send-at で予約したメッセージ
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Scheduled {
+    pub at: SystemTime,
+    pub text: String,
+}
+
+impl Scheduled {
+    pub fn secs(&self) -> u64 {
+        self.at.duration_since(UNIX_EPOCH).unwrap_or_default().as_secs()
+    }
+}
+
+// 送る時刻が来るまで持っておくメッセージ。
+// ファイルを指定すれば "<UNIX秒>\t<本文>" の行で保存し、再起動しても送る。
+#[derive(Debug, Default)]
+pub struct Schedule {
+    entries: Vec<Scheduled>,
+    path: Option<PathBuf>,
+}
+
+impl Schedule {
+    pub fn load(path: Option<PathBuf>) -> Result<Self, Box<dyn Error>> {
+        let mut entries = Vec::new();
+        if let Some(path) = &path
+            && path.exists()
+        {
+            for line in fs::read_to_string(path)?.lines() {
+                let Some((secs, text)) = line.split_once('\t') else {
+                    continue;
+                };
+                entries.push(Scheduled {
+                    at: UNIX_EPOCH + Duration::from_secs(secs.parse()?),
+                    text: text.to_string(),
+                });
+            }
+        }
+        Ok(Schedule { entries, path })
+    }
+
+    pub fn push(&mut self, entry: Scheduled) -> io::Result<()> {
+        self.entries.push(entry);
+        self.save()
+    }
+
+    // 時刻が来たものを取り出す。止まっていた間に過ぎたものも含む。
+    pub fn due(&mut self) -> io::Result<Vec<Scheduled>> {
+        let now = SystemTime::now();
+        let (due, rest) = self.entries.drain(..).partition(|e| e.at <= now);
+        self.entries = rest;
+        if !due.is_empty() {
+            self.save()?;
+        }
+        Ok(due)
+    }
+
+    fn save(&self) -> io::Result<()> {
+        let Some(path) = &self.path else {
+            return Ok(());
+        };
+        let text: String = self.entries.iter().map(|e| format!("{}\t{}\n", e.secs(), e.text)).collect();
+        fs::write(path, text)
+    }
+}
+
+// "+30s" "+10m" "+2h" のような今からの時間か、UNIX秒
+pub fn parse_time(s: &str) -> Result<SystemTime, String> {
+    let Some(rest) = s.strip_prefix('+') else {
+        let secs = s.parse().map_err(|_| format!("invalid time: {s}"))?;
+        return Ok(UNIX_EPOCH + Duration::from_secs(secs));
+    };
+    let (number, unit) = rest.split_at(rest.find(|c: char| !c.is_ascii_digit()).unwrap_or(rest.len()));
+    let number: u64 = number.parse().map_err(|_| format!("invalid time: {s}"))?;
+    let secs = match unit {
+        "" | "s" => number,
+        "m" => number * 60,
+        "h" => number * 3600,
+        _ => return Err(format!("invalid time unit: {unit}")),
+    };
+    Ok(SystemTime::now() + Duration::from_secs(secs))
+}
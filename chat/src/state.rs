@@ -0,0 +1,97 @@
+use std::{
+    collections::{HashMap, HashSet},
+    path::PathBuf,
+    sync::{Mutex, MutexGuard, PoisonError},
+};
+
+use libp2p::{Multiaddr, PeerId};
+
+use crate::{
+    blocklist::Blocklist,
+    blocks::BlockStore,
+    connlog::ConnLog,
+    contacts::{Aliases, Contacts},
+    dedup::Displayed,
+    delivery::Deliveries,
+    diag::Diagnostics,
+    kad_status::KadStatus,
+    dm::Conversations,
+    measure::Stats,
+    mention::Mention,
+    outbox::Outbox,
+    peers::PeerStore,
+    presence::{Presence, Status},
+    reorder::Counters,
+    relay_status::RelayStatus,
+    reputation::Reputation,
+    schedule::Schedule,
+};
+
+// swarmを作り直しても引き継ぐ状態。supervisorとswarmのタスクで共有する。
+#[derive(Debug, Default)]
+pub struct State {
+    // mDNSで見つけたpeer。作り直したときに接続し直す。
+    pub known_peers: Mutex<HashMap<PeerId, Multiaddr>>,
+    // topicをsubscribeしていたpeer。meshがないときに直接送る先。
+    pub subscribers: Mutex<HashMap<String, HashSet<PeerId>>>,
+    pub blocklist: Mutex<Blocklist>,
+    // アプリ側で付けるpeerの評判と一時的なban
+    pub reputation: Mutex<Reputation>,
+    // 見たことのあるpeerと最後に見た時刻
+    pub peers: Mutex<PeerStore>,
+    // 最近の接続・切断・接続の失敗
+    pub connlog: Mutex<ConnLog>,
+    // /diag のために集めたもの
+    pub diag: Mutex<Diagnostics>,
+    // --relay で取ったreservation
+    pub relay: Mutex<RelayStatus>,
+    // DHTのbootstrapとルーティングテーブルの出入り
+    pub kad: Mutex<KadStatus>,
+    // 最近表示したメッセージ。作り直した後にgossipで同じものが来ても表示しない。
+    pub displayed: Mutex<Displayed>,
+    // /dm のやり取り
+    pub conversations: Mutex<Conversations>,
+    // 自分宛てのメッセージ
+    pub mentions: Mutex<Vec<Mention>>,
+    // デスクトップ通知を出さないtopic
+    pub muted_topics: Mutex<HashSet<String>>,
+    // IRCクライアントがJOINしてsubscribeしたtopic
+    pub irc_topics: Mutex<HashSet<String>>,
+    // nickとPeerIdの組(TOFU)と確かめたpeer
+    pub contacts: Mutex<Contacts>,
+    // /add したブロックと、/fetch で取りに行っているもの(保存先)
+    pub blocks: Mutex<BlockStore>,
+    pub fetches: Mutex<HashMap<String, Fetch>>,
+    // 標準入力から来てまだpublishしていない行
+    pub outbox: Mutex<Outbox>,
+    // 最近publishしたメッセージと送った相手
+    pub sent: Mutex<Deliveries>,
+    // publishするときにtopicごとに付ける番号
+    pub counters: Mutex<Counters>,
+    // --measure で受け取ったpeerから届いた、伝わるまでにかかった時間
+    pub measurements: Mutex<Stats>,
+    // /send-at で予約したメッセージ
+    pub schedule: Mutex<Schedule>,
+    // /claim で登録しようとしているnickと、/whois で見つかった持ち主
+    pub claims: Mutex<HashSet<String>>,
+    pub whois: Mutex<HashMap<String, PeerId>>,
+    // /alias で付けた名前
+    pub aliases: Mutex<Aliases>,
+    // 他のpeerの在席状況と自分の状態
+    pub presence: Mutex<Presence>,
+    pub status: Mutex<Status>,
+}
+
+// /fetch で取りに行っているブロック
+#[derive(Debug, Default)]
+pub struct Fetch {
+    // 取れたら書き出すファイル
+    pub output: Option<PathBuf>,
+    // もう頼んだpeerがいればtrue
+    pub requested: bool,
+}
+
+// panicしたタスクが持っていた後でも中身は使い続ける
+pub fn lock<T>(mutex: &Mutex<T>) -> MutexGuard<'_, T> {
+    mutex.lock().unwrap_or_else(PoisonError::into_inner)
+}
@@ -0,0 +1,30 @@
+use libp2p::gossipsub::{TopicHash, TopicSubscriptionFilter};
+use regex::Regex;
+
+use crate::presence;
+
+// subscribeしてよいtopic。他のpeerのSUBSCRIBEもこれで絞るので、
+// 知らないtopicのメッセージを流し込まれることがない。
+// 許可リストが空ならすべて許可する。
+#[derive(Debug, Clone)]
+pub struct SubscriptionFilter {
+    allow: Vec<Regex>,
+}
+
+impl SubscriptionFilter {
+    pub fn new(allow: Vec<Regex>) -> Self {
+        SubscriptionFilter { allow }
+    }
+}
+
+impl TopicSubscriptionFilter for SubscriptionFilter {
+    fn can_subscribe(&mut self, topic_hash: &TopicHash) -> bool {
+        // IdentTopicなのでハッシュはtopic名そのもの
+        let topic = topic_hash.as_str();
+        // このノードが使うtopicは常に許可する
+        if topic == crate::TOPIC || topic == presence::TOPIC || self.allow.is_empty() {
+            return true;
+        }
+        self.allow.iter().any(|re| re.is_match(topic))
+    }
+}
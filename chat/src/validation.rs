@@ -0,0 +1,181 @@
+use libp2p::{
+    PeerId,
+    gossipsub::{Message, MessageAcceptance, TopicHash},
+};
+
+use crate::{blocklist::Blocklist, filter::Filters};
+
+// 受信したgossipsubメッセージを表示・転送してよいか判断する。
+// Okなら表示する本文、Errならgossipsubに返す判定と理由。
+//  Reject : 不正なメッセージ。送ってきたpeerのスコアが下がり、転送もしない。
+//  Ignore : 不正ではないが転送しない。
+pub fn validate(
+    message: &Message,
+    subscribed: bool,
+    blocklist: &Blocklist,
+    filters: &Filters,
+) -> Result<String, (MessageAcceptance, String)> {
+    // 署名の検証自体はValidationMode::Strictでgossipsubがやっている。
+    // ここでは署名と送信者が付いていることだけ確かめる。
+    let Some(source) = message.source else {
+        return Err((MessageAcceptance::Reject, "unsigned message".to_string()));
+    };
+    if message.signature.is_none() {
+        return Err((MessageAcceptance::Reject, "unsigned message".to_string()));
+    }
+    if !subscribed {
+        return Err((MessageAcceptance::Ignore, format!("unknown topic {}", message.topic)));
+    }
+    // ブロックした相手が書いたものは中継されてきても流さない
+    if blocklist.contains(&source) {
+        return Err((MessageAcceptance::Ignore, format!("blocked author {source}")));
+    }
+    // チャットの本文はUTF-8の文字列
+    let text = String::from_utf8(message.data.clone())
+        .map_err(|_| (MessageAcceptance::Reject, "not UTF-8 text".to_string()))?;
+    filters
+        .apply(text)
+        .map_err(|reason| (MessageAcceptance::Reject, reason))
+}
+
+// request-responseで直接送られてきたtopicへのメッセージ(meshができる前の代わり)。
+// Okなら表示する本文、Errなら断る理由。
+pub fn validate_direct(
+    author: &PeerId,
+    topic: &TopicHash,
+    subscribed: bool,
+    blocklist: &Blocklist,
+    filters: &Filters,
+    text: String,
+) -> Result<String, String> {
+    if blocklist.contains(author) {
+        return Err("blocked".to_string());
+    }
+    if !subscribed {
+        return Err(format!("not subscribed to {topic}"));
+    }
+    filters.apply(text)
+}
+
+// 1対1のメッセージ(/dm)
+pub fn validate_dm(author: &PeerId, blocklist: &Blocklist, filters: &Filters, text: String) -> Result<String, String> {
+    if blocklist.contains(author) {
+        return Err("blocked".to_string());
+    }
+    filters.apply(text)
+}
+
+// 書いた本人でもmeshやmDNSのpeerでもないところから届いたなら、
+// IHAVEを見てIWANTで取りに行ったものと考えられる
+pub fn recovered_via_gossip(message: &Message, propagation_source: &PeerId, from_mesh: bool, known_peer: bool) -> bool {
+    message.source.as_ref() != Some(propagation_source) && !from_mesh && !known_peer
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::filter::MaxLength;
+
+    fn message(source: Option<PeerId>, signed: bool, data: &[u8]) -> Message {
+        Message {
+            source,
+            data: data.to_vec(),
+            sequence_number: Some(1),
+            topic: TopicHash::from_raw("test-net"),
+            signature: signed.then(|| vec![0; 64]),
+            key: None,
+        }
+    }
+
+    fn blocked(peer_id: PeerId) -> Blocklist {
+        let mut blocklist = Blocklist::default();
+        blocklist.insert(peer_id).unwrap();
+        blocklist
+    }
+
+    fn max_length(max: usize) -> Filters {
+        let mut filters = Filters::default();
+        filters.push(MaxLength(max));
+        filters
+    }
+
+    #[test]
+    fn validate_accepts_signed_text() {
+        let author = PeerId::random();
+        let verdict = validate(&message(Some(author), true, b"HELLO"), true, &Blocklist::default(), &Filters::default());
+        assert_eq!(verdict, Ok("HELLO".to_string()));
+    }
+
+    #[test]
+    fn validate_rejects_unsigned() {
+        let none = Blocklist::default();
+        let filters = Filters::default();
+        let no_source = validate(&message(None, true, b"HELLO"), true, &none, &filters);
+        assert_eq!(no_source.unwrap_err().0, MessageAcceptance::Reject);
+        let no_signature = validate(&message(Some(PeerId::random()), false, b"HELLO"), true, &none, &filters);
+        assert_eq!(no_signature.unwrap_err().0, MessageAcceptance::Reject);
+    }
+
+    #[test]
+    fn validate_ignores_unsubscribed_topic() {
+        let verdict = validate(&message(Some(PeerId::random()), true, b"HELLO"), false, &Blocklist::default(), &Filters::default());
+        assert_eq!(verdict.unwrap_err().0, MessageAcceptance::Ignore);
+    }
+
+    #[test]
+    fn validate_ignores_blocked_author() {
+        let author = PeerId::random();
+        let verdict = validate(&message(Some(author), true, b"HELLO"), true, &blocked(author), &Filters::default());
+        assert_eq!(verdict.unwrap_err().0, MessageAcceptance::Ignore);
+    }
+
+    #[test]
+    fn validate_rejects_non_utf8() {
+        let verdict = validate(&message(Some(PeerId::random()), true, &[0xff, 0xfe]), true, &Blocklist::default(), &Filters::default());
+        assert_eq!(verdict.unwrap_err().0, MessageAcceptance::Reject);
+    }
+
+    #[test]
+    fn validate_rejects_filtered() {
+        let verdict = validate(&message(Some(PeerId::random()), true, b"HELLO"), true, &Blocklist::default(), &max_length(3));
+        assert_eq!(verdict.unwrap_err().0, MessageAcceptance::Reject);
+    }
+
+    #[test]
+    fn validate_direct_cases() {
+        let author = PeerId::random();
+        let topic = TopicHash::from_raw("test-net");
+        let (none, filters) = (Blocklist::default(), Filters::default());
+        let ok = validate_direct(&author, &topic, true, &none, &filters, "HELLO".to_string());
+        assert_eq!(ok, Ok("HELLO".to_string()));
+        let blocked = validate_direct(&author, &topic, true, &blocked(author), &filters, "HELLO".to_string());
+        assert_eq!(blocked, Err("blocked".to_string()));
+        let unsubscribed = validate_direct(&author, &topic, false, &none, &filters, "HELLO".to_string());
+        assert_eq!(unsubscribed, Err("not subscribed to test-net".to_string()));
+        let filtered = validate_direct(&author, &topic, true, &none, &max_length(3), "HELLO".to_string());
+        assert!(filtered.is_err());
+    }
+
+    #[test]
+    fn validate_dm_cases() {
+        let author = PeerId::random();
+        let (none, filters) = (Blocklist::default(), Filters::default());
+        assert_eq!(validate_dm(&author, &none, &filters, "HI".to_string()), Ok("HI".to_string()));
+        assert_eq!(validate_dm(&author, &blocked(author), &filters, "HI".to_string()), Err("blocked".to_string()));
+        assert!(validate_dm(&author, &none, &max_length(1), "HI".to_string()).is_err());
+    }
+
+    #[test]
+    fn recovered_via_gossip_only_from_unknown_non_author() {
+        let (author, other) = (PeerId::random(), PeerId::random());
+        let msg = message(Some(author), true, b"HELLO");
+        // 書いた本人からなら、meshや既知のpeerかに関わらず取りに行ったものではない
+        for (from_mesh, known_peer) in [(false, false), (true, false), (false, true), (true, true)] {
+            assert!(!recovered_via_gossip(&msg, &author, from_mesh, known_peer));
+        }
+        assert!(recovered_via_gossip(&msg, &other, false, false));
+        assert!(!recovered_via_gossip(&msg, &other, true, false));
+        assert!(!recovered_via_gossip(&msg, &other, false, true));
+        assert!(!recovered_via_gossip(&msg, &other, true, true));
+    }
+}
@@ -0,0 +1,116 @@
+// メッセージを受信したら指定したURLにJSONをPOSTする。`webhook` featureを付けてビルドしたときだけ使える。
+//  export WEBHOOK_SECRET=...   (あれば本文のHMAC-SHA256を X-Signature-256 ヘッダに付ける)
+//  cargo run -p chat --features webhook -- --webhook https://example.com/hook [--webhook-match <regex>]
+//
+// 送る内容
+//  {"topic": "...", "from": "<peer id>", "message_id": "...", "text": "...", "timestamp": <UNIX秒>}
+// 失敗したら間隔を倍にしながら MAX_ATTEMPTS 回まで送り直す。
+
+#[cfg(feature = "webhook")]
+pub use imp::Webhook;
+#[cfg(not(feature = "webhook"))]
+pub use stub::Webhook;
+
+#[cfg(feature = "webhook")]
+mod imp {
+    use std::{
+        error::Error,
+        time::{Duration, SystemTime, UNIX_EPOCH},
+    };
+
+    use hmac::{Hmac, Mac};
+    use regex::Regex;
+    use serde_json::json;
+    use sha2::Sha256;
+
+    const MAX_ATTEMPTS: u32 = 3;
+
+    pub struct Webhook {
+        http: reqwest::Client,
+        url: String,
+        pattern: Option<Regex>,
+        secret: Option<String>,
+    }
+
+    impl Webhook {
+        pub fn new(url: String, pattern: Option<&str>, secret: Option<String>) -> Result<Self, Box<dyn Error>> {
+            Ok(Webhook {
+                http: reqwest::Client::new(),
+                url,
+                pattern: pattern.map(Regex::new).transpose()?,
+                secret,
+            })
+        }
+
+        // 条件に合えば別タスクで送る。swarmのループは待たせない。
+        pub fn notify(&self, topic: &str, from: &str, message_id: &str, text: &str) {
+            if let Some(pattern) = &self.pattern
+                && !pattern.is_match(text)
+            {
+                return;
+            }
+            let timestamp = SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .unwrap_or_default()
+                .as_secs();
+            let body = json!({
+                "topic": topic,
+                "from": from,
+                "message_id": message_id,
+                "text": text,
+                "timestamp": timestamp,
+            })
+            .to_string();
+            let signature = self.secret.as_ref().map(|secret| sign(secret, &body));
+            let (http, url) = (self.http.clone(), self.url.clone());
+
+            tokio::spawn(async move {
+                let mut wait = Duration::from_secs(1);
+                for attempt in 1..=MAX_ATTEMPTS {
+                    let mut request = http
+                        .post(&url)
+                        .header("Content-Type", "application/json")
+                        .body(body.clone());
+                    if let Some(signature) = &signature {
+                        request = request.header("X-Signature-256", format!("sha256={signature}"));
+                    }
+                    match request.send().await.and_then(|r| r.error_for_status()) {
+                        Ok(_) => return,
+                        Err(e) => println!("Webhook error ({attempt}/{MAX_ATTEMPTS}): {e:?}"),
+                    }
+                    tokio::time::sleep(wait).await;
+                    wait *= 2;
+                }
+            });
+        }
+    }
+
+    fn sign(secret: &str, body: &str) -> String {
+        let mut mac = Hmac::<Sha256>::new_from_slice(secret.as_bytes())
+            .expect("HMAC accepts keys of any length");
+        mac.update(body.as_bytes());
+        mac.finalize()
+            .into_bytes()
+            .iter()
+            .map(|b| format!("{b:02x}"))
+            .collect()
+    }
+}
+
+#[cfg(not(feature = "webhook"))]
+mod stub {
+    use std::{convert::Infallible, error::Error};
+
+    // featureなしでは作れない型
+    pub struct Webhook(Infallible);
+
+    impl Webhook {
+        pub fn new(_url: String, _pattern: Option<&str>, _secret: Option<String>) -> Result<Self, Box<dyn Error>> {
+            Err("--webhook needs the `webhook` feature".into())
+        }
+
+        pub fn notify(&self, _topic: &str, _from: &str, _message_id: &str, _text: &str) {
+            match self.0 {}
+        }
+    }
+}
@@ -0,0 +1,131 @@
+use std::{
+    error::Error,
+    io::{self, Write},
+    sync::{
+        Arc, Mutex,
+        atomic::{AtomicBool, AtomicU64, Ordering},
+    },
+    thread,
+    time::{Duration, Instant},
+};
+
+use libp2p::identity::Keypair;
+
+// ed25519鍵のPeerIdは必ずこれで始まる
+const ED25519_PREFIX: &str = "12D3KooW";
+const BASE58_ALPHABET: &str = "123456789ABCDEFGHJKLMNPQRSTUVWXYZabcdefghijkmnopqrstuvwxyz";
+// ed25519鍵のPeerIdが取りうる最小と最大(公開鍵がすべて0x00とすべて0xff)。
+// 頭のバイト列が決まっているので、12D3KooW の次の文字は 9 から T までしかない。
+const ED25519_MIN: &str = "12D3KooW9pNAk8aiBuGVQtWRdbkLmo5qVL3e2h5UxbN2Nz9ttwiw";
+const ED25519_MAX: &str = "12D3KooWT3gYEvLJyx1FyyHqrmvdy1tMjpgxmu9aSeKMEuafQtyC";
+
+// base58の文字列を数として見たときの値。桁が多いのでf64で近似する。
+fn base58_value(s: &str) -> f64 {
+    s.chars()
+        .map(|c| BASE58_ALPHABET.find(c).unwrap_or(0) as f64)
+        .fold(0.0, |value, digit| value * 58.0 + digit)
+}
+
+// ランダムな鍵のPeerIdがtargetで始まる確率。0なら見つからない。
+// base58のアルファベットは文字コード順なので、文字列の大小と数の大小が一致する。
+fn probability(target: &str) -> f64 {
+    if target.len() > ED25519_MAX.len() {
+        return 0.0;
+    }
+    let pad = ED25519_MAX.len() - target.len();
+    let first = format!("{target}{}", "1".repeat(pad));
+    let last = format!("{target}{}", "z".repeat(pad));
+    if last.as_str() < ED25519_MIN || first.as_str() > ED25519_MAX {
+        return 0.0;
+    }
+    let (min, max) = (base58_value(ED25519_MIN), base58_value(ED25519_MAX));
+    let first = base58_value(&first).max(min);
+    let last = base58_value(&last).min(max);
+    (last - first + 1.0) / (max - min + 1.0)
+}
+
+fn main() -> Result<(), Box<dyn Error>> {
+    // 引数
+    //  keygen <prefix> [出力ファイル] [--threads <n>] [--max-tries <n>]
+    let mut prefix = None;
+    let mut output = None;
+    let mut threads = thread::available_parallelism().map_or(1, |n| n.get());
+    // これだけ試して見つからなければやめる
+    let mut max_tries = None;
+    let mut args = std::env::args().skip(1);
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--threads" => threads = args.next().ok_or("--threads needs a value")?.parse()?,
+            "--max-tries" => max_tries = Some(args.next().ok_or("--max-tries needs a value")?.parse::<u64>()?),
+            _ if prefix.is_none() => prefix = Some(arg),
+            _ => output = Some(arg),
+        }
+    }
+    let prefix = prefix.ok_or("usage: keygen <prefix> [output] [--threads <n>] [--max-tries <n>]")?;
+    let prefix = match prefix.strip_prefix(ED25519_PREFIX) {
+        Some(rest) => rest.to_string(),
+        None => prefix,
+    };
+    if let Some(c) = prefix.chars().find(|c| !BASE58_ALPHABET.contains(*c)) {
+        return Err(format!("'{c}' never appears in a base58 PeerId").into());
+    }
+    let probability = probability(&format!("{ED25519_PREFIX}{prefix}"));
+    if probability == 0.0 {
+        return Err(format!(
+            "no ed25519 PeerId starts with {ED25519_PREFIX}{prefix} (it must be between {ED25519_MIN} and {ED25519_MAX})"
+        )
+        .into());
+    }
+    let output = output.unwrap_or_else(|| format!("{prefix}.key"));
+    println!("Searching {ED25519_PREFIX}{prefix}... with {threads} threads (about {:.0} keys expected)", 1.0 / probability);
+
+    // 見つかったか、max_triesに達したらtrueにしてワーカーを止める
+    let found = Arc::new(AtomicBool::new(false));
+    let tried = Arc::new(AtomicU64::new(0));
+    let result: Arc<Mutex<Option<Keypair>>> = Arc::default();
+
+    let workers: Vec<_> = (0..threads)
+        .map(|_| {
+            let (prefix, found, tried, result) =
+                (prefix.clone(), found.clone(), tried.clone(), result.clone());
+            thread::spawn(move || {
+                let target = format!("{ED25519_PREFIX}{prefix}");
+                while !found.load(Ordering::Relaxed) {
+                    let n = tried.fetch_add(1, Ordering::Relaxed);
+                    if max_tries.is_some_and(|max| n >= max) {
+                        found.store(true, Ordering::Relaxed);
+                        break;
+                    }
+                    let keypair = Keypair::generate_ed25519();
+                    if keypair.public().to_peer_id().to_base58().starts_with(&target) {
+                        // 他のスレッドが先に見つけていたらそちらを使う
+                        result.lock().unwrap().get_or_insert(keypair);
+                        found.store(true, Ordering::Relaxed);
+                    }
+                }
+            })
+        })
+        .collect();
+
+    // 1秒ごとに試行回数と速度を表示する
+    let start = Instant::now();
+    while !found.load(Ordering::Relaxed) {
+        thread::sleep(Duration::from_secs(1));
+        let n = tried.load(Ordering::Relaxed);
+        let rate = n as f64 / start.elapsed().as_secs_f64();
+        print!("\rtried {n} keys ({rate:.0} keys/s)");
+        io::stdout().flush()?;
+    }
+    println!();
+    for worker in workers {
+        worker.join().expect("worker thread panicked");
+    }
+
+    let Some(keypair) = result.lock().unwrap().take() else {
+        return Err(format!("not found in {} keys (--max-tries)", max_tries.unwrap_or_default()).into());
+    };
+    std::fs::write(&output, keypair.to_protobuf_encoding()?)?;
+    println!("Peer ID: {}", keypair.public().to_peer_id());
+    println!("Saved key to {output}");
+    Ok(())
+}
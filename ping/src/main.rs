@@ -10,6 +10,23 @@ async fn main() -> Result<(), Box<dyn Error>> {
         .with_env_filter(EnvFilter::from_default_env())
         .try_init();
 
+    // 引数
+    //  ping [--idle-timeout <secs> | --keep-alive] [接続先multiaddr]
+    let mut idle_timeout = Duration::from_secs(60_u64);
+    let mut remote_addr = None;
+    let mut args = std::env::args().skip(1);
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--idle-timeout" => {
+                let secs = args.next().ok_or("--idle-timeout needs a value")?;
+                idle_timeout = Duration::from_secs(secs.parse()?);
+            }
+            // pingを送り続ける間は接続を閉じない
+            "--keep-alive" => idle_timeout = Duration::from_secs(u64::MAX),
+            _ => remote_addr = Some(arg),
+        }
+    }
+
     // swarmのbuildにはTransportとBehaviourがいる
     let mut swarm = libp2p::SwarmBuilder::with_new_identity()
         .with_tokio()
@@ -21,7 +38,7 @@ async fn main() -> Result<(), Box<dyn Error>> {
         .with_behaviour(|_| ping::Behaviour::default())? // Behavior(ping: 15秒ごとに送信, 20秒以内に受信)
         // ここ以下はオプション的なもの
         .with_swarm_config(|cfg| {
-            cfg.with_idle_connection_timeout(Duration::from_secs(60_u64)) // 接続期間。終わるとSwarmEvent::ConnectionClosedが発生。
+            cfg.with_idle_connection_timeout(idle_timeout) // 接続期間。終わるとSwarmEvent::ConnectionClosedが発生。
         })
         .build();
     let peer_id = swarm.local_peer_id();
@@ -30,7 +47,7 @@ async fn main() -> Result<(), Box<dyn Error>> {
     swarm.listen_on("/ip4/0.0.0.0/tcp/0".parse()?)?;
 
     // 引数があれば接続先として扱う
-    if let Some(addr) = std::env::args().nth(1) {
+    if let Some(addr) = remote_addr {
         let remote: Multiaddr = addr.parse()?;
         swarm.dial(remote)?;
         println!("Dialed: {addr}");